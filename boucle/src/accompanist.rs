@@ -0,0 +1,152 @@
+//! Autonomous accompanist: glitch the loop without anyone pressing keys.
+//!
+//! The accompanist listens to the input and estimates how loud and how dense
+//! the playing is. Once per beat it decides whether to start an operation on
+//! the next beat, and which one. Busy, loud playing gets more frequent and
+//! shorter ops; sparse playing gets the occasional reverse or jump.
+//!
+//! The decisions are ops with a known start and duration, scheduled in the
+//! `PatternPlayer` like the ops of audio triggers. They are kept apart from
+//! the performer's keys in the `EventRecorder`, so the accompanist can't end
+//! an op the performer is holding, or the other way round. How much happens
+//! overall is controlled by a single `intensity` setting, from 0.0 (nothing)
+//! to 1.0 (a lot).
+
+use crate::BeatFraction;
+use crate::Sample;
+use crate::SamplePosition;
+use crate::audio_trigger::AudioTrigger;
+use crate::audio_trigger::EnvelopeFollower;
+use crate::audio_trigger::TriggerMode;
+use crate::op_sequence;
+use crate::op_sequence::OpSequence;
+use crate::ops::Operation;
+use crate::random::Random;
+
+use log::*;
+
+// Input level and onsets per beat that count as 'playing flat out'.
+const FULL_LEVEL: f32 = 0.3;
+const FULL_DENSITY: f32 = 4.0;
+
+pub struct Accompanist {
+    intensity: f32,
+    beats_to_samples: f32,
+    random: Random,
+
+    level: EnvelopeFollower,
+    // Only used to detect onsets, the operation is ignored.
+    onsets: AudioTrigger,
+
+    beat: u64,
+    onsets_this_beat: u32,
+    density: f32,
+    busy_until: SamplePosition,
+
+    ops: OpSequence,
+}
+
+impl Accompanist {
+    pub fn new(intensity: f32, beats_to_samples: f32, sample_rate: u32, seed: u64) -> Self {
+        Accompanist {
+            intensity: intensity.max(0.0).min(1.0),
+            beats_to_samples,
+            random: Random::new(seed),
+            level: EnvelopeFollower::new(10.0, 500.0, sample_rate),
+            onsets: AudioTrigger::new(TriggerMode::Onset, 2.0, Operation::NoOp, BeatFraction::from(0.0), sample_rate),
+            beat: 0,
+            onsets_this_beat: 0,
+            density: 0.0,
+            busy_until: 0,
+            ops: OpSequence::new(),
+        }
+    }
+
+    pub fn set_intensity(self: &mut Self, intensity: f32) {
+        self.intensity = intensity.max(0.0).min(1.0);
+    }
+
+    pub fn intensity(self: &Self) -> f32 {
+        return self.intensity;
+    }
+
+    // How much is going on in the input, from 0.0 (silence) to 1.0.
+    pub fn activity(self: &Self) -> f32 {
+        let level = (self.level.envelope() / FULL_LEVEL).min(1.0);
+        let density = (self.density / FULL_DENSITY).min(1.0);
+        return 0.5 * level + 0.5 * density;
+    }
+
+    // Process one input sample, received at `time` on the play clock.
+    pub fn process_sample(self: &mut Self, sample: Sample, time: SamplePosition) {
+        let beat = (time as f32 / self.beats_to_samples) as u64;
+        if beat > self.beat {
+            self.end_beat(beat);
+        }
+
+        self.level.process(sample);
+        if self.onsets.process(sample) {
+            self.onsets_this_beat += 1;
+        }
+    }
+
+    // Decide what to do on the beat after the one that just finished.
+    fn end_beat(self: &mut Self, beat: u64) {
+        self.density = 0.7 * self.density + 0.3 * self.onsets_this_beat as f32;
+        self.onsets_this_beat = 0;
+        self.beat = beat;
+
+        // Always consume the same randomness, so a seed gives the same
+        // choices for the same input.
+        let roll = self.random.next_f32();
+        let pick = self.random.next_f32();
+        let length = self.random.range_f32(0.5, 0.5 + self.intensity * 3.5);
+
+        let activity = self.activity();
+        let chance = self.intensity * (0.2 + 0.8 * activity);
+        let start = ((beat + 1) as f32 * self.beats_to_samples) as SamplePosition;
+        if roll >= chance || start < self.busy_until {
+            return;
+        }
+
+        let operation = self.pick_operation(pick, activity);
+        // Dense playing gets shorter ops.
+        let duration = BeatFraction::from(length * (1.0 - 0.5 * activity)).as_beats().max(0.25);
+        let end = start + (duration * self.beats_to_samples) as SamplePosition;
+        info!("accompanist: {:?} for {} beats at beat {} (activity {:.2})", operation, duration, beat + 1, activity);
+
+        self.ops.push(op_sequence::Entry {
+            start,
+            duration: Some(end - start),
+            operation,
+            probability: 1.0,
+        });
+        self.busy_until = end;
+    }
+
+    fn pick_operation(self: &Self, pick: f32, activity: f32) -> Operation {
+        // Short repeats suit busy playing, reverses and jumps suit sparse playing.
+        let candidates = [
+            (Operation::Reverse, 2.0 - activity),
+            (Operation::Repeat { loop_size: BeatFraction::from(0.25) }, 1.0),
+            (Operation::Repeat { loop_size: BeatFraction::from(0.125) }, 2.0 * activity),
+            (Operation::Jump { offset: BeatFraction::from(-1.0) }, 0.5),
+            (Operation::Jump { offset: BeatFraction::from(1.0) }, 0.5),
+        ];
+        let total_weight: f32 = candidates.iter().map(|(_, weight)| weight).sum();
+
+        let mut threshold = 0.0;
+        for (operation, weight) in candidates.iter() {
+            threshold += weight / total_weight;
+            if pick < threshold {
+                return *operation;
+            }
+        }
+        return Operation::Reverse;
+    }
+
+    // Return the ops decided since the last call, for the `PatternPlayer`.
+    pub fn take_ops(self: &mut Self) -> std::vec::Drain<'_, op_sequence::Entry> {
+        return self.ops.drain(..);
+    }
+}
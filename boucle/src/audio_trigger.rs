@@ -0,0 +1,209 @@
+//! Trigger operations from the recorded input.
+//!
+//! An envelope follower tracks the level of the input. A trigger fires when
+//! the level crosses a threshold, or when an onset (a sudden jump in level,
+//! such as a drum hit) is detected. Each time it fires, the trigger starts
+//! its configured operation.
+
+use crate::BeatFraction;
+use crate::Sample;
+use crate::SamplePosition;
+use crate::ops;
+use crate::ops::Operation;
+use crate::ops::ParseError;
+use crate::op_sequence;
+use crate::op_sequence::OpSequence;
+
+use log::*;
+
+// Don't fire again within this many milliseconds of the last trigger.
+const HOLDOFF_MS: f32 = 50.0;
+
+// Ignore onsets in very quiet input.
+const ONSET_MIN_LEVEL: f32 = 0.01;
+
+/// Track the level of a signal, with separate attack and release times.
+pub struct EnvelopeFollower {
+    attack: f32,
+    release: f32,
+    envelope: f32,
+}
+
+fn time_coefficient(time_ms: f32, sample_rate: u32) -> f32 {
+    return (-1.0 / (time_ms / 1000.0 * sample_rate as f32)).exp();
+}
+
+impl EnvelopeFollower {
+    pub fn new(attack_ms: f32, release_ms: f32, sample_rate: u32) -> Self {
+        EnvelopeFollower {
+            attack: time_coefficient(attack_ms, sample_rate),
+            release: time_coefficient(release_ms, sample_rate),
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process(self: &mut Self, sample: Sample) -> f32 {
+        let level = sample.abs();
+        let coefficient = if level > self.envelope { self.attack } else { self.release };
+        self.envelope = coefficient * self.envelope + (1.0 - coefficient) * level;
+        return self.envelope;
+    }
+
+    pub fn envelope(self: &Self) -> f32 {
+        return self.envelope;
+    }
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum TriggerMode {
+    // Fire when the level rises above `threshold`.
+    Threshold,
+    // Fire when the short-term level jumps above `threshold` times the long-term level.
+    Onset,
+}
+
+pub struct AudioTrigger {
+    mode: TriggerMode,
+    threshold: f32,
+    operation: Operation,
+    duration: BeatFraction,
+
+    fast: EnvelopeFollower,
+    slow: EnvelopeFollower,
+    armed: bool,
+    holdoff_samples: SamplePosition,
+    holdoff: SamplePosition,
+}
+
+impl AudioTrigger {
+    pub fn new(mode: TriggerMode, threshold: f32, operation: Operation, duration: BeatFraction, sample_rate: u32) -> Self {
+        AudioTrigger {
+            mode,
+            threshold,
+            operation,
+            duration,
+            fast: EnvelopeFollower::new(1.0, 20.0, sample_rate),
+            slow: EnvelopeFollower::new(50.0, 250.0, sample_rate),
+            armed: true,
+            holdoff_samples: (HOLDOFF_MS / 1000.0 * sample_rate as f32) as SamplePosition,
+            holdoff: 0,
+        }
+    }
+
+    // Process one input sample, and return true if the trigger fires.
+    pub fn process(self: &mut Self, sample: Sample) -> bool {
+        let fast = self.fast.process(sample);
+        let slow = self.slow.process(sample);
+
+        if self.holdoff > 0 {
+            self.holdoff -= 1;
+        }
+
+        let fire = match self.mode {
+            TriggerMode::Threshold => {
+                if fast > self.threshold {
+                    let fire = self.armed;
+                    self.armed = false;
+                    fire
+                } else {
+                    // Some hysteresis, so a level hovering at the threshold doesn't retrigger.
+                    if fast < self.threshold * 0.5 {
+                        self.armed = true;
+                    }
+                    false
+                }
+            },
+            TriggerMode::Onset => {
+                if fast > ONSET_MIN_LEVEL && fast > slow * self.threshold {
+                    let fire = self.armed;
+                    self.armed = false;
+                    fire
+                } else {
+                    self.armed = true;
+                    false
+                }
+            },
+        };
+
+        if fire && self.holdoff == 0 {
+            self.holdoff = self.holdoff_samples;
+            return true;
+        }
+        return false;
+    }
+}
+
+/// Parse a trigger of the form `MODE THRESHOLD DURATION OPERATION [ARGS]`.
+///
+/// For example, `onset 2 0.5 repeat 0.125` repeats a 1/8 beat loop for half
+/// a beat on each drum hit.
+pub fn new_from_string(line: &str, sample_rate: u32) -> Result<AudioTrigger, ParseError> {
+    let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+    if parts.len() < 4 {
+        return Err(ParseError { message: format!("expected 'MODE THRESHOLD DURATION OPERATION [ARGS]', got '{}'", line) });
+    }
+
+    let mode = match parts[0] {
+        "threshold" => TriggerMode::Threshold,
+        "onset" => TriggerMode::Onset,
+        _ => return Err(ParseError { message: format!("unknown trigger mode '{}'", parts[0]) }),
+    };
+    let threshold = parts[1].parse::<f32>()?;
+    let duration = parts[2].parse::<f32>()?;
+    let operation = ops::operation_from_parts(parts[3], &parts[4..])?;
+
+    return Ok(AudioTrigger::new(mode, threshold, operation, BeatFraction::from(duration), sample_rate));
+}
+
+struct TriggerEvent {
+    time: SamplePosition,
+    operation: Operation,
+    duration: BeatFraction,
+}
+
+/// The set of audio triggers that listen to the input.
+pub struct AudioTriggers {
+    triggers: Vec<AudioTrigger>,
+    events: Vec<TriggerEvent>,
+}
+
+impl AudioTriggers {
+    pub fn new() -> Self {
+        AudioTriggers { triggers: Vec::new(), events: Vec::new() }
+    }
+
+    pub fn add_trigger(self: &mut Self, trigger: AudioTrigger) {
+        self.triggers.push(trigger);
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.triggers.is_empty();
+    }
+
+    // Process one input sample, received at `time` on the play clock.
+    pub fn process_sample(self: &mut Self, sample: Sample, time: SamplePosition) {
+        for trigger in self.triggers.iter_mut() {
+            if trigger.process(sample) {
+                debug!("{}: audio trigger {:?}", time, trigger.operation);
+                self.events.push(TriggerEvent {
+                    time,
+                    operation: trigger.operation,
+                    duration: trigger.duration,
+                });
+            }
+        }
+    }
+
+    // Return ops for all triggers that fired since the last call.
+    pub fn take_ops(self: &mut Self, beat_fraction_to_samples: f32) -> OpSequence {
+        return self.events.drain(..).map(|event| op_sequence::Entry {
+            start: event.time,
+            duration: Some(event.duration.as_sample_position(beat_fraction_to_samples)),
+            operation: event.operation,
+            probability: 1.0,
+        }).collect();
+    }
+}
@@ -1,25 +1,669 @@
-pub type Buffer = Vec<crate::Sample>;
+use crate::Sample;
+use crate::SamplePosition;
+use crate::accompanist::Accompanist;
+use crate::audio_trigger::AudioTriggers;
+use crate::buses::DEFAULT_DRY_WET;
+use crate::passthrough::Passthrough;
+use crate::punch::Punch;
 
+use log::*;
+
+use std::collections::VecDeque;
+
+pub type Buffer = Vec<Sample>;
+
+/// Default number of loops to keep: one being recorded, one being played.
+pub const DEFAULT_HISTORY_DEPTH: usize = 2;
+
+/// Upper limit on the number of loops to keep, so memory use is bounded.
+pub const MAX_HISTORY_DEPTH: usize = 16;
+
+// Recording and playback each leave the freeze at their own loop boundary,
+// as input and output don't reach the end of the loop at the same time.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum Freeze {
+    Off,
+    On,
+    Releasing,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum Bounce {
+    Off,
+    // Waiting for the next loop boundary.
+    Armed,
+    // Capturing one loop of output.
+    Capturing,
+}
+
+/// How to fill the new part of the loop when it gets longer.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
 #[derive(PartialEq)]
-pub enum InputBuffer { A, B }
+pub enum ResizeFill {
+    // Repeat the existing loop.
+    Repeat,
+    Silence,
+    // Record the input into the loop being played, the first time round.
+    Input,
+}
+
+/// Settings for sound-on-sound recording.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Overdub {
+    // How much of the existing loop is kept each time round, 0.0 to 1.0.
+    pub feedback: f32,
+    // How much of the processed output is printed back into the loop.
+    pub print: f32,
+}
+
+pub const DEFAULT_OVERDUB_FEEDBACK: f32 = 0.8;
+
+// Most input samples to queue for the output, if the output falls behind.
+const MAX_MONITOR_SAMPLES: usize = 8192;
+
+// Levels below this pass through the limiter unchanged, so only overdubs
+// that get close to full scale are coloured.
+const LIMITER_THRESHOLD: Sample = 0.9;
+
+/// Soft limiter, so feedback can't build up past full scale.
+pub fn soft_limit(sample: Sample) -> Sample {
+    let level = sample.abs();
+    if level <= LIMITER_THRESHOLD {
+        return sample;
+    }
+    let headroom = 1.0 - LIMITER_THRESHOLD;
+    let limited = LIMITER_THRESHOLD + headroom * ((level - LIMITER_THRESHOLD) / headroom).tanh();
+    return limited.copysign(sample);
+}
+
+// Fill the part of a loop between the old and new loop lengths.
+fn fill_extension(buffer: &mut [Sample], old_length: SamplePosition, new_length: SamplePosition, fill: ResizeFill) {
+    match fill {
+        ResizeFill::Repeat if old_length > 0 => {
+            let mut position = old_length;
+            while position < new_length {
+                let length = (new_length - position).min(old_length);
+                buffer.copy_within(0..length, position);
+                position += length;
+            }
+        },
+        _ => buffer[old_length..new_length].fill(0.0),
+    }
+}
+
+/// Room for the loops to grow into, made before taking the buffers, so the
+/// allocation doesn't hold up the audio thread. See
+/// `LoopBuffers::resize_loop_into()`.
+#[derive(Default)]
+pub struct LoopSpace {
+    loops: Vec<Buffer>,
+    bounce_buffer: Buffer,
+}
+
+impl LoopSpace {
+    /// Room for `loop_count` loops `loop_length` long, as many as
+    /// `LoopBuffers::loop_count()`.
+    pub fn new(loop_length: SamplePosition, loop_count: usize) -> Self {
+        LoopSpace {
+            loops: vec!(vec!(0.0; loop_length); loop_count),
+            bounce_buffer: vec!(0.0; loop_length),
+        }
+    }
+
+    /// `loops`, newest first, laid out for `LoopBuffers::load_loops_from()`
+    /// on buffers `buffer_length` long that keep `loop_count` loops.
+    pub fn with_loops(loops: &[Buffer], loop_length: SamplePosition,
+                      buffer_length: usize, loop_count: usize) -> Self {
+        let length = loop_length.max(buffer_length);
+        let mut space = LoopSpace::new(length, loop_count);
+        space.bounce_buffer = Buffer::new();
+        for (buffer, samples) in space.loops.iter_mut().zip(loops.iter()) {
+            let samples_length = samples.len().min(loop_length);
+            buffer[..samples_length].copy_from_slice(&samples[..samples_length]);
+        }
+        return space;
+    }
+
+    fn fits(self: &Self, loop_length: SamplePosition, loop_count: usize) -> bool {
+        return self.loops.len() == loop_count && self.bounce_buffer.len() == loop_length &&
+            self.loops.iter().all(|buffer| buffer.len() == loop_length);
+    }
+}
 
 pub struct LoopBuffers {
-    pub input_a: Buffer,
-    pub input_b: Buffer,
-    pub current_input: InputBuffer,
-    pub current_output: InputBuffer,
-    pub record_pos: crate::SamplePosition,
-    pub play_clock: crate::SamplePosition,
+    // Ring of loop recordings. We record into `current_input` and play
+    // `current_output`, the loops before that are older takes. Recording
+    // overwrites the oldest loop.
+    pub history: Vec<Buffer>,
+    pub current_input: usize,
+    pub current_output: usize,
+    pub record_pos: SamplePosition,
+    pub play_clock: SamplePosition,
+    pub audio_triggers: AudioTriggers,
+    pub accompanist: Option<Accompanist>,
+    pub overdub: Option<Overdub>,
+    // When set, the output is the live input, and ops play from its history.
+    pub passthrough: Option<Passthrough>,
+    // Mix of input and output, from 0.0 for dry to 1.0 for wet.
+    pub dry_wet: f32,
+
+    record_freeze: Freeze,
+    play_freeze: Freeze,
+
+    bounce: Bounce,
+    bounce_buffer: Buffer,
+
+    // The loop can be shorter than the buffers. Like freezing, recording and
+    // playback each change length at their own loop boundary.
+    record_length: SamplePosition,
+    play_length: SamplePosition,
+    pending_record_length: Option<SamplePosition>,
+    pending_play_length: Option<(SamplePosition, ResizeFill)>,
+    // Record input into the loop being played from here, after `ResizeFill::Input`.
+    input_fill_start: Option<SamplePosition>,
+
+    // Punch-in recording. Until it is done, the input is passed through to
+    // the output, instead of the loop being played.
+    punch: Option<Punch>,
+    // Input waiting to be played as the dry output.
+    monitor: VecDeque<Sample>,
+    // Play clock where recording should restart the loop, after a punch.
+    record_restart: Option<SamplePosition>,
 }
 
 pub fn create_buffers(buffer_size_samples: usize) -> LoopBuffers {
+    return create_buffers_with_history(buffer_size_samples, DEFAULT_HISTORY_DEPTH);
+}
+
+/// Create buffers that keep `history_depth` loops, including the one being
+/// recorded. Memory use is `history_depth * buffer_size_samples` samples.
+pub fn create_buffers_with_history(buffer_size_samples: usize, history_depth: usize) -> LoopBuffers {
+    let history_depth = history_depth.max(DEFAULT_HISTORY_DEPTH).min(MAX_HISTORY_DEPTH);
     let this = LoopBuffers {
-        input_a: vec!(0.0; buffer_size_samples),
-        input_b: vec!(0.0; buffer_size_samples),
-        current_input: InputBuffer::B,
-        current_output: InputBuffer::A,
+        history: vec!(vec!(0.0; buffer_size_samples); history_depth),
+        current_input: 1,
+        current_output: 0,
         record_pos: 0,
         play_clock: 0,
+        audio_triggers: AudioTriggers::new(),
+        accompanist: None,
+        overdub: None,
+        passthrough: None,
+        dry_wet: DEFAULT_DRY_WET,
+        record_freeze: Freeze::Off,
+        play_freeze: Freeze::Off,
+        bounce: Bounce::Off,
+        bounce_buffer: Buffer::new(),
+        record_length: buffer_size_samples,
+        play_length: buffer_size_samples,
+        pending_record_length: None,
+        pending_play_length: None,
+        input_fill_start: None,
+        punch: None,
+        monitor: VecDeque::with_capacity(MAX_MONITOR_SAMPLES),
+        record_restart: None,
     };
     return this;
 }
+
+impl LoopBuffers {
+    pub fn buffer_length(self: &Self) -> usize {
+        return self.history[0].len();
+    }
+
+    pub fn output_buffer(self: &Self) -> &Buffer {
+        return &self.history[self.current_output];
+    }
+
+    // Length of the loop being recorded.
+    pub fn record_length(self: &Self) -> SamplePosition {
+        return self.record_length;
+    }
+
+    // Length of the loop being played.
+    pub fn play_length(self: &Self) -> SamplePosition {
+        return self.play_length;
+    }
+
+    /// How many loops are kept, including the one being recorded.
+    pub fn loop_count(self: &Self) -> usize {
+        return self.history.len();
+    }
+
+    /// Change the loop length from the next loop boundary.
+    ///
+    /// If the loop gets longer, the new part is filled as given by `fill`.
+    /// Buffers grow if needed, which allocates, so don't call this from
+    /// an audio callback.
+    pub fn resize_loop(self: &mut Self, loop_length: SamplePosition, fill: ResizeFill) {
+        let space = match loop_length > self.buffer_length() {
+            true => LoopSpace::new(loop_length, self.loop_count()),
+            false => LoopSpace::default(),
+        };
+        self.resize_loop_into(loop_length, fill, space);
+    }
+
+    /// Like `resize_loop()`, but the buffers grow into `space` instead of
+    /// allocating, unless it's too small. Returns the old buffers, or `space`
+    /// if it wasn't needed, to drop after letting go of these buffers. The
+    /// new part of the loops is filled here too, so the loop boundary in the
+    /// audio callback only switches lengths.
+    pub fn resize_loop_into(self: &mut Self, loop_length: SamplePosition, fill: ResizeFill,
+                            space: LoopSpace) -> LoopSpace {
+        info!("Resize loop to {} samples at next loop, fill with {:?}", loop_length, fill);
+        let space = self.grow_into(loop_length, space);
+        // Fill the new part now rather than at the loop boundary, which is
+        // in the audio callback. Until then, `record_sample()` keeps it up
+        // to date with what is recorded.
+        if loop_length > self.play_length {
+            for buffer in self.history.iter_mut() {
+                fill_extension(buffer, self.play_length, loop_length, fill);
+            }
+        }
+        self.pending_record_length = Some(loop_length);
+        self.pending_play_length = Some((loop_length, fill));
+        return space;
+    }
+
+    // Grow the buffers to `length` if they are shorter, moving the loops into
+    // `space`. Returns what is left over, to drop later.
+    fn grow_into(self: &mut Self, length: SamplePosition, mut space: LoopSpace) -> LoopSpace {
+        if length > self.buffer_length() {
+            if !space.fits(length, self.loop_count()) {
+                warn!("Allocating loops of {} samples in place", length);
+                space = LoopSpace::new(length, self.loop_count());
+            }
+            for (new_buffer, buffer) in space.loops.iter_mut().zip(self.history.iter()) {
+                new_buffer[..buffer.len()].copy_from_slice(buffer);
+            }
+            std::mem::swap(&mut self.history, &mut space.loops);
+            if !self.bounce_buffer.is_empty() {
+                space.bounce_buffer[..self.bounce_buffer.len()].copy_from_slice(&self.bounce_buffer);
+                std::mem::swap(&mut self.bounce_buffer, &mut space.bounce_buffer);
+            }
+        }
+        return space;
+    }
+
+    // Copy the sample at `position` in a loop to where it repeats in the part
+    // that a pending resize adds, so the fill matches what was recorded.
+    fn repeat_into_extension(self: &mut Self, index: usize, position: SamplePosition) {
+        let old_length = self.play_length;
+        if let (Some(_), Some((new_length, ResizeFill::Repeat))) = (self.pending_record_length, self.pending_play_length) {
+            let buffer = &mut self.history[index];
+            let mut repeat = position + old_length;
+            while position < old_length && repeat < new_length {
+                buffer[repeat] = buffer[position];
+                repeat += old_length;
+            }
+        }
+    }
+
+    // Start recording the next loop. Call when recording reaches the end of the loop.
+    pub fn next_input(self: &mut Self) {
+        if let Some(loop_length) = self.pending_record_length.take() {
+            self.record_length = loop_length;
+        }
+
+        match self.record_freeze {
+            Freeze::Off => self.current_input = (self.current_input + 1) % self.history.len(),
+            Freeze::On => {},
+            // Record over the loop we stopped recording, from the start.
+            Freeze::Releasing => self.record_freeze = Freeze::Off,
+        }
+    }
+
+    // Start playing the next loop. Call when playback reaches the end of the loop.
+    pub fn next_output(self: &mut Self) {
+        self.input_fill_start = None;
+        if let Some((loop_length, fill)) = self.pending_play_length.take() {
+            // The new part was filled by `resize_loop_into()`.
+            if loop_length > self.play_length && fill == ResizeFill::Input {
+                self.input_fill_start = Some(self.play_length);
+            }
+            self.play_length = loop_length;
+        }
+
+        match self.bounce {
+            Bounce::Off => {},
+            Bounce::Armed => {
+                info!("Bounce: capturing output");
+                self.bounce = Bounce::Capturing;
+            },
+            Bounce::Capturing => {
+                // Replace the loop we just played with what it sounded like,
+                // and hold it so it can be glitched again.
+                info!("Bounce: done");
+                self.bounce = Bounce::Off;
+                let current_output = self.current_output;
+                std::mem::swap(&mut self.history[current_output], &mut self.bounce_buffer);
+                self.freeze();
+                return;
+            },
+        }
+
+        match self.play_freeze {
+            Freeze::Off => self.current_output = (self.current_output + 1) % self.history.len(),
+            Freeze::On => {},
+            // Keep playing the frozen loop until the next loop is recorded.
+            Freeze::Releasing => self.play_freeze = Freeze::Off,
+        }
+    }
+
+    /// Stop recording, and keep playing the current loop until unfrozen.
+    pub fn freeze(self: &mut Self) {
+        info!("Freeze");
+        self.record_freeze = Freeze::On;
+        self.play_freeze = Freeze::On;
+    }
+
+    /// Start recording again from the next loop boundary.
+    pub fn unfreeze(self: &mut Self) {
+        info!("Unfreeze at next loop");
+        if self.record_freeze == Freeze::On {
+            self.record_freeze = Freeze::Releasing;
+        }
+        if self.play_freeze == Freeze::On {
+            self.play_freeze = Freeze::Releasing;
+        }
+    }
+
+    pub fn is_frozen(self: &Self) -> bool {
+        return self.record_freeze != Freeze::Off || self.play_freeze != Freeze::Off;
+    }
+
+    pub fn is_recording(self: &Self) -> bool {
+        return self.record_freeze == Freeze::Off;
+    }
+
+    /// Start mixing the input into the loop being played, instead of
+    /// recording a new loop.
+    pub fn start_overdub(self: &mut Self, overdub: Overdub) {
+        info!("Overdub: {:?}", overdub);
+        self.overdub = Some(overdub);
+        self.freeze();
+    }
+
+    /// Go back to recording a new loop, from the next loop boundary.
+    pub fn stop_overdub(self: &mut Self) {
+        info!("Stop overdub");
+        self.overdub = None;
+        self.unfreeze();
+    }
+
+    // Record one input sample at `position` in the loop.
+    pub fn record_sample(self: &mut Self, position: SamplePosition, sample: Sample) {
+        if let Some(overdub) = self.overdub {
+            let buffer = &mut self.history[self.current_output];
+            buffer[position] = soft_limit(buffer[position] * overdub.feedback + sample);
+            self.repeat_into_extension(self.current_output, position);
+        } else {
+            if self.is_recording() {
+                self.history[self.current_input][position] = sample;
+                self.repeat_into_extension(self.current_input, position);
+            }
+            if matches!(self.input_fill_start, Some(start) if position >= start) {
+                self.history[self.current_output][position] = sample;
+            }
+        }
+    }
+
+    /// Play the live input instead of the last loop, keeping as much input
+    /// history as the loops do, so that ops reach as far back. This
+    /// allocates, so don't call it from an audio callback.
+    pub fn start_passthrough(self: &mut Self) {
+        if self.passthrough.is_none() {
+            self.start_passthrough_with(Passthrough::new(self.passthrough_length()));
+        }
+    }
+
+    /// Like `start_passthrough()`, with a `Passthrough` made beforehand, as
+    /// long as `passthrough_length()`. Returns it if passthrough was already
+    /// on, to drop after letting go of these buffers.
+    pub fn start_passthrough_with(self: &mut Self, passthrough: Passthrough) -> Option<Passthrough> {
+        if self.passthrough.is_some() {
+            return Some(passthrough);
+        }
+        info!("Passthrough");
+        self.passthrough = Some(passthrough);
+        return None;
+    }
+
+    /// Input history that passthrough needs, as much as the loops keep.
+    pub fn passthrough_length(self: &Self) -> SamplePosition {
+        return self.buffer_length() * self.history.len();
+    }
+
+    /// Go back to playing the last loop.
+    pub fn stop_passthrough(self: &mut Self) {
+        self.take_passthrough();
+    }
+
+    /// Like `stop_passthrough()`, but returns the input history to drop
+    /// after letting go of these buffers.
+    pub fn take_passthrough(self: &mut Self) -> Option<Passthrough> {
+        info!("Stop passthrough");
+        return self.passthrough.take();
+    }
+
+    /// Capture the output for one loop, starting from the next loop boundary,
+    /// then hold it as the loop being played.
+    pub fn bounce(self: &mut Self) {
+        if self.bounce == Bounce::Off {
+            info!("Bounce at next loop");
+            // Allocate here, rather than in the audio callback.
+            self.bounce_buffer.resize(self.buffer_length(), 0.0);
+            self.bounce = Bounce::Armed;
+        }
+    }
+
+    pub fn is_bouncing(self: &Self) -> bool {
+        return self.bounce != Bounce::Off;
+    }
+
+    // True if the processed output needs passing to `print_output()`.
+    pub fn is_printing(self: &Self) -> bool {
+        return self.bounce == Bounce::Capturing ||
+               matches!(self.overdub, Some(overdub) if overdub.print > 0.0);
+    }
+
+    // Print processed output back into the loop being played, from `position`,
+    // or capture it if we are bouncing.
+    pub fn print_output(self: &mut Self, position: SamplePosition, samples: &[Sample]) {
+        if self.bounce == Bounce::Capturing {
+            self.bounce_buffer[position..position + samples.len()].copy_from_slice(samples);
+        }
+        if let Some(overdub) = self.overdub {
+            for (i, s) in samples.iter().enumerate() {
+                let buffer = &mut self.history[self.current_output];
+                buffer[position + i] = soft_limit(buffer[position + i] + s * overdub.print);
+                self.repeat_into_extension(self.current_output, position + i);
+            }
+        }
+    }
+
+    /// Arm punch-in recording. Until the punch is done, the loop is held and
+    /// the input passes through to the output. Buffers grow to fit the
+    /// recording if needed, so don't call this from an audio callback.
+    pub fn arm_punch(self: &mut Self, punch: Punch) {
+        let space = match punch.buffer_length() > self.buffer_length() {
+            true => LoopSpace::new(punch.buffer_length(), self.loop_count()),
+            false => LoopSpace::default(),
+        };
+        self.arm_punch_into(punch, space);
+    }
+
+    /// Like `arm_punch()`, but the buffers grow into `space`, made with
+    /// `LoopSpace::new(punch.buffer_length(), loop_count)`, as for
+    /// `resize_loop_into()`.
+    pub fn arm_punch_into(self: &mut Self, punch: Punch, space: LoopSpace) -> LoopSpace {
+        info!("Punch: armed");
+        let space = self.grow_into(punch.buffer_length(), space);
+        self.overdub = None;
+        self.bounce = Bounce::Off;
+        self.freeze();
+        self.punch = Some(punch);
+        return space;
+    }
+
+    /// Punch out at the end of the current bar.
+    pub fn punch_out(self: &mut Self) {
+        if let Some(punch) = &mut self.punch {
+            info!("Punch: out at end of bar");
+            punch.stop();
+        }
+    }
+
+    // True while the punch is armed, counting in or recording.
+    pub fn is_punching(self: &Self) -> bool {
+        return matches!(self.punch, Some(punch) if !punch.is_done());
+    }
+
+    // Pass one input sample, arriving at `clock`, to the punch recording.
+    pub fn punch_sample(self: &mut Self, clock: SamplePosition, sample: Sample) {
+        let buffer_length = self.buffer_length();
+        let punch = match &mut self.punch {
+            Some(punch) if !punch.is_done() => punch,
+            _ => return,
+        };
+
+        if let Some(position) = punch.process(clock, buffer_length) {
+            self.history[self.current_input][position] = sample;
+        }
+
+        if let Some((start, loop_length)) = punch.recorded_loop() {
+            // Play what we recorded straight away, and hold it.
+            self.current_output = self.current_input;
+            self.current_input = (self.current_input + 1) % self.history.len();
+            self.record_length = loop_length;
+            self.play_length = loop_length;
+            self.pending_record_length = None;
+            self.pending_play_length = None;
+            self.input_fill_start = None;
+            self.record_restart = Some(start);
+        }
+    }
+
+    // Count-in click to play at `clock`, while punching.
+    pub fn punch_click(self: &Self, clock: SamplePosition) -> Sample {
+        return self.punch.map_or(0.0, |punch| punch.click(clock));
+    }
+
+    // Queue an input sample to be played as the dry output.
+    pub fn queue_input(self: &mut Self, sample: Sample) {
+        if self.monitor.len() >= MAX_MONITOR_SAMPLES {
+            self.monitor.pop_front();
+        }
+        self.monitor.push_back(sample);
+    }
+
+    // Next input sample to play as the dry output, one for each output sample.
+    pub fn take_input(self: &mut Self) -> Sample {
+        return self.monitor.pop_front().unwrap_or(0.0);
+    }
+
+    /// Once a punch is done, return the play clock where the new loop
+    /// starts and its length, once only.
+    pub fn take_punched_loop(self: &mut Self) -> Option<(SamplePosition, SamplePosition)> {
+        let recorded_loop = self.punch.and_then(|punch| punch.recorded_loop());
+        if recorded_loop.is_some() {
+            self.punch = None;
+        }
+        return recorded_loop;
+    }
+
+    // Play clock that the record position should count from, after a punch.
+    pub fn take_record_restart(self: &mut Self) -> Option<SamplePosition> {
+        return self.record_restart.take();
+    }
+
+    /// Replace the loop being played and the loops before it with `loops`,
+    /// newest first, and hold them. Loops that don't fit in the history are
+    /// dropped, and any older than the ones given are silent. This
+    /// allocates, so don't call it from an audio callback.
+    pub fn load_loops(self: &mut Self, loops: &[Buffer], loop_length: SamplePosition) {
+        let space = LoopSpace::with_loops(loops, loop_length, self.buffer_length(), self.loop_count());
+        self.load_loops_from(space, loop_length);
+    }
+
+    /// Like `load_loops()`, but the loops are laid out beforehand with
+    /// `LoopSpace::with_loops()`, and swapped in. Returns the old loops, to
+    /// drop after letting go of these buffers.
+    pub fn load_loops_from(self: &mut Self, mut space: LoopSpace, loop_length: SamplePosition) -> LoopSpace {
+        info!("Load loops of {} samples", loop_length);
+        let depth = self.history.len();
+        let buffer_length = self.buffer_length().max(loop_length);
+        if space.loops.len() != depth || space.loops.iter().any(|buffer| buffer.len() != buffer_length) {
+            warn!("Allocating loops of {} samples while loading", buffer_length);
+            space = LoopSpace::with_loops(&space.loops, loop_length, self.buffer_length(), depth);
+        }
+        if self.current_input == self.current_output {
+            self.current_input = (self.current_output + 1) % depth;
+        }
+        // Keep the loop being recorded, the rest are replaced.
+        if buffer_length != self.buffer_length() {
+            let recording = &self.history[self.current_input];
+            space.loops[depth - 1][..recording.len()].copy_from_slice(recording);
+            std::mem::swap(&mut self.history[self.current_input], &mut space.loops[depth - 1]);
+        }
+        for loops_back in 0..depth - 1 {
+            let index = (self.current_output + depth - loops_back) % depth;
+            std::mem::swap(&mut self.history[index], &mut space.loops[loops_back]);
+        }
+
+        self.record_length = loop_length;
+        self.play_length = loop_length;
+        self.pending_record_length = None;
+        self.pending_play_length = None;
+        self.input_fill_start = None;
+        self.overdub = None;
+        self.bounce = Bounce::Off;
+        self.punch = None;
+        self.record_restart = None;
+        self.freeze();
+        return space;
+    }
+
+    /// The loop being played, followed by older loops, newest first.
+    /// The loop being recorded is not included, unless it is also the one
+    /// being played, which happens when nothing is recording the input.
+    pub fn output_history(self: &Self) -> OutputHistory<'_> {
+        let depth = self.history.len();
+        let older = (1..depth)
+            .map(|loops_back| (self.current_output + depth - loops_back) % depth)
+            .take_while(|&index| index != self.current_input);
+        let mut history = OutputHistory { loops: [&[]; MAX_HISTORY_DEPTH], len: 0 };
+        for index in std::iter::once(self.current_output).chain(older).take(MAX_HISTORY_DEPTH) {
+            history.loops[history.len] = self.history[index].as_slice();
+            history.len += 1;
+        }
+        return history;
+    }
+}
+
+/// Loops to play from, newest first, see `LoopBuffers::output_history()`.
+/// They are kept in a fixed array, so the audio callback can get them
+/// without allocating.
+pub struct OutputHistory<'a> {
+    loops: [&'a [Sample]; MAX_HISTORY_DEPTH],
+    len: usize,
+}
+
+impl<'a> std::ops::Deref for OutputHistory<'a> {
+    type Target = [&'a [Sample]];
+
+    fn deref(&self) -> &Self::Target {
+        return &self.loops[..self.len];
+    }
+}
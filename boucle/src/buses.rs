@@ -0,0 +1,55 @@
+//! Output buses, so the glitches can be processed separately from the input.
+
+use crate::Sample;
+
+/// Number of buses in a `Frame`, which is how many channels they take.
+pub const BUS_COUNT: usize = 4;
+
+/// Wet only, which is how Boucle has always sounded.
+pub const DEFAULT_DRY_WET: f32 = 1.0;
+
+/// One sample of output on each bus.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+pub struct Frame {
+    // Dry input and wet output mixed together.
+    pub main: Sample,
+    pub dry: Sample,
+    pub wet: Sample,
+    // Wet output while an op is active, otherwise silence.
+    pub ops: Sample,
+}
+
+impl Frame {
+    // `dry_wet` goes from 0.0 for only the input to 1.0 for only the output.
+    pub fn new(dry: Sample, wet: Sample, op_active: bool, dry_wet: f32) -> Self {
+        Frame {
+            main: dry * (1.0 - dry_wet) + wet * dry_wet,
+            dry,
+            wet,
+            ops: if op_active { wet } else { 0.0 },
+        }
+    }
+
+    /// The buses in channel order: main, dry, wet, ops.
+    pub fn buses(self: &Self) -> [Sample; BUS_COUNT] {
+        return [self.main, self.dry, self.wet, self.ops];
+    }
+
+    // Add dry input to a frame that only has the wet side of the mix.
+    pub fn add_dry(self: &mut Self, dry: Sample, dry_wet: f32) {
+        self.main += dry * (1.0 - dry_wet);
+        self.dry += dry;
+    }
+
+    // Add another frame into this one, at `gain`.
+    pub fn mix(self: &mut Self, other: &Frame, gain: f32) {
+        self.main += other.main * gain;
+        self.dry += other.dry * gain;
+        self.wet += other.wet * gain;
+        self.ops += other.ops * gain;
+    }
+}
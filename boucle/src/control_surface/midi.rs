@@ -1,17 +1,22 @@
 // Helpers to map various interface types to Boucle operations.
 
 pub mod op1;
+pub mod slicer;
 
+use crate::buffers::DEFAULT_OVERDUB_FEEDBACK;
+use crate::buffers::Overdub;
+use crate::event::Command;
 use crate::event::StateChange;
 use crate::ops::Operation;
 
-type MidiNote = u8;
+pub type MidiNote = u8;
 
 #[allow(non_upper_case_globals)]
 #[allow(unused)]
 mod note {
     use super::MidiNote;
 
+    pub const NOTE_C3: MidiNote = 36;
     pub const NOTE_C4: MidiNote = 48;
     pub const NOTE_Db4: MidiNote = 49;
     pub const NOTE_D4: MidiNote = 50;
@@ -51,6 +56,24 @@ fn is_note_off(midi_status: u8) -> bool {
     return (midi_status & 0xF0) == 0x80;
 }
 
+fn is_control_change(midi_status: u8) -> bool {
+    return (midi_status & 0xF0) == 0xB0;
+}
+
+/// Sustain pedal, which holds (freezes) the loop while pressed.
+pub const CC_SUSTAIN: u8 = 64;
+
+/// Sostenuto pedal, which overdubs onto the loop while pressed.
+pub const CC_SOSTENUTO: u8 = 66;
+
+/// Hold 2 pedal, which bounces the output into the loop when pressed.
+pub const CC_HOLD_2: u8 = 69;
+
+/// Map note velocity to the probability that an op fires.
+pub fn velocity_to_probability(velocity: u8) -> f32 {
+    return (velocity.min(127) as f32) / 127.0;
+}
+
 /// Trait to share code between control surfaces that process MIDI events.
 ///
 /// In most cases a MIDI control surface only needs to implement map_midi_note().
@@ -68,4 +91,105 @@ pub trait MidiControlSurface {
     fn map_midi_note(self: &Self, _note: MidiNote) -> Operation {
         return Operation::NoOp;
     }
+
+    fn map_midi_command(self: &Self, status: u8, data1: u8, data2: u8) -> Option<Command> {
+        if is_control_change(status) && data1 == CC_SUSTAIN {
+            return Some(if data2 >= 64 { Command::Freeze } else { Command::Unfreeze });
+        }
+        if is_control_change(status) && data1 == CC_SOSTENUTO {
+            if data2 >= 64 {
+                return Some(Command::Overdub(Overdub { feedback: DEFAULT_OVERDUB_FEEDBACK, print: 0.0 }));
+            }
+            return Some(Command::StopOverdub);
+        }
+        if is_control_change(status) && data1 == CC_HOLD_2 && data2 >= 64 {
+            return Some(Command::Bounce);
+        }
+        return None;
+    }
+}
+
+/// Control surface that maps notes to ops from a table, so a mapping can be
+/// saved and loaded, for example in a session.
+///
+/// With the `serde` feature, a mapping is serialized as a list of notes, each
+/// with the fields of its op, for example
+/// `{"notes": [{"note": 53, "op": "jump", "offset": -8.0}]}`.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "NoteMapTable", into = "NoteMapTable"))]
+pub struct NoteMap {
+    // Sorted by note.
+    notes: Vec<(MidiNote, Operation)>,
+}
+
+// How a `NoteMap` is serialized.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct NoteMapTable {
+    notes: Vec<MappedNote>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MappedNote {
+    note: MidiNote,
+    #[serde(flatten)]
+    operation: Operation,
+}
+
+#[cfg(feature = "serde")]
+impl From<NoteMapTable> for NoteMap {
+    fn from(table: NoteMapTable) -> Self {
+        NoteMap::new(table.notes.into_iter().map(|mapped| (mapped.note, mapped.operation)).collect())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<NoteMap> for NoteMapTable {
+    fn from(map: NoteMap) -> Self {
+        NoteMapTable {
+            notes: map.notes.into_iter().map(|(note, operation)| MappedNote { note, operation }).collect(),
+        }
+    }
+}
+
+impl NoteMap {
+    pub fn new(mut notes: Vec<(MidiNote, Operation)>) -> Self {
+        notes.sort_by_key(|(note, _)| *note);
+        notes.dedup_by_key(|(note, _)| *note);
+        NoteMap { notes }
+    }
+
+    /// Copy the note mapping of another control surface.
+    pub fn from_surface(surface: &dyn MidiControlSurface) -> Self {
+        let notes = (0..=127)
+            .map(|note| (note, surface.map_midi_note(note)))
+            .filter(|(_, operation)| *operation != Operation::NoOp)
+            .collect();
+        NoteMap { notes }
+    }
+
+    pub fn notes(self: &Self) -> &[(MidiNote, Operation)] {
+        return &self.notes;
+    }
+
+    /// This mapping with the notes of `other` added, replacing any notes
+    /// that both map.
+    pub fn overlay(self: &Self, other: &NoteMap) -> Self {
+        let mut notes = other.notes.clone();
+        notes.extend(self.notes.iter().filter(|(note, _)| other.notes.binary_search_by_key(note, |(n, _)| *n).is_err()));
+        return NoteMap::new(notes);
+    }
+}
+
+impl MidiControlSurface for NoteMap {
+    fn map_midi_note(self: &Self, note: MidiNote) -> Operation {
+        return match self.notes.binary_search_by_key(&note, |(n, _)| *n) {
+            Ok(index) => self.notes[index].1,
+            Err(_) => Operation::NoOp,
+        };
+    }
 }
@@ -17,7 +17,7 @@ impl MidiControlSurface for Op1 {
             note::NOTE_G4 => Operation::Jump { offset: BeatFraction::from(-2.0) },
             note::NOTE_Ab4 => Operation::Jump { offset: BeatFraction::from(-1.0) },
             note::NOTE_A4 => Operation::Jump { offset: BeatFraction::from(-0.5) },
-            note::NOTE_Bb4 => Operation::NoOp,
+            note::NOTE_Bb4 => Operation::PastLoop { loops_back: 1 },
             note::NOTE_B4 => Operation::Jump { offset: BeatFraction::from(-0.25) },
 
             note::NOTE_C5 => Operation::Repeat { loop_size: BeatFraction::from(0.0625) },
@@ -26,14 +26,14 @@ impl MidiControlSurface for Op1 {
             note::NOTE_Eb5 => Operation::Repeat { loop_size: BeatFraction::from(0.5) },
 
             note::NOTE_E5 => Operation::Reverse,
-            note::NOTE_F5 => Operation::NoOp,
+            note::NOTE_F5 => Operation::Random,
 
             note::NOTE_Gb5 => Operation::Repeat { loop_size: BeatFraction::from(1.0) },
             note::NOTE_G5 => Operation::Repeat { loop_size: BeatFraction::from(2.0) },
             note::NOTE_Ab5 => Operation::Repeat { loop_size: BeatFraction::from(4.0) },
             note::NOTE_A5 => Operation::Repeat { loop_size: BeatFraction::from(8.0) },
 
-            note::NOTE_Bb5 => Operation::NoOp,
+            note::NOTE_Bb5 => Operation::PastLoop { loops_back: 2 },
 
             note::NOTE_B5 => Operation::Jump { offset: BeatFraction::from(0.25) },
             note::NOTE_C6 => Operation::Jump { offset: BeatFraction::from(0.5) },
@@ -0,0 +1,34 @@
+// Chromatic keyboard map that plays slices of the loop, one per key.
+
+use crate::Operation;
+use crate::slices::SliceMode;
+use super::note;
+use super::MidiControlSurface;
+use super::MidiNote;
+
+/// Key for the first slice, by default. Slices go up chromatically from
+/// there, below the keys that the OP-1 map uses.
+pub const DEFAULT_FIRST_NOTE: MidiNote = note::NOTE_C3;
+
+/// Keys for slicing at transients, which aren't known until the loop is
+/// played. Keys past the last transient go round to the first.
+pub const DEFAULT_TRANSIENT_SLICES: usize = 16;
+
+pub struct Slicer {
+    pub mode: SliceMode,
+    pub first_note: MidiNote,
+    pub slices: usize,
+}
+
+impl MidiControlSurface for Slicer {
+    fn map_midi_note(self: &Self, note: MidiNote) -> Operation {
+        if note < self.first_note || (note - self.first_note) as usize >= self.slices {
+            return Operation::NoOp;
+        }
+        let index = (note - self.first_note) as usize;
+        return match self.mode {
+            SliceMode::Beats(size) => Operation::PlaySlice { size, index },
+            SliceMode::Transients => Operation::PlayTransientSlice { index },
+        };
+    }
+}
@@ -6,125 +6,94 @@ use cpal::traits::{DeviceTrait};
 use log::*;
 
 use crate::Boucle;
-use crate::buffers::{Buffer, InputBuffer,LoopBuffers};
+use crate::Sample;
+use crate::buses::BUS_COUNT;
+use crate::buses::Frame;
+use crate::loop_station::LoopStation;
+use crate::loop_station::MAX_BLOCK_LENGTH;
+
+/// Return a valid cpal output configuration for the given Boucle config,
+/// with at least `channels` channels if the device has them.
+/// Panic if no config is found.
+pub fn get_audio_config(boucle: &Boucle, device: &cpal::Device, channels: u16) -> cpal::SupportedStreamConfig {
+    let supported_configs: Vec<cpal::SupportedStreamConfigRange> = device.supported_output_configs()
+        .expect("error while querying configs")
+        .collect();
+    let supported_config = supported_configs.iter()
+        .find(|config| config.channels() >= channels)
+        .or_else(|| supported_configs.first())
+        .expect("no supported config")
+        .clone()
+        .with_sample_rate(cpal::SampleRate(boucle.sample_rate));
+    info!("audio config: {:?}", supported_config);
+    return supported_config;
+}
 
-/// Return a valid cpal configuration for the given Boucle config.
+/// Return a valid cpal input configuration for the given Boucle config.
 /// Panic if no config is found.
-pub fn get_audio_config(boucle: &Boucle, device: &cpal::Device) -> cpal::SupportedStreamConfig {
-    let mut supported_configs_range = device.supported_output_configs()
+pub fn get_input_audio_config(boucle: &Boucle, device: &cpal::Device) -> cpal::SupportedStreamConfig {
+    let mut supported_configs_range = device.supported_input_configs()
         .expect("error while querying configs");
     let supported_config = supported_configs_range.next()
         .expect("no supported config")
         .with_sample_rate(cpal::SampleRate(boucle.sample_rate));
-    info!("audio config: {:?}", supported_config);
+    info!("audio input config: {:?}", supported_config);
     return supported_config;
 }
 
-/// Open a cpal input stream for 'device', and start recording input into given buffers.
+/// Open a cpal input stream for 'device', and start recording input into
+/// the tracks of the given loop station. Only the first channel is used.
 pub fn open_in_stream<T: cpal::Sample>(device: cpal::Device,
                                        config: cpal::StreamConfig,
-                                       buffers_rc: Arc<Mutex<LoopBuffers>>) -> Box<cpal::Stream> {
+                                       station_rc: Arc<Mutex<LoopStation>>) -> Box<cpal::Stream> {
+    let channels = config.channels.max(1) as usize;
+    let mut input: Vec<Sample> = Vec::with_capacity(MAX_BLOCK_LENGTH);
     return Box::new(device.build_input_stream(
         &config,
         move |data: &[T], _: &cpal::InputCallbackInfo| {
-            let mut buffers = buffers_rc.lock().unwrap();
-
-            let buffer_length = buffers.input_a.len();
-
-            let mut record_pos = buffers.record_pos.clone();
-
-            {
-                let mut record_buffer: &mut Buffer = match buffers.current_input {
-                    InputBuffer::A => &mut buffers.input_a,
-                    InputBuffer::B => &mut buffers.input_b,
-                };
-
-                for &s in data {
-                    record_buffer[record_pos] = cpal::Sample::from(&s);
-                    record_pos += 1;
-                    if record_pos >= buffer_length {
-                        if buffers.current_input == InputBuffer::A {
-                            buffers.current_input = InputBuffer::B;
-                            record_buffer = &mut buffers.input_b;
-                        } else {
-                            buffers.current_input = InputBuffer::A;
-                            record_buffer = &mut buffers.input_a;
-                        }
-                        debug!("Record buffer flip");
-                        record_pos = 0;
-                    }
-                }
-            }
-
-            buffers.record_pos = record_pos;
+            input.clear();
+            input.extend(data.chunks(channels).map(|frame| -> Sample { cpal::Sample::from(&frame[0]) }));
+            station_rc.lock().unwrap().record(&input);
         },
         move |err| { warn!("{}", err) }
     ).unwrap());
 }
 
-/// Open a cpal output stream for 'device', and start processing the given buffers,
-/// using the controller assigned to the given Boucle instance.
+/// Open a cpal output stream for 'device', and start playing the mix of the
+/// tracks of the given loop station. With `buses`, the channels are the
+/// main, dry, wet and ops buses in turn, otherwise every channel is main.
 pub fn open_out_stream<T: cpal::Sample>(device: cpal::Device,
                                         config: cpal::StreamConfig,
-                                        boucle_rc: Arc<Mutex<Boucle>>,
-                                        buffers_rc: Arc<Mutex<LoopBuffers>>) -> Box<cpal::Stream> {
+                                        station_rc: Arc<Mutex<LoopStation>>,
+                                        buses: bool) -> Box<cpal::Stream> {
+    let channels = config.channels.max(1) as usize;
+    if buses && channels < BUS_COUNT {
+        warn!("Output has {} channels, so only the first {} buses are played", channels, channels);
+    }
+    let mut output: Vec<Frame> = Vec::with_capacity(MAX_BLOCK_LENGTH);
     return Box::new(device.build_output_stream(
         &config,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            let mut boucle = boucle_rc.lock().unwrap();
-            let mut buffers = buffers_rc.lock().unwrap();
-
-            let buffer_length = buffers.input_a.len();
-
-            let mut play_clock = buffers.play_clock.clone();
-            {
-                let mut in_buffer = match buffers.current_output {
-                    InputBuffer::A => &buffers.input_a,
-                    InputBuffer::B => &buffers.input_b,
-                };
-
-                let mut out_pos = 0;
-                let play_pos = play_clock % buffer_length;
-                let span = std::cmp::min(buffer_length - play_pos, data.len());
-                debug!("Play clock {} pos {}/{} span {} (total data {})", play_clock, play_pos, buffer_length, span, data.len());
-
-                let ops = boucle.event_recorder.ops_for_period(play_clock, span);
-                boucle.process_buffer(&in_buffer, play_clock, span,
-                                      &ops, &mut |s| {
-                    data[out_pos] = cpal::Sample::from(&s);
-                    out_pos += 1;
-                });
-                play_clock += span;
-
-                if out_pos < data.len() {
-                    // Flip buffer and continue
-                    let span_2 = data.len() - span;
-                    debug!("play buffer flip");
-
-                    if buffers.current_output == InputBuffer::A {
-                        buffers.current_output = InputBuffer::B;
-                        in_buffer = &mut buffers.input_b;
-                    } else {
-                        buffers.current_output = InputBuffer::A;
-                        in_buffer = &mut buffers.input_a;
-                    }
-
-                    let ops = boucle.event_recorder.ops_for_period(play_clock, span_2);
-                    boucle.process_buffer(&in_buffer, play_clock, span_2,
-                                          &ops, &mut |s| {
-                        data[out_pos] = cpal::Sample::from(&s);
-                        out_pos += 1;
-                    });
-                    play_clock += span_2;
+            let mut station = station_rc.lock().unwrap();
+
+            output.clear();
+            output.resize(data.len() / channels, Frame::default());
+            station.play(&mut output);
+            for (out, frame) in data.chunks_mut(channels).zip(output.iter()) {
+                let bus_samples = frame.buses();
+                for (channel, d) in out.iter_mut().enumerate() {
+                    let s = match buses {
+                        true => bus_samples.get(channel).copied().unwrap_or(0.0),
+                        false => frame.main,
+                    };
+                    *d = cpal::Sample::from(&s);
                 }
             }
 
-            buffers.play_clock = play_clock;
-
             // Performer responds to what they hear.
             // The MIDI events we receive are therefore treated as relative
             // to the last thing the performer heard.
-            boucle.event_recorder.set_event_sync_point(Instant::now(), play_clock);
+            station.set_event_sync_point(Instant::now());
         },
         move |err| { warn!("{}", err) }
     ).unwrap());
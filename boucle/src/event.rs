@@ -1,3 +1,9 @@
+use crate::SamplePosition;
+use crate::buffers::Overdub;
+use crate::buffers::ResizeFill;
+
+#[derive(Clone)]
+#[derive(Copy)]
 #[derive(Debug)]
 #[derive(PartialEq)]
 pub enum StateChange {
@@ -5,3 +11,27 @@ pub enum StateChange {
     On,
     Off,
 }
+
+/// Commands that control the looper itself, rather than an operation.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Command {
+    Freeze,
+    Unfreeze,
+    Overdub(Overdub),
+    StopOverdub,
+    // Capture one loop of output and hold it as the new loop.
+    Bounce,
+    // Change the loop length, in samples, from the next loop.
+    ResizeLoop { loop_length: SamplePosition, fill: ResizeFill },
+    // Arm punch-in recording after a count-in, stopping after `bars` if given.
+    Punch { count_in_bars: u32, bars: Option<u32> },
+    PunchOut,
+    // Play the live input, with ops playing from its recent history.
+    Passthrough,
+    StopPassthrough,
+    // Mix of input and output, from 0.0 for dry to 1.0 for wet.
+    DryWet(f32),
+}
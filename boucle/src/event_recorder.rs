@@ -9,17 +9,35 @@ use crate::event::StateChange;
 use crate::ops::Operation;
 use crate::op_sequence;
 use crate::op_sequence::OpSequence;
+use crate::ops;
+use crate::random::Random;
 
 use log::*;
 
 use std::cmp::max;
 use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::time::{Instant};
 
+// Most finished ops to keep as automation. Older ones are dropped.
+pub(crate) const MAX_AUTOMATION_ENTRIES: usize = 4096;
+
+// Keep a finished op as automation, with its whole duration.
+fn push_automation(automation: &mut VecDeque<op_sequence::Entry>, entry: &op_sequence::Entry, end: SamplePosition) {
+    if automation.len() >= MAX_AUTOMATION_ENTRIES {
+        automation.pop_front();
+    }
+    automation.push_back(op_sequence::Entry {
+        duration: Some(end - end.min(entry.start)),
+        ..entry.clone()
+    });
+}
+
 struct RecordedEvent {
     time: SamplePosition,
     state_change: StateChange,
     operation: Operation,
+    probability: f32,
 }
 
 pub struct EventRecorder {
@@ -30,9 +48,20 @@ pub struct EventRecorder {
     active_reverse: Option<op_sequence::Entry>,
     active_jumps: HashMap<BeatFraction, op_sequence::Entry>,
     active_repeats: HashMap<BeatFraction, op_sequence::Entry>,
+    active_random: Option<op_sequence::Entry>,
+    active_past_loops: HashMap<usize, op_sequence::Entry>,
+    // Slice ops, each held until the same op is turned off.
+    active_slices: Vec<op_sequence::Entry>,
+
+    random_ops: Vec<(Operation, f32)>,
+    random: Random,
 
     event_sync_time: Instant,
     event_sync_sample_position: SamplePosition,
+
+    // Ops the performer has played, for saving in a session. Made with room
+    // for them all, so keeping them doesn't allocate.
+    automation: VecDeque<op_sequence::Entry>,
 }
 
 impl EventRecorder {
@@ -43,9 +72,39 @@ impl EventRecorder {
             active_reverse: None,
             active_jumps: HashMap::new(),
             active_repeats: HashMap::new(),
+            active_random: None,
+            active_past_loops: HashMap::new(),
+            active_slices: Vec::new(),
+            random_ops: ops::default_random_ops(),
+            random: Random::new(0),
             event_sync_time: Instant::now(),
             event_sync_sample_position: 0,
+            automation: VecDeque::with_capacity(MAX_AUTOMATION_ENTRIES),
+        }
+    }
+
+    pub fn set_seed(self: &mut Self, seed: u64) {
+        self.random = Random::new(seed);
+    }
+
+    // Set the candidate ops for `Operation::Random`, with their relative weights.
+    pub fn set_random_ops(self: &mut Self, random_ops: Vec<(Operation, f32)>) {
+        self.random_ops = random_ops;
+    }
+
+    /// Pick one of the candidate ops for `Operation::Random`, by weight.
+    pub fn pick_random_op(self: &mut Self) -> Operation {
+        let total_weight: f32 = self.random_ops.iter().map(|(_, weight)| weight).sum();
+        let roll = self.random.next_f32() * total_weight;
+
+        let mut threshold = 0.0;
+        for (operation, weight) in &self.random_ops {
+            threshold += weight;
+            if roll < threshold {
+                return *operation;
+            }
         }
+        return Operation::NoOp;
     }
 
     pub fn set_event_sync_point(self: &mut Self, time: Instant, sample_position: SamplePosition) {
@@ -54,6 +113,11 @@ impl EventRecorder {
         self.event_sync_sample_position = sample_position;
     }
 
+    /// Ops that were played and have finished, oldest first.
+    pub fn automation(self: &Self) -> &VecDeque<op_sequence::Entry> {
+        return &self.automation;
+    }
+
     fn time_to_sample_position(self: &Self,
                                time: Instant) -> SamplePosition {
         let duration = time - self.event_sync_time;
@@ -66,14 +130,38 @@ impl EventRecorder {
                         timestamp: std::time::Instant,
                         state_change: StateChange,
                         operation: Operation) {
+        self.record_event_with_probability(timestamp, state_change, operation, 1.0);
+    }
+
+    // Record a control event for an op that only fires some of the time.
+    // The probability is taken from the 'On' event.
+    pub fn record_event_with_probability(self: &mut Self,
+                                         timestamp: std::time::Instant,
+                                         state_change: StateChange,
+                                         operation: Operation,
+                                         probability: f32) {
         if state_change == StateChange::NoChange {
             return;
         }
 
         let time = self.time_to_sample_position(timestamp);
-        info!("recorded event {:?} {:?} at pos {} clock {:?}", state_change, operation, time, timestamp);
+        info!("recorded event {:?} {:?} ({}) at pos {} clock {:?}", state_change, operation, probability, time, timestamp);
+        self.record_event_at(time, state_change, operation, probability);
+    }
+
+    // Record a control event at a known sample position, for events that
+    // Boucle generates itself rather than receiving from a performer.
+    pub fn record_event_at(self: &mut Self,
+                           time: SamplePosition,
+                           state_change: StateChange,
+                           operation: Operation,
+                           probability: f32) {
+        if state_change == StateChange::NoChange {
+            return;
+        }
+
         self.event_buffer.push(RecordedEvent {
-            time, state_change, operation
+            time, state_change, operation, probability
         });
     }
 
@@ -100,11 +188,13 @@ impl EventRecorder {
                                 start: event_sample_position,
                                 duration: None,
                                 operation: event.operation,
+                                probability: event.probability,
                             });
                         } else if event.state_change == StateChange::Off && matches!(self.active_reverse, Some(_)) {
                             let mut op_entry: op_sequence::Entry = self.active_reverse.take().unwrap();
                             info!("{:#?}: reverse off (sample pos {}, op start {})", event.time, event_sample_position, op_entry.start);
                             op_entry.duration = Some(event_sample_position - max(op_entry.start, period_start));
+                            push_automation(&mut self.automation, &op_entry, event_sample_position);
                             op_sequence.push(op_entry);
                         } else {
                             warn!("Warning: mismatched state change for {:?}", event.operation);
@@ -118,11 +208,13 @@ impl EventRecorder {
                                 start: event_sample_position,
                                 duration: None,
                                 operation: event.operation,
+                                probability: event.probability,
                             });
                         } else if event.state_change == StateChange::Off && self.active_repeats.contains_key(&loop_size) {
                             info!("{:#?}: repeat({}) on", event_sample_position, loop_size);
                             let mut op_entry: op_sequence::Entry = self.active_repeats.remove(&loop_size).unwrap();
                             op_entry.duration = Some(event_sample_position - max(op_entry.start, period_start));
+                            push_automation(&mut self.automation, &op_entry, event_sample_position);
                             op_sequence.push(op_entry);
                         } else {
                             warn!("Warning: mismatched state change for {:?}", event.operation);
@@ -136,11 +228,77 @@ impl EventRecorder {
                                 start: event_sample_position,
                                 duration: None,
                                 operation: event.operation,
+                                probability: event.probability,
                             });
                         } else if event.state_change == StateChange::Off && self.active_jumps.contains_key(&offset) {
                             info!("{:#?}: jumps({}) on", event_sample_position, offset);
                             let mut op_entry: op_sequence::Entry = self.active_jumps.remove(&offset).unwrap();
                             op_entry.duration = Some(event_sample_position - max(op_entry.start, period_start));
+                            push_automation(&mut self.automation, &op_entry, event_sample_position);
+                            op_sequence.push(op_entry);
+                        } else {
+                            warn!("Warning: mismatched state change for {:?}", event.operation);
+                        }
+                    },
+                    Operation::Random => {
+                        if event.state_change == StateChange::On && self.active_random.is_none() {
+                            let probability = event.probability;
+                            let operation = self.pick_random_op();
+                            info!("{:#?}: random on, picked {:?}", event_sample_position, operation);
+                            self.active_random = Some(op_sequence::Entry {
+                                start: event_sample_position,
+                                duration: None,
+                                operation,
+                                probability,
+                            });
+                        } else if event.state_change == StateChange::Off && matches!(self.active_random, Some(_)) {
+                            let mut op_entry: op_sequence::Entry = self.active_random.take().unwrap();
+                            info!("{:#?}: random off, releasing {:?}", event_sample_position, op_entry.operation);
+                            op_entry.duration = Some(event_sample_position - max(op_entry.start, period_start));
+                            push_automation(&mut self.automation, &op_entry, event_sample_position);
+                            op_sequence.push(op_entry);
+                        } else {
+                            warn!("Warning: mismatched state change for {:?}", event.operation);
+                        }
+                    },
+                    Operation::PastLoop { loops_back } => {
+                        if event.state_change == StateChange::On && !self.active_past_loops.contains_key(&loops_back) {
+                            info!("{:#?}: past-loop({}) on", event_sample_position, loops_back);
+                            self.active_past_loops.insert(loops_back, op_sequence::Entry {
+                                start: event_sample_position,
+                                duration: None,
+                                operation: event.operation,
+                                probability: event.probability,
+                            });
+                        } else if event.state_change == StateChange::Off && self.active_past_loops.contains_key(&loops_back) {
+                            info!("{:#?}: past-loop({}) off", event_sample_position, loops_back);
+                            let mut op_entry: op_sequence::Entry = self.active_past_loops.remove(&loops_back).unwrap();
+                            op_entry.duration = Some(event_sample_position - max(op_entry.start, period_start));
+                            push_automation(&mut self.automation, &op_entry, event_sample_position);
+                            op_sequence.push(op_entry);
+                        } else {
+                            warn!("Warning: mismatched state change for {:?}", event.operation);
+                        }
+                    },
+                    Operation::PlaySlice { .. } |
+                    Operation::ShuffleSlices { .. } |
+                    Operation::ReverseSlices { .. } |
+                    Operation::StretchSlice { .. } |
+                    Operation::PlayTransientSlice { .. } => {
+                        let active = self.active_slices.iter().position(|entry| entry.operation == event.operation);
+                        if event.state_change == StateChange::On && active.is_none() {
+                            info!("{:#?}: {} on", event_sample_position, event.operation);
+                            self.active_slices.push(op_sequence::Entry {
+                                start: event_sample_position,
+                                duration: None,
+                                operation: event.operation,
+                                probability: event.probability,
+                            });
+                        } else if let (StateChange::Off, Some(index)) = (event.state_change, active) {
+                            info!("{:#?}: {} off", event_sample_position, event.operation);
+                            let mut op_entry: op_sequence::Entry = self.active_slices.remove(index);
+                            op_entry.duration = Some(event_sample_position - max(op_entry.start, period_start));
+                            push_automation(&mut self.automation, &op_entry, event_sample_position);
                             op_sequence.push(op_entry);
                         } else {
                             warn!("Warning: mismatched state change for {:?}", event.operation);
@@ -171,6 +329,21 @@ impl EventRecorder {
             debug!("{:#?}: jumps on since", op_entry.start);
             op_sequence.push(op_entry.clone());
         }
+
+        if let Some(op_entry) = &self.active_random {
+            debug!("{:#?}: random on since", op_entry.start);
+            op_sequence.push(op_entry.clone());
+        }
+
+        for op_entry in self.active_past_loops.values() {
+            debug!("{:#?}: past-loop on since", op_entry.start);
+            op_sequence.push(op_entry.clone());
+        }
+
+        for op_entry in self.active_slices.iter() {
+            debug!("{:#?}: {} on since", op_entry.start, op_entry.operation);
+            op_sequence.push(op_entry.clone());
+        }
         return op_sequence;
     }
 }
@@ -1,11 +1,24 @@
+pub mod accompanist;
+pub mod audio_trigger;
 pub mod buffers;
+pub mod buses;
 pub mod control_surface;
 pub mod cpal_helpers;
 pub mod event;
 pub mod event_recorder;
+pub mod loop_station;
+pub mod mini_notation;
+pub mod modulation;
 pub mod ops;
 pub mod op_sequence;
+pub mod passthrough;
 pub mod patterns;
+pub mod punch;
+pub mod random;
+pub mod session;
+pub mod slices;
+pub mod snapshot;
+pub mod transients;
 pub mod units;
 mod tests;
 
@@ -15,94 +28,283 @@ use log::*;
 
 pub use control_surface::midi::MidiControlSurface;
 pub use event_recorder::EventRecorder;
+pub use modulation::Modulator;
 pub use ops::Operation;
 pub use op_sequence::OpSequence;
+pub use passthrough::Passthrough;
+pub use patterns::PatternPlayer;
+pub use transients::Markers;
+pub use transients::MarkerRequest;
+pub use transients::SnapMode;
 pub use units::BeatFraction;
 pub use units::Sample;
 pub use units::SampleOffset;
 pub use units::SamplePosition;
 
+/// With the `serde` feature, the config is serialized with its fields by
+/// name. Missing fields take their default value.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Config {
     pub sample_rate: u32,
     pub beat_fraction_to_samples: f32,
+    // Seed for random choices, such as whether an op with a probability fires.
+    pub seed: u64,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             sample_rate: 44100,
-            beat_fraction_to_samples: 44100.0 / 16.0   /* Assumes 1 beat = 1 second at 44.1KHz */
+            beat_fraction_to_samples: 44100.0 / 16.0,   /* Assumes 1 beat = 1 second at 44.1KHz */
+            seed: 0,
         }
     }
 }
 
 pub struct Boucle {
     pub event_recorder: EventRecorder,
+    pub pattern_player: PatternPlayer,
+    pub modulator: Modulator,
     pub sample_rate: u32,
     pub beat_fraction_to_samples: f32,
     pub loop_length: SamplePosition,
+    // Play clock at the start of a loop, so the loop can change length
+    // without the clock jumping.
+    pub loop_start: SamplePosition,
+    pub seed: u64,
+    // What `Jump` and `Repeat` snap to, if anything, and whether transient
+    // slices are played. Markers for them are found in the loop being played,
+    // and are stale when it changes until new ones are handed back.
+    snap: Option<SnapMode>,
+    transient_slices: bool,
+    markers: Markers,
+    markers_stale: bool,
 }
 
 impl Boucle {
     pub fn new(config: &Config, loop_length: SamplePosition) -> Boucle {
+        let mut event_recorder = EventRecorder::new(config.sample_rate);
+        event_recorder.set_seed(config.seed);
         return Boucle {
-            event_recorder: EventRecorder::new(config.sample_rate),
+            event_recorder,
+            pattern_player: PatternPlayer::new(config.beat_fraction_to_samples * 16.0),
+            modulator: Modulator::new(config.beat_fraction_to_samples * 16.0),
             sample_rate: config.sample_rate,
             beat_fraction_to_samples: config.beat_fraction_to_samples,
             loop_length: loop_length,
+            loop_start: 0,
+            seed: config.seed,
+            snap: None,
+            transient_slices: false,
+            markers: Markers::default(),
+            markers_stale: false,
         }
     }
 
     // When increasing loop length, old recordings may play from the buffer.
     // It's up to caller to erase these if desired before updating loop length.
+    // Use `LoopBuffers::resize_loop()` and `restart_loop()` to do that at a
+    // loop boundary.
     pub fn set_loop_length(self: &mut Self, loop_length: SamplePosition) {
         self.loop_length = loop_length;
     }
 
+    // Start a loop of the given length at `play_clock`.
+    pub fn restart_loop(self: &mut Self, loop_length: SamplePosition, play_clock: SamplePosition) {
+        info!("Loop length {} from clock {}", loop_length, play_clock);
+        self.loop_length = loop_length;
+        self.loop_start = play_clock;
+    }
+
+    // Position in the loop buffer for a given play clock, before any ops.
+    pub fn loop_position(self: &Boucle, play_clock: SamplePosition) -> SamplePosition {
+        return (play_clock - self.loop_start.min(play_clock)) % self.loop_length;
+    }
+
     pub fn loop_length(self: &Boucle) -> SamplePosition {
         return self.loop_length;
     }
 
+    /// Snap `Jump` and `Repeat` to the transients or zero crossings of
+    /// `loop_buffer`, or stop snapping with None.
+    pub fn set_snap(self: &mut Self, snap: Option<SnapMode>, loop_buffer: &[Sample]) {
+        self.snap = snap;
+        self.find_markers(loop_buffer);
+    }
+
+    pub fn snap(self: &Self) -> Option<SnapMode> {
+        return self.snap;
+    }
+
+    /// Play `PlayTransientSlice` ops from the transients of `loop_buffer`.
+    pub fn set_transient_slices(self: &mut Self, transient_slices: bool, loop_buffer: &[Sample]) {
+        self.transient_slices = transient_slices;
+        self.find_markers(loop_buffer);
+    }
+
+    fn marker_request(self: &Self) -> MarkerRequest {
+        return MarkerRequest { snap: self.snap, onsets: self.transient_slices };
+    }
+
+    /// Find the markers in `loop_buffer` now. This is too slow for the audio
+    /// thread, which calls `loop_changed()` instead.
+    pub fn find_markers(self: &mut Self, loop_buffer: &[Sample]) {
+        let length = self.loop_length.min(loop_buffer.len());
+        self.markers = transients::find_markers(&loop_buffer[..length], self.marker_request(), self.sample_rate);
+        self.markers_stale = false;
+    }
+
+    /// Note that the loop being played changed, so the markers need finding
+    /// again. Ops play unsnapped until they are handed back.
+    pub fn loop_changed(self: &mut Self) {
+        self.markers_stale = self.marker_request() != MarkerRequest::default();
+    }
+
+    /// What to find in the loop being played, if the markers are stale. The
+    /// loop is then up to the caller to copy and find the markers in with
+    /// `transients::find_markers()`, off the audio thread.
+    pub fn take_marker_request(self: &mut Self) -> Option<MarkerRequest> {
+        if !self.markers_stale {
+            return None;
+        }
+        self.markers_stale = false;
+        return Some(self.marker_request());
+    }
+
+    /// Use markers found for a `take_marker_request()`, and return the old
+    /// ones to be dropped away from the audio thread. If the loop changed
+    /// again meanwhile, there's another request for it.
+    pub fn replace_markers(self: &mut Self, markers: Markers) -> Markers {
+        return std::mem::replace(&mut self.markers, markers);
+    }
+
+    // Return ops from live control and from active patterns, for a given time period,
+    // with modulation applied.
+    pub fn ops_for_period(self: &mut Self,
+                          period_start: SamplePosition,
+                          period_duration: SamplePosition) -> OpSequence {
+        let mut ops = self.event_recorder.ops_for_period(period_start, period_duration);
+        ops.extend_from_slice(self.pattern_player.ops_for_period(period_start, period_duration));
+        self.modulator.apply(&mut ops, period_start);
+        return ops;
+    }
+
     pub fn next_sample(self: &Boucle, loop_buffer: &[Sample], op_sequence: &OpSequence, play_clock: SamplePosition) -> Sample {
-        let loop_length = self.loop_length();
-        let mut transformed_clock: SampleOffset = play_clock.try_into().unwrap();
+        return self.next_sample_from_history(&[loop_buffer], op_sequence, play_clock);
+    }
+
+    // Sum of the transforms of the ops active at `play_clock`, and how many
+    // loops back `PastLoop` ops ask for. Markers are in the loop buffer, so
+    // they are only used with `from_loop`, when playing from it.
+    fn transform(self: &Boucle, op_sequence: &OpSequence, play_clock: SamplePosition, from_loop: bool) -> (SampleOffset, usize) {
+        let mut transform: SampleOffset = 0;
+        let mut loops_back: usize = 0;
+        let loop_position = self.loop_position(play_clock);
+        let snap = ops::Snap { points: &self.markers.snap_points, loop_start: self.loop_start };
+        let snap = if from_loop && !self.markers.snap_points.is_empty() { Some(&snap) } else { None };
+        let onsets: &[SamplePosition] = if from_loop { &self.markers.onsets } else { &[] };
 
         for entry in op_sequence {
-            if op_sequence::op_active(entry, play_clock) {
-                let transform = ops::get_transform(
+            if op_sequence::op_active(entry, play_clock) &&
+               op_sequence::op_fires(entry, play_clock, self.beat_fraction_to_samples, self.seed) {
+                if let Operation::PastLoop { loops_back: n } = entry.operation {
+                    loops_back += n;
+                }
+                transform += ops::get_transform(
+                    entry.operation,
+                    self.beat_fraction_to_samples,
+                    play_clock,
+                    entry.start,
+                    self.loop_length(),
+                    snap
+                );
+                transform += slices::get_transform(
                     entry.operation,
                     self.beat_fraction_to_samples,
                     play_clock,
                     entry.start,
-                    loop_length
+                    loop_position,
+                    self.loop_length(),
+                    self.seed,
+                    onsets
                 );
-                transformed_clock += transform;
             }
         }
+        return (transform, loops_back);
+    }
 
-        let loop_position;
-        if transformed_clock < 0 {
+    // True if any op changes the sound at `play_clock`.
+    pub fn op_active(self: &Boucle, op_sequence: &OpSequence, play_clock: SamplePosition) -> bool {
+        return op_sequence.iter().any(|entry| {
+            entry.operation != Operation::NoOp &&
+            op_sequence::op_active(entry, play_clock) &&
+            op_sequence::op_fires(entry, play_clock, self.beat_fraction_to_samples, self.seed)
+        });
+    }
+
+    // Like `next_sample()`, but `PastLoop` ops can play from older loops.
+    // The history starts with the loop being played, followed by older loops.
+    pub fn next_sample_from_history(self: &Boucle, history: &[&[Sample]], op_sequence: &OpSequence, play_clock: SamplePosition) -> Sample {
+        let loop_length = self.loop_length();
+        let mut transformed_clock: SampleOffset = play_clock.try_into().unwrap();
+        transformed_clock -= self.loop_start as SampleOffset;
+        let (transform, loops_back) = self.transform(op_sequence, play_clock, true);
+        transformed_clock += transform;
+
+        let loop_position = if transformed_clock < 0 {
             debug!("transforming {}", transformed_clock.saturating_abs() as usize%loop_length);
-            loop_position = (loop_length - ((transformed_clock.saturating_abs() as SamplePosition) % loop_length)) % loop_length;
+            (loop_length - ((transformed_clock.saturating_abs() as SamplePosition) % loop_length)) % loop_length
         } else {
-            loop_position = (transformed_clock as SamplePosition) % loop_length;
-        }
+            (transformed_clock as SamplePosition) % loop_length
+        };
 
+        // Play the oldest loop we have, if asked for one that's older.
+        let loop_buffer = history[loops_back.min(history.len() - 1)];
         return loop_buffer[loop_position];
     }
 
+    // Sample to play at `play_clock` in passthrough mode, where the input at
+    // `input_position` is what's happening now. With no ops active this is
+    // the live input; ops read from the recent past instead.
+    pub fn next_passthrough_sample(self: &Boucle, passthrough: &Passthrough, op_sequence: &OpSequence,
+                                   play_clock: SamplePosition, input_position: SamplePosition) -> Sample {
+        let loop_length = self.loop_length() as SampleOffset;
+        let (transform, loops_back) = self.transform(op_sequence, play_clock, false);
+        let mut offset = transform - (loops_back as SampleOffset) * loop_length;
+        if offset > 0 {
+            // We can't play the future, so jumping forward goes round the loop.
+            offset -= (offset + loop_length - 1) / loop_length * loop_length;
+        }
+        let position = input_position as SampleOffset + offset;
+        if position < 0 {
+            return 0.0;
+        }
+        return passthrough.read(position as SamplePosition);
+    }
+
     pub fn process_buffer(self: &Boucle,
                           loop_buffer: &[Sample],
                           play_clock: SamplePosition,
                           out_buffer_length: SamplePosition,
                           ops: &OpSequence,
                           write_sample: &mut dyn FnMut(Sample)) {
+        self.process_history(&[loop_buffer], play_clock, out_buffer_length, ops, write_sample);
+    }
+
+    // Like `process_buffer()`, with older loops available to `PastLoop` ops.
+    pub fn process_history(self: &Boucle,
+                           history: &[&[Sample]],
+                           play_clock: SamplePosition,
+                           out_buffer_length: SamplePosition,
+                           ops: &OpSequence,
+                           write_sample: &mut dyn FnMut(Sample)) {
         let loop_length = self.loop_length();
-        info!("Buffer is {:#?} samples long, loop is {:#?} playing at {:?} for {:#?}",
-              loop_buffer.len(), loop_length, play_clock, out_buffer_length);
+        info!("Buffer is {:#?} samples long ({} loops of history), loop is {:#?} playing at {:?} for {:#?}",
+              history[0].len(), history.len(), loop_length, play_clock, out_buffer_length);
 
         for sample in 0..out_buffer_length {
-            let s = self.next_sample(&loop_buffer, ops, play_clock + sample);
+            let s = self.next_sample_from_history(history, ops, play_clock + sample);
             write_sample(s);
         }
     }
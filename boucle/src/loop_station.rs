@@ -0,0 +1,391 @@
+//! Several loops running side by side, each with its own buffers and ops,
+//! mixed to one output.
+//!
+//! Track loop lengths are given relative to a master loop, and don't have to
+//! be multiples of each other, so 3 beats can play against 4. All tracks
+//! follow one master clock, so they realign every so often.
+
+use crate::Boucle;
+use crate::Config;
+use crate::Sample;
+use crate::SamplePosition;
+use crate::buffers::LoopBuffers;
+use crate::buses::DEFAULT_DRY_WET;
+use crate::buses::Frame;
+use crate::buffers::create_buffers_with_history;
+use crate::event::StateChange;
+use crate::ops::Operation;
+
+use log::*;
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::time::Instant;
+
+/// Blocks up to this long are played without allocating.
+pub const MAX_BLOCK_LENGTH: usize = 8192;
+
+pub struct Track {
+    pub boucle: Boucle,
+    pub buffers: LoopBuffers,
+    pub mute: bool,
+    pub volume: f32,
+    // Scratch space for `play()`, for the input and the output to print.
+    dry: Vec<Sample>,
+    printed: Vec<Sample>,
+}
+
+impl Track {
+    pub fn new(config: &Config, loop_length: SamplePosition, history_depth: usize) -> Self {
+        Track {
+            boucle: Boucle::new(config, loop_length),
+            buffers: create_buffers_with_history(loop_length, history_depth),
+            mute: false,
+            volume: 1.0,
+            dry: Vec::with_capacity(MAX_BLOCK_LENGTH),
+            printed: Vec::with_capacity(MAX_BLOCK_LENGTH),
+        }
+    }
+
+    /// Record into the track when armed. When not armed, the track holds
+    /// and plays its current loop.
+    pub fn arm(self: &mut Self, armed: bool) {
+        if armed {
+            self.buffers.unfreeze();
+        } else {
+            self.buffers.freeze();
+        }
+    }
+
+    pub fn is_armed(self: &Self) -> bool {
+        return self.buffers.is_recording();
+    }
+
+    /// Record a block of input. Input is treated as arriving at the current
+    /// play position.
+    pub fn record(self: &mut Self, input: &[Sample]) {
+        let buffers = &mut self.buffers;
+        let mut record_pos = buffers.record_pos;
+        if let Some(start) = buffers.take_record_restart() {
+            // A punch just finished, so line recording up with the new loop.
+            let clock = buffers.play_clock;
+            record_pos = (clock - start.min(clock)) % buffers.record_length();
+        }
+
+        for &s in input {
+            buffers.record_sample(record_pos, s);
+            record_pos += 1;
+            if record_pos >= buffers.record_length() {
+                buffers.next_input();
+                debug!("Record buffer flip");
+                record_pos = 0;
+            }
+        }
+
+        buffers.record_pos = record_pos;
+
+        if let Some(passthrough) = &mut buffers.passthrough {
+            for &s in input {
+                passthrough.write(s);
+            }
+        }
+
+        let clock = buffers.play_clock;
+        for (i, &s) in input.iter().enumerate() {
+            buffers.queue_input(s);
+            buffers.punch_sample(clock + i, s);
+            buffers.audio_triggers.process_sample(s, clock + i);
+            if let Some(accompanist) = &mut buffers.accompanist {
+                accompanist.process_sample(s, clock + i);
+            }
+        }
+    }
+
+    /// Play the next `length` samples of the track on each bus, before mute
+    /// and volume. The dry input is left to the station, so the main bus
+    /// has only the wet side of the mix.
+    pub fn play(self: &mut Self, length: usize, write_frame: &mut dyn FnMut(Frame)) {
+        let boucle = &mut self.boucle;
+        let buffers = &mut self.buffers;
+        let dry_wet = buffers.dry_wet;
+        let dry = &mut self.dry;
+        dry.clear();
+        dry.extend((0..length).map(|_| buffers.take_input()));
+
+        let mut play_clock = buffers.play_clock;
+        if buffers.is_punching() {
+            // Pass the input through until the punch is done, on top of the
+            // dry input the station adds.
+            for (i, &s) in dry.iter().enumerate() {
+                let main = s * dry_wet + buffers.punch_click(play_clock + i);
+                write_frame(Frame { main, dry: 0.0, wet: 0.0, ops: 0.0 });
+            }
+            buffers.play_clock = play_clock + length;
+            return;
+        }
+        if let Some((start, loop_length)) = buffers.take_punched_loop() {
+            boucle.restart_loop(loop_length, start);
+        }
+
+        for entry in buffers.audio_triggers.take_ops(boucle.beat_fraction_to_samples) {
+            boucle.pattern_player.schedule(entry);
+        }
+        if let Some(accompanist) = &mut buffers.accompanist {
+            for entry in accompanist.take_ops() {
+                boucle.pattern_player.schedule(entry);
+            }
+        }
+
+        if let Some(passthrough) = &mut buffers.passthrough {
+            let input_position = passthrough.start_block(length);
+            let ops = boucle.ops_for_period(play_clock, length);
+            for i in 0..length {
+                let clock = play_clock + i;
+                let wet = boucle.next_passthrough_sample(passthrough, &ops, clock, input_position + i);
+                write_frame(Frame::new(0.0, wet, boucle.op_active(&ops, clock), dry_wet));
+            }
+
+            // Keep the loops going, to come back to.
+            let play_pos = boucle.loop_position(play_clock);
+            if play_pos + length >= boucle.loop_length() {
+                buffers.next_output();
+                let loop_start = play_clock + boucle.loop_length() - play_pos;
+                if buffers.play_length() != boucle.loop_length() {
+                    boucle.restart_loop(buffers.play_length(), loop_start);
+                }
+                boucle.loop_changed();
+            }
+            buffers.play_clock = play_clock + length;
+            return;
+        }
+
+        // Output to print back into the loop, when overdubbing or bouncing.
+        let mut printing = buffers.is_printing();
+        let printed = &mut self.printed;
+        printed.clear();
+
+        let mut out_pos = 0;
+        let loop_length = boucle.loop_length();
+        let play_pos = boucle.loop_position(play_clock);
+        let span = std::cmp::min(loop_length - play_pos, length);
+        debug!("Play clock {} pos {}/{} span {} (total data {})", play_clock, play_pos, loop_length, span, length);
+
+        let ops = boucle.ops_for_period(play_clock, span);
+        boucle.process_history(&buffers.output_history(), play_clock, span,
+                              &ops, &mut |s| {
+            let op_active = boucle.op_active(&ops, play_clock + out_pos);
+            write_frame(Frame::new(0.0, s, op_active, dry_wet));
+            out_pos += 1;
+            if printing { printed.push(s) };
+        });
+        buffers.print_output(play_pos, printed);
+        printed.clear();
+        play_clock += span;
+
+        if play_pos + length >= loop_length {
+            // Flip buffer and continue, even if the block ends right at the loop end
+            let span_2 = length - span;
+            debug!("play buffer flip");
+            buffers.next_output();
+            printing = buffers.is_printing();
+            if buffers.play_length() != boucle.loop_length() {
+                boucle.restart_loop(buffers.play_length(), play_clock);
+            }
+            boucle.loop_changed();
+
+            let ops = boucle.ops_for_period(play_clock, span_2);
+            boucle.process_history(&buffers.output_history(), play_clock, span_2,
+                                  &ops, &mut |s| {
+                let op_active = boucle.op_active(&ops, play_clock + out_pos - span);
+                write_frame(Frame::new(0.0, s, op_active, dry_wet));
+                out_pos += 1;
+                if printing { printed.push(s) };
+            });
+            buffers.print_output(0, printed);
+            play_clock += span_2;
+        }
+
+        buffers.play_clock = play_clock;
+    }
+}
+
+/// Which tracks an op applies to.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Route {
+    All,
+    Track(usize),
+}
+
+pub struct LoopStation {
+    pub tracks: Vec<Track>,
+    master_length: SamplePosition,
+    // Master clock, which every track's play clock follows.
+    clock: SamplePosition,
+    // Where ops that are on were sent, so they are turned off in the same place.
+    active_routes: Vec<(Operation, Route)>,
+    // Mix of the dry input, which is played once however many tracks there are.
+    pub dry_wet: f32,
+    // Input waiting to be played on the dry bus.
+    monitor: VecDeque<Sample>,
+}
+
+// Greatest common divisor, and x and y such that a*x + b*y = gcd.
+fn extended_gcd(a: i128, b: i128) -> (i128, i128, i128) {
+    if b == 0 {
+        return (a, 1, 0);
+    }
+    let (g, x, y) = extended_gcd(b, a % b);
+    return (g, y, x - (a / b) * y);
+}
+
+impl LoopStation {
+    pub fn new(master_length: SamplePosition) -> Self {
+        LoopStation {
+            tracks: Vec::new(),
+            master_length,
+            clock: 0,
+            active_routes: Vec::new(),
+            dry_wet: DEFAULT_DRY_WET,
+            monitor: VecDeque::with_capacity(MAX_BLOCK_LENGTH),
+        }
+    }
+
+    /// Set the dry/wet mix, from 0.0 for only the input to 1.0 for only
+    /// the loops, for the station and every track.
+    pub fn set_dry_wet(self: &mut Self, dry_wet: f32) {
+        self.dry_wet = dry_wet;
+        for track in self.tracks.iter_mut() {
+            track.buffers.dry_wet = dry_wet;
+        }
+    }
+
+    pub fn clock(self: &Self) -> SamplePosition {
+        return self.clock;
+    }
+
+    pub fn master_length(self: &Self) -> SamplePosition {
+        return self.master_length;
+    }
+
+    /// Add a track `length` master loops long, and return its index. The
+    /// length doesn't need to be whole, for example 0.75 plays 3 beats
+    /// against a 4 beat master loop.
+    /// Only the first track starts armed; the others hold silence until armed.
+    pub fn add_track(self: &mut Self, config: &Config, length: f32, history_depth: usize) -> usize {
+        let loop_length = ((self.master_length as f32 * length).round() as SamplePosition).max(1);
+        let mut track = Track::new(config, loop_length, history_depth);
+        track.buffers.play_clock = self.clock;
+        if !self.tracks.is_empty() {
+            track.arm(false);
+        }
+        info!("Track {}: {} samples", self.tracks.len(), loop_length);
+        self.tracks.push(track);
+        return self.tracks.len() - 1;
+    }
+
+    /// Indexes of the tracks given by `route`, empty if there is no such track.
+    pub fn route_tracks(self: &Self, route: Route) -> std::ops::Range<usize> {
+        return match route {
+            Route::All => 0..self.tracks.len(),
+            Route::Track(index) => index.min(self.tracks.len())..(index + 1).min(self.tracks.len()),
+        };
+    }
+
+    /// Record a control event for the tracks given by `route`. An op is
+    /// turned off on the tracks it was turned on for, wherever `route` says.
+    pub fn record_event(self: &mut Self, route: Route, timestamp: Instant, state_change: StateChange,
+                        operation: Operation, probability: f32) {
+        let route = match state_change {
+            StateChange::On => {
+                self.active_routes.push((operation, route));
+                route
+            },
+            StateChange::Off => match self.active_routes.iter().position(|(op, _)| *op == operation) {
+                Some(index) => self.active_routes.remove(index).1,
+                None => route,
+            },
+            StateChange::NoChange => return,
+        };
+        for index in self.route_tracks(route) {
+            self.tracks[index].boucle.event_recorder.record_event_with_probability(
+                timestamp, state_change, operation, probability);
+        }
+    }
+
+    /// Master clock where every track is next at the start of its loop,
+    /// or None if they never line up.
+    pub fn next_realignment(self: &Self) -> Option<SamplePosition> {
+        // Solve t = loop_start (mod loop_length) for every track, by the
+        // Chinese remainder theorem.
+        let mut start: i128 = 0;
+        let mut period: i128 = 1;
+        for track in self.tracks.iter() {
+            let loop_start = track.boucle.loop_start as i128;
+            let loop_length = track.boucle.loop_length() as i128;
+            let (g, x, _) = extended_gcd(period, loop_length);
+            if (loop_start - start) % g != 0 {
+                return None;
+            }
+            let steps = ((loop_start - start) / g % (loop_length / g)) * x % (loop_length / g);
+            start += period * steps;
+            period = period / g * loop_length;
+            if period > u64::MAX as i128 {
+                return None;
+            }
+            start = start.rem_euclid(period);
+        }
+
+        let clock = self.clock as i128;
+        let realignment = match clock <= start {
+            true => start,
+            false => start + (clock - start + period - 1) / period * period,
+        };
+        return realignment.try_into().ok();
+    }
+
+    /// Record a block of input into every track.
+    pub fn record(self: &mut Self, input: &[Sample]) {
+        for track in self.tracks.iter_mut() {
+            track.buffers.play_clock = self.clock;
+            track.record(input);
+        }
+        for &s in input {
+            if self.monitor.len() >= MAX_BLOCK_LENGTH {
+                self.monitor.pop_front();
+            }
+            self.monitor.push_back(s);
+        }
+    }
+
+    /// Play every track and mix them into `output`, with the dry input
+    /// added once.
+    pub fn play(self: &mut Self, output: &mut [Frame]) {
+        for frame in output.iter_mut() {
+            *frame = Frame::default();
+        }
+        for track in self.tracks.iter_mut() {
+            let gain = if track.mute { 0.0 } else { track.volume };
+            let mut out_pos = 0;
+            track.buffers.play_clock = self.clock;
+            track.play(output.len(), &mut |frame| {
+                output[out_pos].mix(&frame, gain);
+                out_pos += 1;
+            });
+        }
+        for frame in output.iter_mut() {
+            frame.add_dry(self.monitor.pop_front().unwrap_or(0.0), self.dry_wet);
+        }
+        self.clock += output.len();
+    }
+
+    /// MIDI events we receive are treated as relative to the last thing
+    /// the performer heard.
+    pub fn set_event_sync_point(self: &mut Self, now: Instant) {
+        for track in self.tracks.iter_mut() {
+            track.boucle.event_recorder.set_event_sync_point(now, self.clock);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Compact pattern language for writing glitch patterns.
+//!
+//! In the style of TidalCycles mini-notation, a string describes one bar.
+//! The steps of the bar are separated by spaces and share the bar equally.
+//!
+//!   * `r`          reverse
+//!   * `jN`         jump by N beats, e.g. `j-1`, `j+1/2`
+//!   * `xN`         repeat a loop of N beats, e.g. `x1/4`
+//!   * `xC:N`       repeat a loop of N beats C times, e.g. `x4:1/8`
+//!   * `~`          rest
+//!   * `[a b]`      split one step into several
+//!   * `a*N`        play a step N times within its slot
+//!   * `a?`, `a?P`  play a step with 50% chance, or with probability P
+//!   * `a(P,S,R)`   play a step on P of S sub-steps, spread out evenly and
+//!                  shifted later by R sub-steps (R is optional)
+//!
+//! Ops last for their whole step, except `xC:N` which lasts `C * N` beats.
+//!
+//! Example: `"r ~ [j-1 j+1] x4:1/8"`, or `"r(3,8,1)"` for a Euclidean rhythm.
+
+use crate::BeatFraction;
+use crate::ops::Operation;
+use crate::ops::ParseError;
+use crate::op_sequence;
+use crate::op_sequence::OpSequence;
+use crate::patterns::Beats;
+use crate::patterns::EuclideanRhythm;
+use crate::patterns::Pattern;
+
+pub const DEFAULT_BEATS_PER_BAR: Beats = 4.0;
+
+// Limits, so a pattern typed in live can't overflow the stack or use up
+// the memory.
+const MAX_NESTING: usize = 16;
+const MAX_STEPS: usize = 1024;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Step {
+    Rest,
+    Op { operation: Operation, duration: Option<Beats>, probability: f32 },
+    Group(Vec<Step>),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct MiniNotation {
+    steps: Vec<Step>,
+    beats_per_bar: Beats,
+}
+
+fn parse_number(text: &str) -> Result<f32, ParseError> {
+    let text = text.strip_prefix('+').unwrap_or(text);
+    if let Some((numerator, denominator)) = text.split_once('/') {
+        let numerator = numerator.parse::<f32>()?;
+        let denominator = denominator.parse::<f32>()?;
+        if denominator == 0.0 {
+            return Err(ParseError { message: format!("division by zero in '{}'", text) });
+        }
+        return Ok(numerator / denominator);
+    }
+    return Ok(text.parse::<f32>()?);
+}
+
+// Loop sizes below a 16th of a beat would be 0 as a `BeatFraction`.
+fn parse_loop_size(text: &str) -> Result<f32, ParseError> {
+    let loop_size = parse_number(text)?;
+    if !(loop_size >= 1.0 / 16.0) {
+        return Err(ParseError { message: format!("repeat size '{}' is less than 1/16 of a beat", text) });
+    }
+    return Ok(loop_size);
+}
+
+fn parse_atom(text: &str) -> Result<Step, ParseError> {
+    if text == "~" {
+        return Ok(Step::Rest);
+    }
+
+    let name_length = text.chars().next().map_or(0, |c| c.len_utf8());
+    let (name, args) = text.split_at(name_length);
+    match name {
+        "r" if args.is_empty() => {
+            Ok(Step::Op { operation: Operation::Reverse, duration: None, probability: 1.0 })
+        },
+        "j" => {
+            let offset = parse_number(args)?;
+            Ok(Step::Op { operation: Operation::Jump { offset: BeatFraction::from(offset) }, duration: None, probability: 1.0 })
+        },
+        "x" => {
+            match args.split_once(':') {
+                Some((count, loop_size)) => {
+                    let count = count.parse::<u32>()?;
+                    let loop_size = parse_loop_size(loop_size)?;
+                    Ok(Step::Op {
+                        operation: Operation::Repeat { loop_size: BeatFraction::from(loop_size) },
+                        duration: Some(count as Beats * loop_size),
+                        probability: 1.0,
+                    })
+                },
+                None => {
+                    let loop_size = parse_loop_size(args)?;
+                    Ok(Step::Op { operation: Operation::Repeat { loop_size: BeatFraction::from(loop_size) }, duration: None, probability: 1.0 })
+                }
+            }
+        },
+        _ => Err(ParseError { message: format!("unknown step '{}'", text) }),
+    }
+}
+
+// Number of rests and ops in a step.
+fn step_count(step: &Step) -> usize {
+    return match step {
+        Step::Group(steps) => steps.iter().map(step_count).sum(),
+        _ => 1,
+    };
+}
+
+fn scale_probability(step: &mut Step, scale: f32) {
+    match step {
+        Step::Rest => {},
+        Step::Op { probability, .. } => *probability *= scale,
+        Step::Group(steps) => {
+            for step in steps.iter_mut() {
+                scale_probability(step, scale);
+            }
+        },
+    }
+}
+
+// Parse the `P,S,R` of a `(P,S,R)` suffix.
+fn parse_euclidean(args: &str) -> Result<EuclideanRhythm, ParseError> {
+    let args: Vec<&str> = args.split(',').collect();
+    if args.len() < 2 || args.len() > 3 {
+        return Err(ParseError { message: format!("expected '(PULSES,STEPS[,ROTATION])', got '({})'", args.join(",")) });
+    }
+    let pulses = args[0].parse::<u32>()?;
+    let steps = args[1].parse::<u32>()?;
+    let rotation = match args.get(2) {
+        Some(rotation) => rotation.parse::<u32>()?,
+        None => 0,
+    };
+    return EuclideanRhythm::new(steps, pulses, rotation);
+}
+
+// Apply `*N`, `?P` and `(P,S,R)` suffixes, if present.
+fn parse_modifiers(mut step: Step, suffix: &str) -> Result<Step, ParseError> {
+    let mut rest = suffix;
+    while !rest.is_empty() {
+        if !rest.starts_with(|c| c == '*' || c == '?' || c == '(') {
+            return Err(ParseError { message: format!("unexpected '{}'", rest) });
+        }
+        let end = match rest.strip_prefix('(') {
+            Some(args) => match args.find(')') {
+                Some(i) => i + 2,
+                None => return Err(ParseError { message: format!("missing ')' in '{}'", rest) }),
+            },
+            None => rest[1..].find(|c| c == '*' || c == '?' || c == '(').map_or(rest.len(), |i| i + 1),
+        };
+        let (modifier, remainder) = rest.split_at(end);
+        if let Some(count) = modifier.strip_prefix('*') {
+            let count = count.parse::<usize>()?;
+            if count.saturating_mul(step_count(&step)) > MAX_STEPS {
+                return Err(ParseError { message: format!("'*{}' makes more than {} steps", count, MAX_STEPS) });
+            }
+            step = Step::Group(vec!(step; count));
+        } else if let Some(probability) = modifier.strip_prefix('?') {
+            let probability = if probability.is_empty() { 0.5 } else { parse_number(probability)? };
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(ParseError { message: format!("'{}' is not a probability between 0 and 1", modifier) });
+            }
+            scale_probability(&mut step, probability);
+        } else if let Some(args) = modifier.strip_prefix('(').and_then(|args| args.strip_suffix(')')) {
+            let rhythm = parse_euclidean(args)?;
+            if (rhythm.steps() as usize).saturating_mul(step_count(&step)) > MAX_STEPS {
+                return Err(ParseError { message: format!("'{}' makes more than {} steps", modifier, MAX_STEPS) });
+            }
+            step = Step::Group((0..rhythm.steps()).map(|i| match rhythm.is_pulse(i) {
+                true => step.clone(),
+                false => Step::Rest,
+            }).collect());
+        }
+        rest = remainder;
+    }
+    return Ok(step);
+}
+
+// Parse steps up to the end of the text, or the end of the group when
+// `depth` is more than 0.
+fn parse_steps(chars: &mut std::iter::Peekable<std::str::Chars>, depth: usize) -> Result<Vec<Step>, ParseError> {
+    let in_group = depth > 0;
+    let mut steps = Vec::new();
+
+    loop {
+        match chars.peek() {
+            None => {
+                if in_group {
+                    return Err(ParseError { message: "missing ']'".to_string() });
+                }
+                return Ok(steps);
+            },
+            Some(c) if c.is_whitespace() => {
+                chars.next();
+            },
+            Some(']') => {
+                chars.next();
+                if !in_group {
+                    return Err(ParseError { message: "unexpected ']'".to_string() });
+                }
+                return Ok(steps);
+            },
+            Some('[') => {
+                chars.next();
+                if depth >= MAX_NESTING {
+                    return Err(ParseError { message: format!("more than {} levels of '['", MAX_NESTING) });
+                }
+                let group = Step::Group(parse_steps(chars, depth + 1)?);
+                let suffix = take_word(chars);
+                steps.push(parse_modifiers(group, &suffix)?);
+            },
+            Some(_) => {
+                let word = take_word(chars);
+                let (atom, suffix) = match word.find(|c| c == '*' || c == '?' || c == '(') {
+                    Some(index) => word.split_at(index),
+                    None => (word.as_str(), ""),
+                };
+                steps.push(parse_modifiers(parse_atom(atom)?, suffix)?);
+            },
+        }
+    }
+}
+
+fn take_word(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == '[' || c == ']' {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    return word;
+}
+
+fn compile_step(step: &Step, start: Beats, length: Beats, beats_to_samples: f32, ops: &mut OpSequence) {
+    match step {
+        Step::Rest => {},
+        Step::Op { operation, duration, probability } => {
+            let duration = duration.unwrap_or(length);
+            ops.push(op_sequence::Entry {
+                start: (start * beats_to_samples) as usize,
+                duration: Some((duration * beats_to_samples) as usize),
+                operation: *operation,
+                probability: *probability,
+            });
+        },
+        Step::Group(steps) => {
+            let step_length = length / steps.len() as Beats;
+            for (i, step) in steps.iter().enumerate() {
+                compile_step(step, start + i as Beats * step_length, step_length, beats_to_samples, ops);
+            }
+        },
+    }
+}
+
+impl MiniNotation {
+    pub fn parse(text: &str, beats_per_bar: Beats) -> Result<MiniNotation, ParseError> {
+        let steps = parse_steps(&mut text.chars().peekable(), 0)?;
+        if steps.is_empty() {
+            return Err(ParseError { message: "empty pattern".to_string() });
+        }
+        if steps.iter().map(step_count).sum::<usize>() > MAX_STEPS {
+            return Err(ParseError { message: format!("pattern has more than {} steps", MAX_STEPS) });
+        }
+        return Ok(MiniNotation { steps, beats_per_bar });
+    }
+
+    pub fn steps(self: &Self) -> &[Step] {
+        return &self.steps;
+    }
+
+    /// Compile the pattern to the ops for one bar, starting at `bar_start`.
+    pub fn ops_for_bar(self: &Self, bar_start: Beats, beats_to_samples: f32) -> OpSequence {
+        let mut ops = OpSequence::new();
+        let step_length = self.beats_per_bar / self.steps.len() as Beats;
+        for (i, step) in self.steps.iter().enumerate() {
+            compile_step(step, bar_start + i as Beats * step_length, step_length, beats_to_samples, &mut ops);
+        }
+        return ops;
+    }
+
+    /// Compile the pattern to ops for every bar that starts in the given period,
+    /// dropping any ops that would start after the period.
+    pub fn ops_between(self: &Self, start: Beats, duration: Beats, beats_to_samples: f32) -> OpSequence {
+        let end_sample = ((start + duration) * beats_to_samples) as usize;
+        let mut ops = OpSequence::new();
+        let mut bar_start = start;
+        while bar_start < start + duration {
+            ops.extend(self.ops_for_bar(bar_start, beats_to_samples)
+                .into_iter()
+                .filter(|entry| entry.start < end_sample));
+            bar_start += self.beats_per_bar;
+        }
+        return ops;
+    }
+}
+
+impl Pattern for MiniNotation {
+    fn ops_for_beat(self: &mut Self, beat: Beats, beats_to_samples: f32) -> OpSequence {
+        if beat % self.beats_per_bar == 0.0 {
+            return self.ops_for_bar(beat, beats_to_samples);
+        }
+        return OpSequence::new();
+    }
+}
@@ -0,0 +1,181 @@
+//! Automate operation parameters with tempo-synced LFOs.
+//!
+//! Each `Routing` connects one LFO to one parameter, scaled to a range.
+//! The `Modulator` applies all routings to the ops for each block of audio,
+//! using the LFO values at the start of the block.
+
+use crate::BeatFraction;
+use crate::SamplePosition;
+use crate::ops::Operation;
+use crate::ops::ParseError;
+use crate::op_sequence::OpSequence;
+use crate::random;
+
+use std::f32::consts::PI;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    SampleAndHold,
+    RandomWalk,
+}
+
+// `SpeedRamp` has no targets, as its speeds are not used yet.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Target {
+    RepeatLoopSize,
+    JumpOffset,
+    // Scales the probability of every op.
+    Probability,
+}
+
+/// Low frequency oscillator, synced to the beat.
+///
+/// Output is in the range 0.0 to 1.0. Random waveforms take a new value
+/// once per period, based on the seed, so they are the same for every render.
+pub struct Lfo {
+    waveform: Waveform,
+    period: BeatFraction,
+    seed: u64,
+
+    // Random walk position, cached so we don't walk from the start every block.
+    walk_cycle: u64,
+    walk_value: f32,
+}
+
+impl Lfo {
+    pub fn new(waveform: Waveform, period: BeatFraction, seed: u64) -> Self {
+        Lfo { waveform, period, seed, walk_cycle: 0, walk_value: 0.5 }
+    }
+
+    pub fn value_at(self: &mut Self, beat: f32) -> f32 {
+        let period = self.period.as_beats();
+        if period <= 0.0 {
+            return 0.0;
+        }
+        let cycle = (beat / period).floor().max(0.0) as u64;
+        let phase = (beat / period).fract();
+
+        match self.waveform {
+            Waveform::Sine => 0.5 + 0.5 * (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - (2.0 * phase - 1.0).abs(),
+            Waveform::Square => if phase < 0.5 { 1.0 } else { 0.0 },
+            Waveform::SampleAndHold => random::hash_f32(self.seed, &[cycle]),
+            Waveform::RandomWalk => {
+                if cycle < self.walk_cycle {
+                    self.walk_cycle = 0;
+                    self.walk_value = 0.5;
+                }
+                while self.walk_cycle < cycle {
+                    self.walk_cycle += 1;
+                    let step = (random::hash_f32(self.seed, &[self.walk_cycle]) - 0.5) * 0.5;
+                    // Reflect at the edges of the range.
+                    self.walk_value += step;
+                    if self.walk_value < 0.0 {
+                        self.walk_value = -self.walk_value;
+                    } else if self.walk_value > 1.0 {
+                        self.walk_value = 2.0 - self.walk_value;
+                    }
+                }
+                self.walk_value
+            },
+        }
+    }
+}
+
+/// Connect an LFO to an operation parameter.
+pub struct Routing {
+    pub lfo: Lfo,
+    pub target: Target,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Routing {
+    pub fn new(lfo: Lfo, target: Target, min: f32, max: f32) -> Self {
+        Routing { lfo, target, min, max }
+    }
+}
+
+/// Parse a routing of the form `TARGET WAVEFORM PERIOD MIN MAX`.
+///
+/// For example, `repeat-size sine 4 0.125 1` sweeps the loop size of every
+/// `Repeat` op between 1/8 and 1 beat, over a period of 4 beats.
+pub fn new_from_string(line: &str, seed: u64) -> Result<Routing, ParseError> {
+    let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+    if parts.len() != 5 {
+        return Err(ParseError { message: format!("expected 'TARGET WAVEFORM PERIOD MIN MAX', got '{}'", line) });
+    }
+
+    let target = match parts[0] {
+        "repeat-size" => Target::RepeatLoopSize,
+        "jump-offset" => Target::JumpOffset,
+        "probability" => Target::Probability,
+        _ => return Err(ParseError { message: format!("unknown LFO target '{}'", parts[0]) }),
+    };
+    let waveform = match parts[1] {
+        "sine" => Waveform::Sine,
+        "triangle" => Waveform::Triangle,
+        "square" => Waveform::Square,
+        "sample-and-hold" => Waveform::SampleAndHold,
+        "random-walk" => Waveform::RandomWalk,
+        _ => return Err(ParseError { message: format!("unknown LFO waveform '{}'", parts[1]) }),
+    };
+    let period = parts[2].parse::<f32>()?;
+    let min = parts[3].parse::<f32>()?;
+    let max = parts[4].parse::<f32>()?;
+
+    return Ok(Routing::new(Lfo::new(waveform, BeatFraction::from(period), seed), target, min, max));
+}
+
+pub struct Modulator {
+    routings: Vec<Routing>,
+    beats_to_samples: f32,
+}
+
+impl Modulator {
+    pub fn new(beats_to_samples: f32) -> Self {
+        Modulator { routings: Vec::new(), beats_to_samples }
+    }
+
+    pub fn add_routing(self: &mut Self, routing: Routing) {
+        self.routings.push(routing);
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.routings.is_empty();
+    }
+
+    // Apply modulation to the ops for a block that starts at `clock`.
+    pub fn apply(self: &mut Self, ops: &mut OpSequence, clock: SamplePosition) {
+        let beat = clock as f32 / self.beats_to_samples;
+
+        for routing in self.routings.iter_mut() {
+            let value = routing.min + routing.lfo.value_at(beat) * (routing.max - routing.min);
+
+            for entry in ops.iter_mut() {
+                match (routing.target, &mut entry.operation) {
+                    (Target::RepeatLoopSize, Operation::Repeat { loop_size }) => {
+                        // A repeat needs an inner loop of at least one 16th of a beat.
+                        *loop_size = BeatFraction::from(value.max(1.0 / 16.0));
+                    },
+                    (Target::JumpOffset, Operation::Jump { offset }) => {
+                        *offset = BeatFraction::from(value);
+                    },
+                    (Target::Probability, _) => {
+                        entry.probability *= value;
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+}
@@ -1,14 +1,36 @@
+use crate::BeatFraction;
+use crate::SampleOffset;
 use crate::SamplePosition;
+use crate::ops;
 use crate::ops::Operation;
+use crate::random;
 
 use std::fmt;
+use std::mem;
 
+/// With the `serde` feature, an entry is serialized with its fields by name,
+/// positions in samples, for example `{"start": 44100, "duration": 22050,
+/// "operation": {"op": "reverse"}, "probability": 1.0}`. `duration` may be
+/// left out or null for an op that lasts until the next one, and
+/// `probability` defaults to 1.
 #[derive(Clone)]
 #[derive(Debug)]
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Entry {
     pub start: SamplePosition,
+    #[cfg_attr(feature = "serde", serde(default))]
     pub duration: Option<SamplePosition>,
     pub operation: Operation,
+    // Chance that the op fires each time it would start. A `Repeat` rolls
+    // again on each cycle of its inner loop.
+    #[cfg_attr(feature = "serde", serde(default = "always"))]
+    pub probability: f32,
+}
+
+#[cfg(feature = "serde")]
+fn always() -> f32 {
+    1.0
 }
 
 impl fmt::Display for Entry {
@@ -17,6 +39,9 @@ impl fmt::Display for Entry {
             Some(duration) => format!("{:#?}", duration),
             None => format!("∞"),
         };
+        if self.probability < 1.0 {
+            return write!(f, "({:#?}->{}): {:?} ({:.0}%)", self.start, end, self.operation, self.probability * 100.0);
+        }
         return write!(f, "({:#?}->{}): {:?}", self.start, end, self.operation);
     }
 }
@@ -31,3 +56,159 @@ pub fn op_active(entry: &Entry, clock: SamplePosition) -> bool {
     };
     return started && !finished;
 }
+
+/// Where an op stops, or None if it lasts until it is replaced.
+pub fn op_end(entry: &Entry) -> Option<SamplePosition> {
+    return entry.duration.map(|duration| entry.start + duration);
+}
+
+// Decide whether an op fires at `clock`, according to its probability.
+//
+// The decision only depends on the seed, the op, its start time and the
+// repeat cycle, so it is the same however the audio is split into buffers,
+// and ops starting together decide separately.
+pub fn op_fires(entry: &Entry, clock: SamplePosition, beat_fraction_to_samples: f32, seed: u64) -> bool {
+    if entry.probability >= 1.0 {
+        return true;
+    }
+
+    let cycle = match entry.operation {
+        Operation::Repeat { loop_size } => {
+            let inner_loop_size = loop_size.as_sample_position(beat_fraction_to_samples);
+            if inner_loop_size > 0 { (clock - entry.start) / inner_loop_size } else { 0 }
+        },
+        _ => 0,
+    };
+    let id = ops::op_id(entry.operation);
+    return random::hash_f32(seed, &[id, entry.start as u64, cycle as u64]) < entry.probability;
+}
+
+// Transforms, for scripting variations of a sequence. Each returns a new
+// sequence and leaves the one it is given as it was.
+
+/// Move every entry `offset` samples later, or earlier if `offset` is
+/// negative. Entries moved to before 0 are cut to start at 0, and dropped if
+/// they would be over by then.
+pub fn shift(sequence: &[Entry], offset: SampleOffset) -> OpSequence {
+    let mut shifted = OpSequence::new();
+    for entry in sequence {
+        let start = entry.start as SampleOffset + offset;
+        if start >= 0 {
+            shifted.push(Entry { start: start as SamplePosition, ..entry.clone() });
+            continue;
+        }
+        match entry.duration {
+            None => shifted.push(Entry { start: 0, ..entry.clone() }),
+            Some(duration) if start + (duration as SampleOffset) > 0 => shifted.push(Entry {
+                start: 0,
+                duration: Some((start + duration as SampleOffset) as SamplePosition),
+                ..entry.clone()
+            }),
+            Some(_) => {},
+        }
+    }
+    return shifted;
+}
+
+/// Stretch the timing of a sequence by `factor`, from position 0. To play a
+/// sequence recorded at `old_bpm` at `new_bpm`, use `old_bpm / new_bpm`.
+/// Op arguments are in beats, so they follow the tempo as they are.
+pub fn scale(sequence: &[Entry], factor: f64) -> OpSequence {
+    let scale_position = |position: SamplePosition| (position as f64 * factor).round() as SamplePosition;
+    return sequence.iter().map(|entry| Entry {
+        start: scale_position(entry.start),
+        duration: entry.duration.map(scale_position),
+        ..entry.clone()
+    }).collect();
+}
+
+/// Move the start and end of each entry to the nearest step of a `grid`
+/// beat fraction. Entries are kept at least one step long.
+pub fn quantize(sequence: &[Entry], grid: BeatFraction, beat_fraction_to_samples: f32) -> OpSequence {
+    let step = grid.as_sample_position(beat_fraction_to_samples).max(1);
+    let nearest_step = |position: SamplePosition| (position + step / 2) / step * step;
+    return sequence.iter().map(|entry| {
+        let start = nearest_step(entry.start);
+        Entry {
+            start,
+            duration: op_end(entry).map(|end| nearest_step(end).saturating_sub(start).max(step)),
+            ..entry.clone()
+        }
+    }).collect();
+}
+
+/// Sort by start. Entries that start together stay in the order they were.
+pub fn sort(sequence: &mut OpSequence) {
+    sequence.sort_by_key(|entry| entry.start);
+}
+
+/// The entries of all of `sequences` as one sequence, sorted by start.
+pub fn merge(sequences: &[&[Entry]]) -> OpSequence {
+    let mut merged: OpSequence = sequences.iter().flat_map(|sequence| sequence.iter().cloned()).collect();
+    sort(&mut merged);
+    return merged;
+}
+
+/// The parts of the entries between `start` and `end`, with positions as
+/// they were. An entry that started before `start` is cut to start there,
+/// which restarts ops such as `Reverse` and `Repeat` from that point.
+pub fn trim(sequence: &[Entry], start: SamplePosition, end: SamplePosition) -> OpSequence {
+    let mut trimmed = OpSequence::new();
+    for entry in sequence {
+        let entry_start = entry.start.max(start);
+        let entry_end = op_end(entry).unwrap_or(end).min(end);
+        if entry_start < entry_end {
+            trimmed.push(Entry { start: entry_start, duration: Some(entry_end - entry_start), ..entry.clone() });
+        }
+    }
+    return trimmed;
+}
+
+/// The entries with an op that `keep` accepts, for example
+/// `filter(&ops, |op| matches!(op, Operation::Repeat { .. }))`.
+pub fn filter(sequence: &[Entry], keep: impl Fn(&Operation) -> bool) -> OpSequence {
+    return sequence.iter().filter(|entry| keep(&entry.operation)).cloned().collect();
+}
+
+/// The sequence played backwards over `length` samples, so an entry that
+/// ended `t` samples before `length` now starts at `t`. Entries are trimmed
+/// to `length` first, so ones that last until replaced end there.
+pub fn mirror(sequence: &[Entry], length: SamplePosition) -> OpSequence {
+    let mut mirrored: OpSequence = trim(sequence, 0, length).iter().map(|entry| Entry {
+        start: length - op_end(entry).unwrap(),
+        ..entry.clone()
+    }).collect();
+    sort(&mut mirrored);
+    return mirrored;
+}
+
+fn entries_overlap(a: &Entry, b: &Entry) -> bool {
+    let before_end = |start: SamplePosition, entry: &Entry| op_end(entry).map_or(true, |end| start < end);
+    let not_empty = |entry: &Entry| entry.duration != Some(0);
+    return not_empty(a) && not_empty(b) && before_end(a.start, b) && before_end(b.start, a);
+}
+
+/// Indexes of each pair of entries that are active at the same time.
+pub fn overlaps(sequence: &[Entry]) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for (i, a) in sequence.iter().enumerate() {
+        for (j, b) in sequence.iter().enumerate().skip(i + 1) {
+            if entries_overlap(a, b) {
+                pairs.push((i, j));
+            }
+        }
+    }
+    return pairs;
+}
+
+/// Indexes of each pair of entries with the same kind of op, such as two
+/// `Repeat`s, that are active at the same time. The engine adds their
+/// transforms together, which is rarely what was meant.
+pub fn conflicts(sequence: &[Entry]) -> Vec<(usize, usize)> {
+    return overlaps(sequence).into_iter()
+        .filter(|&(i, j)| {
+            let (a, b) = (&sequence[i].operation, &sequence[j].operation);
+            *a != Operation::NoOp && mem::discriminant(a) == mem::discriminant(b)
+        })
+        .collect();
+}
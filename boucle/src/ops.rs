@@ -1,31 +1,179 @@
 use crate::BeatFraction;
 use crate::SamplePosition;
 use crate::SampleOffset;
+use crate::random;
+use crate::transients;
 
 use std::fmt;
 use std::num;
 
 use log::*;
 
-
+/// With the `serde` feature, an op is serialized as a table with its name,
+/// as written in ops files, under `op` and its arguments by field name, for
+/// example `{"op": "repeat", "loop_size": 0.25}` or `{"op": "reverse"}`.
 #[derive(Clone)]
 #[derive(Copy)]
 #[derive(Debug)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "op", rename_all = "kebab-case"))]
 pub enum Operation {
     NoOp,
     Reverse,
     Repeat { loop_size: BeatFraction },
     Jump { offset: BeatFraction },
     SpeedRamp { start_speed: f32, end_speed: f32 },
+    // Picks one of a list of candidate ops each time it's switched on.
+    // The `EventRecorder` resolves it to a concrete op.
+    Random,
+    // Play from the loop recorded this many loops before the current one.
+    PastLoop { loops_back: usize },
+    // Ops on slices of the loop `size` long, see `slices`.
+    PlaySlice { size: BeatFraction, index: usize },
+    ShuffleSlices { size: BeatFraction },
+    ReverseSlices { size: BeatFraction },
+    StretchSlice { size: BeatFraction, index: usize, slices: usize },
+    // Loop the slice from one transient to the next, see `transients`.
+    PlayTransientSlice { index: usize },
+}
+
+// Written the way `operation_from_parts()` reads it, e.g. `repeat 0.25`.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::NoOp => write!(f, "no-op"),
+            Operation::Reverse => write!(f, "reverse"),
+            Operation::Repeat { loop_size } => write!(f, "repeat {}", loop_size.as_beats()),
+            Operation::Jump { offset } => write!(f, "jump {}", offset.as_beats()),
+            Operation::SpeedRamp { start_speed, end_speed } => write!(f, "speed-ramp {} {}", start_speed, end_speed),
+            Operation::Random => write!(f, "random"),
+            Operation::PastLoop { loops_back } => write!(f, "past-loop {}", loops_back),
+            Operation::PlaySlice { size, index } => write!(f, "play-slice {} {}", size.as_beats(), index),
+            Operation::ShuffleSlices { size } => write!(f, "shuffle-slices {}", size.as_beats()),
+            Operation::ReverseSlices { size } => write!(f, "reverse-slices {}", size.as_beats()),
+            Operation::StretchSlice { size, index, slices } =>
+                write!(f, "stretch-slice {} {} {}", size.as_beats(), index, slices),
+            Operation::PlayTransientSlice { index } => write!(f, "play-transient-slice {}", index),
+        }
+    }
+}
+
+/// Candidate ops and their relative weights, for use by `Operation::Random`.
+pub fn default_random_ops() -> Vec<(Operation, f32)> {
+    vec!(
+        (Operation::Reverse, 2.0),
+        (Operation::Repeat { loop_size: BeatFraction::from(0.25) }, 2.0),
+        (Operation::Repeat { loop_size: BeatFraction::from(0.125) }, 1.0),
+        (Operation::Jump { offset: BeatFraction::from(-1.0) }, 1.0),
+        (Operation::Jump { offset: BeatFraction::from(1.0) }, 1.0),
+    )
+}
+
+/// Parse candidate ops for `Operation::Random` and their relative weights,
+/// separated by ',', for example `2 reverse, 1 jump -1`.
+pub fn random_ops_from_string(text: &str) -> Result<Vec<(Operation, f32)>, ParseError> {
+    let mut random_ops = Vec::new();
+    for candidate in text.split(',') {
+        let parts: Vec<&str> = candidate.split_ascii_whitespace().collect();
+        if parts.len() < 2 {
+            return Err(ParseError { message: format!("expected 'WEIGHT OPERATION [ARGS]', got '{}'", candidate.trim()) });
+        }
+        let weight = parts[0].parse::<f32>()?;
+        if !(weight >= 0.0) {
+            return Err(ParseError { message: format!("weight must not be negative, got {}", weight) });
+        }
+        random_ops.push((operation_from_parts(parts[1], &parts[2..])?, weight));
+    }
+    return Ok(random_ops);
+}
+
+/// A number for an op and its arguments, so random choices for different
+/// ops at the same time are independent. Equal ops give equal numbers.
+pub fn op_id(op: Operation) -> u64 {
+    let beats = |fraction: BeatFraction| fraction.as_beats().to_bits() as u64;
+    let (kind, args): (u64, [u64; 3]) = match op {
+        Operation::NoOp => (0, [0, 0, 0]),
+        Operation::Reverse => (1, [0, 0, 0]),
+        Operation::Repeat { loop_size } => (2, [beats(loop_size), 0, 0]),
+        Operation::Jump { offset } => (3, [beats(offset), 0, 0]),
+        Operation::SpeedRamp { start_speed, end_speed } => (4, [start_speed.to_bits() as u64, end_speed.to_bits() as u64, 0]),
+        Operation::Random => (5, [0, 0, 0]),
+        Operation::PastLoop { loops_back } => (6, [loops_back as u64, 0, 0]),
+        Operation::PlaySlice { size, index } => (7, [beats(size), index as u64, 0]),
+        Operation::ShuffleSlices { size } => (8, [beats(size), 0, 0]),
+        Operation::ReverseSlices { size } => (9, [beats(size), 0, 0]),
+        Operation::StretchSlice { size, index, slices } => (10, [beats(size), index as u64, slices as u64]),
+        Operation::PlayTransientSlice { index } => (11, [index as u64, 0, 0]),
+    };
+    return random::hash_u64(kind, &args);
+}
+
+/// Points in the loop for `Jump` and `Repeat` to snap to, from
+/// `transients::find_markers()`.
+pub struct Snap<'a> {
+    pub points: &'a [SamplePosition],
+    // Play clock at the start of the loop, to find positions in the loop.
+    pub loop_start: SamplePosition,
+}
+
+impl Snap<'_> {
+    fn loop_position(self: &Self, play_clock: SamplePosition, loop_length: SamplePosition) -> SamplePosition {
+        return (play_clock - self.loop_start.min(play_clock)) % loop_length;
+    }
+}
+
+// Transform for `Jump` and `Repeat` with their boundaries moved to the
+// nearest snap points, or None for other ops.
+fn get_snapped_transform(op: Operation,
+                         beat_fraction_to_samples: f32,
+                         play_clock: SamplePosition,
+                         op_start: SamplePosition,
+                         loop_length: SamplePosition,
+                         snap: &Snap) -> Option<isize> {
+    let start_position = snap.loop_position(op_start, loop_length);
+    let nearest = |position: SampleOffset| {
+        transients::nearest(snap.points, position.rem_euclid(loop_length as SampleOffset) as SamplePosition, loop_length)
+    };
+    match op {
+        // Land on the point nearest to where the jump would land.
+        Operation::Jump { offset } => {
+            let target = nearest(start_position as SampleOffset + offset.as_sample_offset(beat_fraction_to_samples));
+            Some(target as SampleOffset - start_position as SampleOffset)
+        },
+
+        // Loop from the point nearest to where the op started, to the one
+        // nearest to where the inner loop would end.
+        Operation::Repeat { loop_size } => {
+            let inner_loop_size = loop_size.as_sample_position(beat_fraction_to_samples);
+            let repeat_start = nearest(start_position as SampleOffset);
+            let repeat_end = nearest((repeat_start + inner_loop_size) as SampleOffset);
+            let mut snapped_size = (repeat_end + loop_length - repeat_start) % loop_length;
+            if snapped_size == 0 {
+                snapped_size = inner_loop_size.max(1);
+            }
+            let target = repeat_start + (play_clock - op_start) % snapped_size;
+            Some(target as SampleOffset - snap.loop_position(play_clock, loop_length) as SampleOffset)
+        },
+
+        _ => None,
+    }
 }
 
 // Return a +/- delta that will be applied to `play_clock` to represent given operation.
+// With `snap`, `Jump` and `Repeat` snap to the given points.
 pub fn get_transform(op: Operation,
                      beat_fraction_to_samples: f32,
                      play_clock: SamplePosition,
                      op_start: SamplePosition,
-                     _loop_length: SamplePosition) -> isize {
+                     loop_length: SamplePosition,
+                     snap: Option<&Snap>) -> isize {
+    if let Some(snap) = snap {
+        if let Some(transform) = get_snapped_transform(op, beat_fraction_to_samples, play_clock, op_start, loop_length, snap) {
+            return transform;
+        }
+    }
+
     match op {
         Operation::NoOp => 0,
 
@@ -41,10 +189,10 @@ pub fn get_transform(op: Operation,
         Operation::Repeat { loop_size } => {
             // Samples since operation started
             let delta = play_clock - op_start;
-            // Times the inner loop has repeated
-            let cycle_count: usize = delta / loop_size.as_sample_position(beat_fraction_to_samples);
             // Offset within current inner loop
-            let inner_loop_size = loop_size.as_sample_position(beat_fraction_to_samples);
+            let inner_loop_size = loop_size.as_sample_position(beat_fraction_to_samples).max(1);
+            // Times the inner loop has repeated
+            let cycle_count: usize = delta / inner_loop_size;
             let mut offset: SampleOffset = 0;
             if cycle_count > 0 {
                 offset = (cycle_count * inner_loop_size) as SampleOffset;
@@ -57,12 +205,25 @@ pub fn get_transform(op: Operation,
 
         // Not implemented
         Operation::SpeedRamp { .. } => 0,
+
+        // Resolved before it reaches the engine
+        Operation::Random => 0,
+
+        // Changes which loop is played, rather than the position in it
+        Operation::PastLoop { .. } => 0,
+
+        // Need the loop position, see `slices::get_transform()`
+        Operation::PlaySlice { .. } |
+        Operation::ShuffleSlices { .. } |
+        Operation::ReverseSlices { .. } |
+        Operation::StretchSlice { .. } |
+        Operation::PlayTransientSlice { .. } => 0,
     }
 }
 
 #[derive(Debug)]
 pub struct ParseError {
-    message: String
+    pub message: String
 }
 
 impl fmt::Display for ParseError {
@@ -83,38 +244,100 @@ impl From<num::ParseIntError> for ParseError {
   }
 }
 
-pub fn new_from_string(line: &str) -> Result<(f64, f64, Operation), ParseError> {
-    let parts: Vec<&str> = line.split_ascii_whitespace().collect();
-
-    let start = parts[1].parse::<f64>()?;
-    let duration = parts[2].parse::<f64>()?;
+fn expect_args(name: &str, args: &[&str], count: usize) -> Result<(), ParseError> {
+    if args.len() < count {
+        return Err(ParseError { message: format!("'{}' needs {} argument(s)", name, count) });
+    }
+    Ok(())
+}
 
-    match parts[0] {
+// Parse an operation name and its arguments, e.g. `repeat 0.25`.
+pub fn operation_from_parts(name: &str, args: &[&str]) -> Result<Operation, ParseError> {
+    match name {
         "reverse" => {
-          Ok((start, duration, Operation::Reverse))
+          Ok(Operation::Reverse)
         },
         "jump" => {
-          let offset = parts[3].parse::<f32>()?;
-          Ok((start, duration, Operation::Jump {
+          expect_args(name, args, 1)?;
+          let offset = args[0].parse::<f32>()?;
+          Ok(Operation::Jump {
               offset: BeatFraction::from(offset)
-          }))
+          })
         },
         "repeat" => {
-          let loop_size = parts[3].parse::<f32>()?;
-          Ok((start, duration, Operation::Repeat {
+          expect_args(name, args, 1)?;
+          let loop_size = args[0].parse::<f32>()?;
+          Ok(Operation::Repeat {
               loop_size: BeatFraction::from(loop_size)
-          }))
+          })
+        },
+        "past-loop" => {
+          expect_args(name, args, 1)?;
+          let loops_back = args[0].parse::<usize>()?;
+          Ok(Operation::PastLoop {
+              loops_back
+          })
+        },
+        "play-slice" => {
+          expect_args(name, args, 2)?;
+          Ok(Operation::PlaySlice {
+              size: BeatFraction::from(args[0].parse::<f32>()?),
+              index: args[1].parse::<usize>()?,
+          })
+        },
+        "shuffle-slices" => {
+          expect_args(name, args, 1)?;
+          Ok(Operation::ShuffleSlices {
+              size: BeatFraction::from(args[0].parse::<f32>()?)
+          })
+        },
+        "reverse-slices" => {
+          expect_args(name, args, 1)?;
+          Ok(Operation::ReverseSlices {
+              size: BeatFraction::from(args[0].parse::<f32>()?)
+          })
+        },
+        "stretch-slice" => {
+          expect_args(name, args, 3)?;
+          Ok(Operation::StretchSlice {
+              size: BeatFraction::from(args[0].parse::<f32>()?),
+              index: args[1].parse::<usize>()?,
+              slices: args[2].parse::<usize>()?,
+          })
+        },
+        "play-transient-slice" => {
+          expect_args(name, args, 1)?;
+          Ok(Operation::PlayTransientSlice {
+              index: args[0].parse::<usize>()?,
+          })
         },
         "speed-ramp" => {
-          let start_speed = parts[3].parse::<f32>()?;
-          let end_speed = parts[4].parse::<f32>()?;
+          expect_args(name, args, 2)?;
+          let start_speed = args[0].parse::<f32>()?;
+          let end_speed = args[1].parse::<f32>()?;
 
-          Ok((start, duration, Operation::SpeedRamp {
+          Ok(Operation::SpeedRamp {
               start_speed, end_speed
-          }))
+          })
         },
         _ => {
-          Err(ParseError { message: format!("unknown operation '{}'", parts[0]) })
+          Err(ParseError { message: format!("unknown operation '{}'", name) })
         }
     }
 }
+
+pub fn new_from_string(line: &str) -> Result<(f64, f64, Operation), ParseError> {
+    let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+    if parts.len() < 3 {
+        return Err(ParseError { message: format!("expected 'OPERATION START DURATION', got '{}'", line) });
+    }
+
+    let start = parts[1].parse::<f64>()?;
+    let duration = parts[2].parse::<f64>()?;
+    let operation = match parts[0] {
+        // Left for the reader to pick, see `EventRecorder::pick_random_op()`.
+        "random" => Operation::Random,
+        name => operation_from_parts(name, &parts[3..])?,
+    };
+    Ok((start, duration, operation))
+}
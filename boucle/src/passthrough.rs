@@ -0,0 +1,60 @@
+//! Continuous history of the input, for using Boucle as an insert effect.
+//!
+//! Rather than playing the last loop, the output is the live input, and ops
+//! read from the recent past of the input, like a stutter effect.
+
+use crate::Sample;
+use crate::SamplePosition;
+
+// Most output delay we allow, before catching up with the input, in case
+// input and output streams drift.
+const MAX_LATENCY: SamplePosition = 8192;
+
+pub struct Passthrough {
+    ring: Vec<Sample>,
+    // Input samples written so far, which is also the position of the next one.
+    written: SamplePosition,
+    read_position: SamplePosition,
+}
+
+impl Passthrough {
+    pub fn new(length: SamplePosition) -> Self {
+        Passthrough {
+            ring: vec!(0.0; length.max(1)),
+            written: 0,
+            read_position: 0,
+        }
+    }
+
+    pub fn length(self: &Self) -> SamplePosition {
+        return self.ring.len();
+    }
+
+    pub fn write(self: &mut Self, sample: Sample) {
+        let length = self.ring.len();
+        self.ring[self.written % length] = sample;
+        self.written += 1;
+    }
+
+    /// Input sample at `position`, or silence if it's too old or not
+    /// written yet.
+    pub fn read(self: &Self, position: SamplePosition) -> Sample {
+        if position >= self.written || position + self.ring.len() < self.written {
+            return 0.0;
+        }
+        return self.ring[position % self.ring.len()];
+    }
+
+    /// Position of the input to play at the start of the next `length`
+    /// output samples. If input for the whole block has been written, as
+    /// when input and output are processed together, this adds no delay.
+    pub fn start_block(self: &mut Self, length: SamplePosition) -> SamplePosition {
+        let latest = self.written.saturating_sub(length);
+        if self.read_position > latest || self.read_position + MAX_LATENCY < latest {
+            self.read_position = latest;
+        }
+        let start = self.read_position;
+        self.read_position += length;
+        return start;
+    }
+}
@@ -1,29 +1,45 @@
-// Predefined patterns you can play.
+//! Predefined patterns you can play.
+//!
+//! A pattern generates operations by itself, one beat at a time. The
+//! `PatternPlayer` asks each active pattern for ops every beat and
+//! returns them as an `OpSequence`, which can be merged with ops from
+//! live control or from a file.
 
-/*pub trait Pattern {
-    fn get() -> 
-}*/
+use crate::BeatFraction;
+use crate::SamplePosition;
+use crate::mini_notation;
+use crate::mini_notation::MiniNotation;
+use crate::ops;
 use crate::ops::Operation;
+use crate::ops::ParseError;
 use crate::op_sequence;
+use crate::op_sequence::OpSequence;
+use crate::random::Random;
 
-type Beats = f32;
+use log::*;
 
-pub trait Pattern {
-    fn op_for_beat(self: &mut Self, beat: Beats, beats_to_secs: f32) -> Option<op_sequence::Entry>;
+pub type Beats = f32;
+
+pub trait Pattern: Send {
+    fn op_for_beat(self: &mut Self, _beat: Beats, _beats_to_samples: f32) -> Option<op_sequence::Entry> {
+        None
+    }
+
+    /// Patterns that can start more than one op per beat implement this
+    /// instead of `op_for_beat()`.
+    fn ops_for_beat(self: &mut Self, beat: Beats, beats_to_samples: f32) -> OpSequence {
+        return self.op_for_beat(beat, beats_to_samples).into_iter().collect();
+    }
 }
 
 pub struct CheckersReverse {
     period: Beats,
     duration: Beats,
-
 }
 
 impl CheckersReverse {
-    fn new(_bpm: Beats, _sample_rate: i32) -> CheckersReverse {
-        CheckersReverse {
-            period: 2.0,   // Reverse every 2nd beat.
-            duration: 1.0,
-        }
+    pub fn new(period: Beats, duration: Beats) -> CheckersReverse {
+        CheckersReverse { period, duration }
     }
 }
 
@@ -34,6 +50,7 @@ impl Pattern for CheckersReverse {
                 start: (beat * beats_to_samples) as usize,
                 duration: Some((self.duration * beats_to_samples) as usize),
                 operation: Operation::Reverse,
+                probability: 1.0,
             })
         } else {
             None
@@ -41,33 +58,261 @@ impl Pattern for CheckersReverse {
     }
 }
 
-/*
-pub struct RandomReverse {
-    probability: f32,
-    min_duration: Beats,
-    max_duration: Beats,
+/// Start a random operation on each beat.
+///
+/// Each candidate operation has its own probability of being picked on a
+/// given beat. The probabilities should add up to 1.0 or less; the remainder
+/// is the chance that nothing happens. Durations are picked between
+/// `min_duration` and `max_duration`, rounded down to a 16th of a beat.
+///
+/// The generator consumes the same amount of randomness on every beat, so
+/// a given seed always produces the same ops.
+pub struct RandomOps {
+    ops: Vec<(Operation, f32)>,
+    min_duration: BeatFraction,
+    max_duration: BeatFraction,
+    random: Random,
+}
+
+impl RandomOps {
+    pub fn new(seed: u64, min_duration: BeatFraction, max_duration: BeatFraction) -> RandomOps {
+        RandomOps {
+            ops: Vec::new(),
+            min_duration,
+            max_duration,
+            random: Random::new(seed),
+        }
+    }
+
+    pub fn add_op(self: &mut Self, operation: Operation, probability: f32) {
+        self.ops.push((operation, probability));
+    }
 }
 
-impl RandomReverse {
-    fn new() -> RandomReverse {
-        RandomReverse { probability = 50; }    // 50% chance of reverse
+impl Pattern for RandomOps {
+    fn op_for_beat(self: &mut Self, beat: Beats, beats_to_samples: f32) -> Option<op_sequence::Entry> {
+        let roll = self.random.next_f32();
+        let duration = BeatFraction::from(
+            self.random.range_f32(self.min_duration.as_beats(), self.max_duration.as_beats()));
+
+        let mut threshold = 0.0;
+        for (operation, probability) in &self.ops {
+            threshold += probability;
+            if roll < threshold {
+                return Some(op_sequence::Entry {
+                    start: (beat * beats_to_samples) as usize,
+                    duration: Some((duration.as_beats() * beats_to_samples) as usize),
+                    operation: *operation,
+                    probability: 1.0,
+                });
+            }
+        }
+        None
     }
 }
 
-impl Pattern for RandomReverse {
-    fn op_for_beat(self: &mut Self, beat: Beats, beats_to_blocks: i32) -> Option<op_sequence::Entry> {
-        if (beat % period) == 0 {
-            Some(op_sequence::Entry {
-                start: beat * self.beats_to_blocks,
-                duration: self.duration * self.beats_to_blocks,
-                op: ops::ReverseOp {};
-            })
-        } else {
-            None()
+/// Euclidean rhythm: spread `pulses` as evenly as possible over `steps`,
+/// shifted later by `rotation` steps.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct EuclideanRhythm {
+    steps: u32,
+    pulses: u32,
+    rotation: u32,
+}
+
+impl EuclideanRhythm {
+    pub fn new(steps: u32, pulses: u32, rotation: u32) -> Result<EuclideanRhythm, ParseError> {
+        if steps == 0 {
+            return Err(ParseError { message: "a Euclidean rhythm needs at least one step".to_string() });
+        }
+        if pulses > steps {
+            return Err(ParseError { message: format!("{} pulses don't fit in {} steps", pulses, steps) });
+        }
+        return Ok(EuclideanRhythm { steps, pulses, rotation });
+    }
+
+    pub fn steps(self: &Self) -> u32 {
+        return self.steps;
+    }
+
+    pub fn is_pulse(self: &Self, step: u32) -> bool {
+        let step = (step % self.steps + self.steps - (self.rotation % self.steps)) % self.steps;
+        return (step * self.pulses) % self.steps < self.pulses;
+    }
+}
+
+/// Pattern that plays an op on the pulses of a Euclidean rhythm.
+///
+/// Each step lasts `step_size`, and each op lasts for one step. The rhythm
+/// repeats every `steps` steps.
+pub struct Euclidean {
+    rhythm: EuclideanRhythm,
+    step_size: BeatFraction,
+    operation: Operation,
+}
+
+impl Euclidean {
+    pub fn new(rhythm: EuclideanRhythm, step_size: BeatFraction, operation: Operation) -> Euclidean {
+        Euclidean { rhythm, step_size, operation }
+    }
+}
+
+impl Pattern for Euclidean {
+    fn ops_for_beat(self: &mut Self, beat: Beats, beats_to_samples: f32) -> OpSequence {
+        let mut ops = OpSequence::new();
+
+        // Work in 16ths of a beat so that step boundaries are exact.
+        let beat_start = (beat * 16.0) as u64;
+        let step_length = (self.step_size.as_beats() * 16.0) as u64;
+        if step_length == 0 {
+            return ops;
         }
+
+        let mut step = (beat_start + step_length - 1) / step_length;
+        while step * step_length < beat_start + 16 {
+            if self.rhythm.is_pulse((step % self.rhythm.steps() as u64) as u32) {
+                ops.push(op_sequence::Entry {
+                    start: ((step * step_length) as f32 / 16.0 * beats_to_samples) as usize,
+                    duration: Some((self.step_size.as_beats() * beats_to_samples) as usize),
+                    operation: self.operation,
+                    probability: 1.0,
+                });
+            }
+            step += 1;
+        }
+        return ops;
+    }
+}
+
+/// Names of the patterns that `new_from_name()` knows about.
+pub const PATTERN_NAMES: &[&str] = &[
+    "checkers-reverse",
+    "random",
+    "euclidean-repeat",
+    "euclidean-reverse",
+];
+
+/// Create one of the predefined patterns, by name.
+///
+/// The seed is used by patterns that make random choices.
+pub fn new_from_name(name: &str, seed: u64) -> Option<Box<dyn Pattern>> {
+    match name {
+        "checkers-reverse" => Some(Box::new(CheckersReverse::new(2.0, 1.0))),
+        "random" => {
+            // Something happens on 35% of beats.
+            let mut pattern = RandomOps::new(seed, BeatFraction::from(0.25), BeatFraction::from(2.0));
+            for (operation, weight) in ops::default_random_ops() {
+                pattern.add_op(operation, weight * 0.05);
+            }
+            Some(Box::new(pattern))
+        },
+        // Repeat a 16th note on 5 of 16 steps.
+        "euclidean-repeat" => Some(Box::new(Euclidean::new(EuclideanRhythm::new(16, 5, 0).unwrap(), BeatFraction::from(0.25),
+                                                           Operation::Repeat { loop_size: BeatFraction::from(0.25) }))),
+        // Reverse an 8th note on 3 of 8 steps, offset from the beat.
+        "euclidean-reverse" => Some(Box::new(Euclidean::new(EuclideanRhythm::new(8, 3, 1).unwrap(), BeatFraction::from(0.5),
+                                                            Operation::Reverse))),
+        _ => None,
+    }
+}
+
+/// Create a pattern from a predefined pattern name, or from mini-notation.
+pub fn new_from_string(text: &str, seed: u64) -> Result<Box<dyn Pattern>, ParseError> {
+    if let Some(pattern) = new_from_name(text, seed) {
+        return Ok(pattern);
     }
+    let pattern = MiniNotation::parse(text, mini_notation::DEFAULT_BEATS_PER_BAR)?;
+    return Ok(Box::new(pattern));
 }
-impl Iterator for RandomReverse {
 
+/// Drive a set of patterns, beat by beat.
+pub struct PatternPlayer {
+    patterns: Vec<Box<dyn Pattern>>,
+    beats_to_samples: f32,
+
+    next_beat: Beats,
+    active_entries: OpSequence,
+}
+
+impl PatternPlayer {
+    pub fn new(beats_to_samples: f32) -> Self {
+        PatternPlayer {
+            patterns: Vec::new(),
+            beats_to_samples,
+            next_beat: 0.0,
+            active_entries: OpSequence::new(),
+        }
+    }
+
+    pub fn add_pattern(self: &mut Self, pattern: Box<dyn Pattern>) {
+        self.patterns.push(pattern);
+    }
+
+    // Ops that were already generated still play out, so a bar-long pattern
+    // that is swapped in mid-bar takes over from the next bar. Returns the
+    // old patterns, to drop outside the audio thread.
+    pub fn replace_patterns(self: &mut Self, patterns: Vec<Box<dyn Pattern>>) -> Vec<Box<dyn Pattern>> {
+        return std::mem::replace(&mut self.patterns, patterns);
+    }
+
+    // Schedule an op that was generated elsewhere, such as by an audio
+    // trigger. It plays alongside the pattern ops until it finishes.
+    pub fn schedule(self: &mut Self, entry: op_sequence::Entry) {
+        self.active_entries.push(entry);
+    }
+
+    // Schedule several ops at once. The ops already playing are moved over to
+    // `entries`, which is swapped in, so this doesn't allocate when `entries`
+    // has room for them. Returns the old list, emptied.
+    pub fn schedule_all(self: &mut Self, mut entries: OpSequence) -> OpSequence {
+        entries.extend(self.active_entries.drain(..));
+        return std::mem::replace(&mut self.active_entries, entries);
+    }
+
+    pub fn active_count(self: &Self) -> usize {
+        return self.active_entries.len();
+    }
+
+    pub fn clear_patterns(self: &mut Self) {
+        self.patterns.clear();
+        self.active_entries.clear();
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.patterns.is_empty();
+    }
+
+    // Generate ops for every beat that starts before the end of the period,
+    // and return all ops that are active during the period. They are
+    // borrowed rather than copied, so the audio thread doesn't allocate.
+    pub fn ops_for_period(self: &mut Self,
+                          period_start: SamplePosition,
+                          period_duration: SamplePosition) -> &[op_sequence::Entry] {
+        let period_end = period_start + period_duration;
+
+        loop {
+            let beat_start = (self.next_beat * self.beats_to_samples) as SamplePosition;
+            if beat_start >= period_end {
+                break;
+            }
+
+            for pattern in self.patterns.iter_mut() {
+                for entry in pattern.ops_for_beat(self.next_beat, self.beats_to_samples) {
+                    debug!("pattern op for beat {}: {}", self.next_beat, entry);
+                    self.active_entries.push(entry);
+                }
+            }
+            self.next_beat += 1.0;
+        }
+
+        self.active_entries.retain(|entry| match entry.duration {
+            Some(duration) => entry.start + duration > period_start,
+            None => true,
+        });
+
+        return &self.active_entries;
+    }
 }
-*/
@@ -0,0 +1,173 @@
+//! Punch-in / punch-out recording, in the style of a hardware loop pedal.
+//!
+//! Arming starts an optional metronome count-in, from the next bar. Recording
+//! punches in at the end of the count-in and punches out at a bar boundary,
+//! either when asked to or after a given number of bars. The recording then
+//! becomes the loop, starting straight away.
+//!
+//! Times here are on the play clock, the same as the ops.
+
+use crate::Sample;
+use crate::SamplePosition;
+
+use log::*;
+
+use std::f32::consts::PI;
+
+pub const DEFAULT_BEATS_PER_BAR: u32 = 4;
+pub const DEFAULT_COUNT_IN_BARS: u32 = 1;
+
+// Metronome click: a short beep, louder and higher at the start of each bar.
+const CLICK_MS: f32 = 20.0;
+const CLICK_HZ: f32 = 1000.0;
+const CLICK_DOWNBEAT_HZ: f32 = 1500.0;
+const CLICK_LEVEL: Sample = 0.3;
+const CLICK_DOWNBEAT_LEVEL: Sample = 0.5;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum State {
+    // Waiting for the first input, to find the next bar.
+    Armed,
+    CountIn { start: SamplePosition, punch_in: SamplePosition },
+    Recording { punch_in: SamplePosition, punch_out: Option<SamplePosition> },
+    Done { punch_in: SamplePosition, punch_out: SamplePosition },
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Punch {
+    beat_length: SamplePosition,
+    beats_per_bar: u32,
+    count_in_bars: u32,
+    auto_stop_bars: Option<u32>,
+    sample_rate: u32,
+
+    state: State,
+    stop_requested: bool,
+}
+
+impl Punch {
+    pub fn new(beat_length: SamplePosition, beats_per_bar: u32, count_in_bars: u32,
+               auto_stop_bars: Option<u32>, sample_rate: u32) -> Self {
+        Punch {
+            beat_length: beat_length.max(1),
+            beats_per_bar: beats_per_bar.max(1),
+            count_in_bars,
+            auto_stop_bars,
+            sample_rate,
+            state: State::Armed,
+            stop_requested: false,
+        }
+    }
+
+    pub fn bar_length(self: &Self) -> SamplePosition {
+        return self.beat_length * self.beats_per_bar as SamplePosition;
+    }
+
+    // Longest recording we will make, if it stops automatically.
+    pub fn max_length(self: &Self) -> Option<SamplePosition> {
+        return self.auto_stop_bars.map(|bars| bars.max(1) as SamplePosition * self.bar_length());
+    }
+
+    /// Length the loop buffers need to hold the recording.
+    pub fn buffer_length(self: &Self) -> SamplePosition {
+        return self.max_length().unwrap_or(self.bar_length());
+    }
+
+    /// Punch out at the end of the current bar.
+    pub fn stop(self: &mut Self) {
+        self.stop_requested = true;
+    }
+
+    pub fn is_done(self: &Self) -> bool {
+        return matches!(self.state, State::Done { .. });
+    }
+
+    /// The play clock where the new loop starts, and its length, once done.
+    pub fn recorded_loop(self: &Self) -> Option<(SamplePosition, SamplePosition)> {
+        match self.state {
+            State::Done { punch_in, punch_out } => Some((punch_out, punch_out - punch_in)),
+            _ => None,
+        }
+    }
+
+    // Start of the next bar at or after `clock`, counting bars from `origin`.
+    fn next_bar(self: &Self, clock: SamplePosition, origin: SamplePosition) -> SamplePosition {
+        let bar_length = self.bar_length();
+        let bars = (clock - origin + bar_length - 1) / bar_length;
+        return origin + bars * bar_length;
+    }
+
+    /// Advance to `clock`, and return the position to record the input
+    /// sample at, if we are recording. Recording stops at `capacity`, which
+    /// must be at least one bar.
+    pub fn process(self: &mut Self, clock: SamplePosition, capacity: SamplePosition) -> Option<SamplePosition> {
+        if self.state == State::Armed {
+            let start = self.next_bar(clock, 0);
+            let punch_in = start + self.count_in_bars as SamplePosition * self.bar_length();
+            info!("Punch: count-in from {}, punch in at {}", start, punch_in);
+            self.state = State::CountIn { start, punch_in };
+        }
+
+        if let State::CountIn { punch_in, .. } = self.state {
+            if clock < punch_in {
+                return None;
+            }
+            let punch_out = self.max_length().map(|length| punch_in + length);
+            self.state = State::Recording { punch_in, punch_out };
+        }
+
+        if let State::Recording { punch_in, punch_out: None } = self.state {
+            if self.stop_requested {
+                let punch_out = self.next_bar(clock + 1, punch_in);
+                self.state = State::Recording { punch_in, punch_out: Some(punch_out) };
+            }
+        }
+
+        if let State::Recording { punch_in, punch_out } = self.state {
+            // Stop at the last whole bar that fits, if we run out of space.
+            let bar_length = self.bar_length();
+            let limit = punch_in + (capacity / bar_length).max(1) * bar_length;
+            let punch_out = punch_out.unwrap_or(limit).min(limit);
+            if clock >= punch_out {
+                info!("Punch: punch out at {}, loop is {} samples", punch_out, punch_out - punch_in);
+                self.state = State::Done { punch_in, punch_out };
+                return None;
+            }
+            return Some(clock - punch_in);
+        }
+
+        return None;
+    }
+
+    /// Metronome click to play at `clock`, during the count-in.
+    pub fn click(self: &Self, clock: SamplePosition) -> Sample {
+        if let State::CountIn { start, punch_in } = self.state {
+            if clock < start || clock >= punch_in {
+                return 0.0;
+            }
+            let position = clock - start;
+            let beat_position = position % self.beat_length;
+            let click_length = (CLICK_MS / 1000.0 * self.sample_rate as f32) as SamplePosition;
+            if beat_position >= click_length {
+                return 0.0;
+            }
+
+            let downbeat = (position / self.beat_length) % self.beats_per_bar as SamplePosition == 0;
+            let (frequency, level) = if downbeat {
+                (CLICK_DOWNBEAT_HZ, CLICK_DOWNBEAT_LEVEL)
+            } else {
+                (CLICK_HZ, CLICK_LEVEL)
+            };
+            let t = beat_position as f32 / self.sample_rate as f32;
+            let envelope = 1.0 - beat_position as f32 / click_length as f32;
+            return level * envelope * (2.0 * PI * frequency * t).sin();
+        }
+        return 0.0;
+    }
+}
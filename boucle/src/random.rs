@@ -0,0 +1,48 @@
+//! Deterministic pseudo-random numbers.
+//!
+//! We use a small SplitMix64 generator rather than an external crate, so that
+//! a given seed always produces the same sequence, in batch and in live mode,
+//! regardless of dependency versions.
+
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Random { state: seed }
+    }
+
+    pub fn next_u64(self: &mut Self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+
+    /// Return a value in the range [0.0, 1.0).
+    pub fn next_f32(self: &mut Self) -> f32 {
+        // Use the top 24 bits, which is the precision of an f32 mantissa.
+        return (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+    }
+
+    /// Return a value in the range [min, max).
+    pub fn range_f32(self: &mut Self, min: f32, max: f32) -> f32 {
+        return min + self.next_f32() * (max - min);
+    }
+}
+
+/// Return a value that depends only on the inputs.
+pub fn hash_u64(seed: u64, values: &[u64]) -> u64 {
+    let mut random = Random::new(seed);
+    for value in values {
+        random = Random::new(random.next_u64() ^ value);
+    }
+    return random.next_u64();
+}
+
+/// Return a value in the range [0.0, 1.0) that depends only on the inputs.
+pub fn hash_f32(seed: u64, values: &[u64]) -> f32 {
+    return (hash_u64(seed, values) >> 40) as f32 / (1u64 << 24) as f32;
+}
@@ -0,0 +1,342 @@
+//! Sessions: everything needed to pick up a performance where it was left.
+//!
+//! A session is a loop snapshot plus the ops that were played over it, the
+//! active patterns and the MIDI note mapping. Like a snapshot it is saved as
+//! a WAV file of the loops and a text file next to it:
+//!
+//! ```text
+//! # Boucle session
+//! version = 2
+//! sample_rate = 44100
+//! bpm = 120
+//! loop_length = 88200
+//! loops = 2
+//! dry_wet = 1
+//! seed = 0
+//!
+//! [automation]
+//! # START DURATION PROBABILITY OPERATION [ARGS], in samples from the loop start
+//! # A DURATION of - is open-ended
+//! 22050 11025 1 repeat 0.25
+//! 44100 - 0.5 reverse
+//!
+//! [patterns]
+//! r ~ j-1 ~
+//!
+//! [mapping]
+//! # NOTE OPERATION [ARGS]
+//! 53 jump -8
+//! ```
+//!
+//! Files written by older versions are migrated when loaded. A snapshot,
+//! which has no version, is version 1.
+
+use crate::Boucle;
+use crate::buffers::LoopBuffers;
+use crate::buffers::LoopSpace;
+use crate::control_surface::midi::MidiNote;
+use crate::control_surface::midi::NoteMap;
+use crate::op_sequence;
+use crate::op_sequence::OpSequence;
+use crate::ops;
+use crate::ops::Operation;
+use crate::ops::ParseError;
+use crate::patterns;
+use crate::patterns::Pattern;
+use crate::snapshot::Snapshot;
+use crate::snapshot::SnapshotError;
+use crate::snapshot::snapshot_paths;
+
+use log::*;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num;
+use std::path::Path;
+
+/// Version of the session files we write.
+pub const SESSION_VERSION: u32 = 2;
+
+// Only these are section headers, as mini-notation patterns can start with '['.
+const SECTIONS: [&str; 3] = ["automation", "patterns", "mapping"];
+
+#[derive(Debug)]
+pub struct SessionError {
+    pub message: String
+}
+
+impl fmt::Display for SessionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Session error: {}", self.message)
+  }
+}
+
+impl From<io::Error> for SessionError {
+  fn from(error: io::Error) -> Self {
+    SessionError { message: error.to_string() }
+  }
+}
+
+impl From<SnapshotError> for SessionError {
+  fn from(error: SnapshotError) -> Self {
+    SessionError { message: error.message }
+  }
+}
+
+impl From<ParseError> for SessionError {
+  fn from(error: ParseError) -> Self {
+    SessionError { message: error.message }
+  }
+}
+
+impl From<num::ParseFloatError> for SessionError {
+  fn from(error: num::ParseFloatError) -> Self {
+    SessionError { message: error.to_string() }
+  }
+}
+
+impl From<num::ParseIntError> for SessionError {
+  fn from(error: num::ParseIntError) -> Self {
+    SessionError { message: error.to_string() }
+  }
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Session {
+    // Config, loops and settings.
+    pub snapshot: Snapshot,
+    // Ops that were played, starting from the start of the loop.
+    pub automation: OpSequence,
+    // Patterns as `patterns::new_from_string()` reads them.
+    pub patterns: Vec<String>,
+    // None to use the front end's own mapping.
+    pub mapping: Option<NoteMap>,
+}
+
+/// What a session swaps in, made before taking the buffers so the audio
+/// thread isn't held up. See `Session::restore_from()`.
+pub struct SessionSpace {
+    loops: LoopSpace,
+    patterns: Vec<Box<dyn Pattern>>,
+    // Automation from the loop start, with room for the ops already playing.
+    entries: OpSequence,
+}
+
+// Version of a session file, from its settings.
+fn read_version(text: &str) -> Result<u32, SessionError> {
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            break;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            if key.trim() == "version" {
+                return Ok(value.trim().parse::<u32>()?);
+            }
+        }
+    }
+    return Ok(1);
+}
+
+// Version 1 is a loop snapshot, which has the settings and nothing else.
+fn migrate_v1(text: &str) -> String {
+    return format!("# Boucle session\nversion = 2\n{}\n[automation]\n\n[patterns]\n\n[mapping]\n", text);
+}
+
+/// Bring the text of a session file written by an older version up to
+/// date, one version at a time.
+pub fn migrate(text: &str) -> Result<String, SessionError> {
+    let mut version = read_version(text)?;
+    if version > SESSION_VERSION {
+        return Err(SessionError { message: format!("session version {} is newer than this Boucle, which reads up to {}",
+                                                   version, SESSION_VERSION) });
+    }
+    let mut text = text.to_string();
+    while version < SESSION_VERSION {
+        info!("Migrating session from version {}", version);
+        text = match version {
+            1 => migrate_v1(&text),
+            _ => return Err(SessionError { message: format!("unknown session version {}", version) }),
+        };
+        version += 1;
+    }
+    return Ok(text);
+}
+
+// Parse an op as it is written by its `Display`. `Random` is allowed here, for
+// mappings, as `operation_from_parts()` leaves it out.
+fn parse_operation(parts: &[&str]) -> Result<Operation, SessionError> {
+    return match parts {
+        [] => Err(SessionError { message: "missing operation".to_string() }),
+        ["random"] => Ok(Operation::Random),
+        [name, args @ ..] => Ok(ops::operation_from_parts(name, args)?),
+    };
+}
+
+// Written for an open-ended duration.
+const OPEN_ENDED: &str = "-";
+
+fn parse_automation_line(line: &str) -> Result<op_sequence::Entry, SessionError> {
+    let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+    if parts.len() < 4 {
+        return Err(SessionError { message: format!("expected 'START DURATION PROBABILITY OPERATION', got '{}'", line) });
+    }
+    return Ok(op_sequence::Entry {
+        start: parts[0].parse()?,
+        duration: match parts[1] {
+            OPEN_ENDED => None,
+            duration => Some(duration.parse()?),
+        },
+        probability: parts[2].parse()?,
+        operation: parse_operation(&parts[3..])?,
+    });
+}
+
+fn parse_mapping_line(line: &str) -> Result<(MidiNote, Operation), SessionError> {
+    let parts: Vec<&str> = line.split_ascii_whitespace().collect();
+    if parts.len() < 2 {
+        return Err(SessionError { message: format!("expected 'NOTE OPERATION', got '{}'", line) });
+    }
+    return Ok((parts[0].parse()?, parse_operation(&parts[1..])?));
+}
+
+impl Session {
+    /// Take the loops and settings of `boucle` and `buffers`, and the ops
+    /// played so far. Front ends pass the patterns and mapping they use.
+    pub fn take(boucle: &Boucle, buffers: &LoopBuffers, patterns: &[String], mapping: Option<NoteMap>) -> Self {
+        let played = boucle.event_recorder.automation();
+        // Count from the start of the loop that the first op was played in.
+        let origin = played.iter().map(|entry| entry.start).min()
+            .map_or(0, |first| first - boucle.loop_position(first));
+        Session {
+            snapshot: Snapshot::take(boucle, buffers),
+            automation: played.iter()
+                .map(|entry| op_sequence::Entry { start: entry.start - origin, ..entry.clone() })
+                .collect(),
+            patterns: patterns.to_vec(),
+            mapping,
+        }
+    }
+
+    /// Play the session's loops, patterns and automation from the next
+    /// sample. This allocates, so don't call it from an audio callback.
+    pub fn restore(self: &Self, boucle: &mut Boucle, buffers: &mut LoopBuffers) -> Result<(), SessionError> {
+        let space = self.space(boucle.seed, buffers.buffer_length(), buffers.loop_count(),
+                               boucle.pattern_player.active_count())?;
+        self.restore_from(space, boucle, buffers);
+        return Ok(());
+    }
+
+    /// The session's loops, patterns and automation made ready for
+    /// `restore_from()`, on buffers `buffer_length` long that keep
+    /// `loop_count` loops, with `active_count` ops already playing.
+    pub fn space(self: &Self, seed: u64, buffer_length: usize, loop_count: usize,
+                 active_count: usize) -> Result<SessionSpace, SessionError> {
+        let mut patterns = Vec::new();
+        for text in self.patterns.iter() {
+            patterns.push(patterns::new_from_string(text, seed)?);
+        }
+        let mut entries = OpSequence::with_capacity(self.automation.len() + active_count);
+        entries.extend(self.automation.iter().cloned());
+        return Ok(SessionSpace {
+            loops: self.snapshot.loop_space(buffer_length, loop_count),
+            patterns,
+            entries,
+        });
+    }
+
+    /// Like `restore()`, but what `space()` made is swapped in, so the
+    /// buffers are only held briefly. Returns what was replaced, to drop
+    /// after letting go of the buffers.
+    pub fn restore_from(self: &Self, space: SessionSpace, boucle: &mut Boucle,
+                        buffers: &mut LoopBuffers) -> SessionSpace {
+        let SessionSpace { loops, patterns, mut entries } = space;
+        let old_loops = self.snapshot.restore_from(loops, boucle, buffers);
+        let old_patterns = boucle.pattern_player.replace_patterns(patterns);
+        for entry in entries.iter_mut() {
+            entry.start += buffers.play_clock;
+        }
+        let old_entries = boucle.pattern_player.schedule_all(entries);
+        return SessionSpace { loops: old_loops, patterns: old_patterns, entries: old_entries };
+    }
+
+    /// Write the session to `path`, as a text file and a WAV file.
+    pub fn save(self: &Self, path: &Path) -> Result<(), SessionError> {
+        let (wav_path, text_path) = snapshot_paths(path);
+        self.snapshot.save_loops(&wav_path)?;
+        fs::write(&text_path, self.to_text())?;
+        info!("Saved session {}", text_path.display());
+        return Ok(());
+    }
+
+    /// Read a session saved at `path`, or a loop snapshot.
+    pub fn load(path: &Path) -> Result<Self, SessionError> {
+        let (wav_path, text_path) = snapshot_paths(path);
+        let mut session = Session::from_text(&migrate(&fs::read_to_string(&text_path)?)?)?;
+        session.snapshot.load_loops(&wav_path)?;
+        return Ok(session);
+    }
+
+    fn to_text(self: &Self) -> String {
+        let mut text = format!("# Boucle session\nversion = {}\n{}", SESSION_VERSION, self.snapshot.settings());
+
+        text += "\n[automation]\n# START DURATION PROBABILITY OPERATION [ARGS], in samples from the loop start\n";
+        text += &format!("# A DURATION of {} is open-ended\n", OPEN_ENDED);
+        for entry in self.automation.iter() {
+            let duration = entry.duration.map_or(OPEN_ENDED.to_string(), |duration| duration.to_string());
+            text += &format!("{} {} {} {}\n", entry.start, duration, entry.probability, entry.operation);
+        }
+
+        text += "\n[patterns]\n";
+        for pattern in self.patterns.iter() {
+            text += &format!("{}\n", pattern);
+        }
+
+        text += "\n[mapping]\n# NOTE OPERATION [ARGS]\n";
+        if let Some(mapping) = &self.mapping {
+            for (note, operation) in mapping.notes() {
+                text += &format!("{} {}\n", note, operation);
+            }
+        }
+        return text;
+    }
+
+    // Parse a session of the current version. Loops are empty until read
+    // from the WAV file.
+    fn from_text(text: &str) -> Result<Self, SessionError> {
+        let mut session = Session {
+            snapshot: Snapshot::parse_sidecar(text)?,
+            automation: OpSequence::new(),
+            patterns: Vec::new(),
+            mapping: None,
+        };
+        let mut mapping = Vec::new();
+
+        let mut section = "";
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                if SECTIONS.contains(&name) {
+                    section = name;
+                    continue;
+                }
+            }
+            match section {
+                "automation" => session.automation.push(parse_automation_line(line)?),
+                "patterns" => session.patterns.push(line.to_string()),
+                "mapping" => mapping.push(parse_mapping_line(line)?),
+                // Settings, read by the snapshot.
+                _ => {},
+            }
+        }
+
+        if !mapping.is_empty() {
+            session.mapping = Some(NoteMap::new(mapping));
+        }
+        return Ok(session);
+    }
+}
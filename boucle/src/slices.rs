@@ -0,0 +1,124 @@
+//! Slice the loop according to the tempo, and play the slices in a
+//! different order.
+//!
+//! A slice op splits the loop into slices of a beat fraction, for example
+//! quavers with a size of 0.5, counted from the start of the loop. Slices
+//! that don't fit at the end of the loop play as they are.
+//!
+//! Loops that don't follow the tempo can be sliced at their transients
+//! instead, from `transients::find_markers()`.
+
+use crate::BeatFraction;
+use crate::SampleOffset;
+use crate::SamplePosition;
+use crate::ops::Operation;
+use crate::ops::ParseError;
+use crate::random;
+
+/// Where a slicer cuts the loop: into slices of a beat fraction, or at the
+/// transients.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum SliceMode {
+    Beats(BeatFraction),
+    Transients,
+}
+
+/// Parse a slice mode, a size in beats or `transients`.
+pub fn slice_mode_from_string(text: &str) -> Result<SliceMode, ParseError> {
+    if text == "transients" {
+        return Ok(SliceMode::Transients);
+    }
+    let beats = text.parse::<f32>()?;
+    if !(beats > 0.0) {
+        return Err(ParseError { message: format!("slice size must be more than 0 beats, not {}", beats) });
+    }
+    return Ok(SliceMode::Beats(BeatFraction::from(beats)));
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    return if b == 0 { a } else { gcd(b, a % b) };
+}
+
+/// Length of a slice of `size`, and how many of them fit in the loop.
+pub fn slice_count(size: BeatFraction, beat_fraction_to_samples: f32,
+                   loop_length: SamplePosition) -> (SamplePosition, usize) {
+    let slice_length = size.as_sample_position(beat_fraction_to_samples).max(1);
+    return (slice_length, (loop_length / slice_length).max(1));
+}
+
+// Slice that slice `slice` plays while shuffled. The order is a permutation
+// that only depends on the seed and when the op started, so every slice plays
+// once per loop and the order is the same however the audio is split.
+fn shuffled_slice(slice: usize, count: usize, seed: u64, op_start: SamplePosition) -> usize {
+    let hash = random::hash_u64(seed, &[op_start as u64]);
+    let mut step = (hash % count as u64) as usize;
+    while gcd(step, count) != 1 {
+        step += 1;
+    }
+    let offset = ((hash >> 32) % count as u64) as usize;
+    return (slice * step + offset) % count;
+}
+
+/// Return a +/- delta that will be applied to `play_clock` for a slice op,
+/// or 0 for other ops. `loop_position` is where the loop would play without
+/// any ops, and `onsets` are the transients in the loop, in order.
+pub fn get_transform(op: Operation,
+                     beat_fraction_to_samples: f32,
+                     play_clock: SamplePosition,
+                     op_start: SamplePosition,
+                     loop_position: SamplePosition,
+                     loop_length: SamplePosition,
+                     seed: u64,
+                     onsets: &[SamplePosition]) -> SampleOffset {
+    let elapsed = play_clock - op_start.min(play_clock);
+    let target = match op {
+        // Loop one slice while held.
+        Operation::PlaySlice { size, index } => {
+            let (slice_length, count) = slice_count(size, beat_fraction_to_samples, loop_length);
+            (index % count) * slice_length + elapsed % slice_length
+        },
+
+        Operation::ShuffleSlices { size } => {
+            let (slice_length, count) = slice_count(size, beat_fraction_to_samples, loop_length);
+            let slice = loop_position / slice_length;
+            if slice >= count {
+                return 0;
+            }
+            shuffled_slice(slice, count, seed, op_start) * slice_length + loop_position % slice_length
+        },
+
+        // Slices play forwards, last one first.
+        Operation::ReverseSlices { size } => {
+            let (slice_length, count) = slice_count(size, beat_fraction_to_samples, loop_length);
+            let slice = loop_position / slice_length;
+            if slice >= count {
+                return 0;
+            }
+            (count - 1 - slice) * slice_length + loop_position % slice_length
+        },
+
+        // Play one slice slower, so it lasts `slices` slices.
+        Operation::StretchSlice { size, index, slices } => {
+            let (slice_length, count) = slice_count(size, beat_fraction_to_samples, loop_length);
+            (index % count) * slice_length + (elapsed / slices.max(1)) % slice_length
+        },
+
+        // Loop from one transient to the next, or to the first one round the
+        // end of the loop. Until there are transients, the loop plays on.
+        Operation::PlayTransientSlice { index } => {
+            if onsets.is_empty() {
+                return 0;
+            }
+            let index = index % onsets.len();
+            let start = onsets[index];
+            let end = onsets.get(index + 1).copied().unwrap_or(onsets[0] + loop_length);
+            (start + elapsed % (end - start).max(1)) % loop_length
+        },
+
+        _ => return 0,
+    };
+    return target as SampleOffset - loop_position as SampleOffset;
+}
@@ -0,0 +1,307 @@
+//! Save the loops to disk and load them again, so a good loop survives a
+//! power cycle.
+//!
+//! A snapshot is a mono WAV file of the loops one after the other, newest
+//! first, and a small text sidecar next to it with the loop length, tempo
+//! and settings needed to play them back the same way. Snapshots are also
+//! the first version of the session format, see `session`.
+
+use crate::Boucle;
+use crate::Config;
+use crate::Sample;
+use crate::SamplePosition;
+use crate::buffers::Buffer;
+use crate::buffers::LoopBuffers;
+use crate::buffers::LoopSpace;
+use crate::buses::DEFAULT_DRY_WET;
+
+use log::*;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num;
+use std::path::Path;
+use std::path::PathBuf;
+
+const WAV_EXTENSION: &str = "wav";
+const SIDECAR_EXTENSION: &str = "txt";
+
+// Tempo of the default `Config`, for snapshots that don't give one.
+const DEFAULT_BPM: f32 = 60.0;
+
+#[derive(Debug)]
+pub struct SnapshotError {
+    pub message: String
+}
+
+impl fmt::Display for SnapshotError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Snapshot error: {}", self.message)
+  }
+}
+
+impl From<io::Error> for SnapshotError {
+  fn from(error: io::Error) -> Self {
+    SnapshotError { message: error.to_string() }
+  }
+}
+
+impl From<hound::Error> for SnapshotError {
+  fn from(error: hound::Error) -> Self {
+    SnapshotError { message: error.to_string() }
+  }
+}
+
+impl From<num::ParseFloatError> for SnapshotError {
+  fn from(error: num::ParseFloatError) -> Self {
+    SnapshotError { message: error.to_string() }
+  }
+}
+
+impl From<num::ParseIntError> for SnapshotError {
+  fn from(error: num::ParseIntError) -> Self {
+    SnapshotError { message: error.to_string() }
+  }
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub struct Snapshot {
+    pub sample_rate: u32,
+    pub bpm: f32,
+    pub loop_length: SamplePosition,
+    pub dry_wet: f32,
+    pub seed: u64,
+    // The loop being played, followed by older loops.
+    pub loops: Vec<Buffer>,
+}
+
+/// WAV file and sidecar paths for a snapshot saved at `path`. Any extension
+/// on `path` is replaced.
+pub fn snapshot_paths(path: &Path) -> (PathBuf, PathBuf) {
+    return (path.with_extension(WAV_EXTENSION), path.with_extension(SIDECAR_EXTENSION));
+}
+
+impl Snapshot {
+    /// Take a copy of the loops being played, and the settings they play with.
+    pub fn take(boucle: &Boucle, buffers: &LoopBuffers) -> Self {
+        let mut snapshot = Snapshot::with_room(buffers.play_length(), buffers.output_history().len());
+        snapshot.take_settings(boucle);
+        snapshot.copy_loops(buffers);
+        return snapshot;
+    }
+
+    /// An empty snapshot with room for `loop_count` loops `loop_length` long,
+    /// so `copy_loops()` doesn't allocate. Taking a snapshot in steps like
+    /// this keeps the time spent holding the buffers short.
+    pub fn with_room(loop_length: SamplePosition, loop_count: usize) -> Self {
+        Snapshot {
+            sample_rate: 0,
+            bpm: DEFAULT_BPM,
+            loop_length,
+            dry_wet: DEFAULT_DRY_WET,
+            seed: 0,
+            // Cloning would drop the capacity, so each is made separately.
+            loops: (0..loop_count).map(|_| Buffer::with_capacity(loop_length)).collect(),
+        }
+    }
+
+    /// Copy the settings that `boucle` plays with.
+    pub fn take_settings(self: &mut Self, boucle: &Boucle) {
+        self.sample_rate = boucle.sample_rate;
+        self.bpm = 60.0 * boucle.sample_rate as f32 / (boucle.beat_fraction_to_samples * 16.0);
+        self.seed = boucle.seed;
+    }
+
+    /// Copy the loops being played and the dry/wet mix. This only allocates
+    /// if they don't fit in the room made by `with_room()`.
+    pub fn copy_loops(self: &mut Self, buffers: &LoopBuffers) {
+        let history = buffers.output_history();
+        self.loop_length = buffers.play_length();
+        self.dry_wet = buffers.dry_wet;
+        self.loops.resize(history.len(), Buffer::new());
+        for (copy, buffer) in self.loops.iter_mut().zip(history.iter()) {
+            copy.clear();
+            copy.extend_from_slice(&buffer[..self.loop_length.min(buffer.len())]);
+        }
+    }
+
+    /// Config to play the snapshot at its own tempo, for when the snapshot
+    /// is loaded at startup.
+    pub fn config(self: &Self) -> Config {
+        Config {
+            sample_rate: self.sample_rate,
+            beat_fraction_to_samples: 60.0 * self.sample_rate as f32 / (self.bpm * 16.0),
+            seed: self.seed,
+        }
+    }
+
+    /// Play the snapshot's loops, held, from the next sample. The tempo
+    /// stays as it is; use `config()` to start at the snapshot's tempo.
+    /// This allocates, so don't call it from an audio callback.
+    pub fn restore(self: &Self, boucle: &mut Boucle, buffers: &mut LoopBuffers) {
+        let space = self.loop_space(buffers.buffer_length(), buffers.loop_count());
+        self.restore_from(space, boucle, buffers);
+    }
+
+    /// The snapshot's loops laid out for buffers `buffer_length` long that
+    /// keep `loop_count` loops, for `restore_from()`.
+    pub fn loop_space(self: &Self, buffer_length: usize, loop_count: usize) -> LoopSpace {
+        return LoopSpace::with_loops(&self.loops, self.loop_length, buffer_length, loop_count);
+    }
+
+    /// Like `restore()`, but the loops laid out by `loop_space()` are swapped
+    /// in, so the buffers are only held briefly. Returns the old loops, to
+    /// drop after letting go of the buffers.
+    pub fn restore_from(self: &Self, space: LoopSpace, boucle: &mut Boucle, buffers: &mut LoopBuffers) -> LoopSpace {
+        if self.sample_rate != boucle.sample_rate {
+            warn!("Snapshot sample rate is {}, playing at {}", self.sample_rate, boucle.sample_rate);
+        }
+        let old_loops = buffers.load_loops_from(space, self.loop_length);
+        buffers.dry_wet = self.dry_wet;
+        boucle.restart_loop(self.loop_length, buffers.play_clock);
+        boucle.loop_changed();
+        return old_loops;
+    }
+
+    /// Write the snapshot to `path`, as a WAV file and a sidecar.
+    pub fn save(self: &Self, path: &Path) -> Result<(), SnapshotError> {
+        let (wav_path, sidecar_path) = snapshot_paths(path);
+        self.save_loops(&wav_path)?;
+        fs::write(&sidecar_path, self.sidecar())?;
+        info!("Saved snapshot {}", wav_path.display());
+        return Ok(());
+    }
+
+    /// Read a snapshot saved at `path`.
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        let (wav_path, sidecar_path) = snapshot_paths(path);
+        let mut snapshot = Snapshot::parse_sidecar(&fs::read_to_string(&sidecar_path)?)?;
+        snapshot.load_loops(&wav_path)?;
+        return Ok(snapshot);
+    }
+
+    // Write the loops one after the other, creating the directory if needed.
+    pub(crate) fn save_loops(self: &Self, wav_path: &Path) -> Result<(), SnapshotError> {
+        if let Some(directory) = wav_path.parent() {
+            if !directory.as_os_str().is_empty() {
+                fs::create_dir_all(directory)?;
+            }
+        }
+
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(wav_path, spec)?;
+        for buffer in self.loops.iter() {
+            for &sample in buffer.iter() {
+                writer.write_sample(sample)?;
+            }
+        }
+        writer.finalize()?;
+        return Ok(());
+    }
+
+    // Read as many loops as the sidecar said there are.
+    pub(crate) fn load_loops(self: &mut Self, wav_path: &Path) -> Result<(), SnapshotError> {
+        let loop_count = self.loops.len();
+        let mut reader = hound::WavReader::open(wav_path)?;
+        let spec = reader.spec();
+        if spec.channels != 1 {
+            return Err(SnapshotError { message: format!("expected a mono WAV file, got {} channels", spec.channels) });
+        }
+        let samples: Vec<Sample> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<Result<_, _>>()?
+            },
+        };
+        if samples.len() < self.loop_length * loop_count {
+            return Err(SnapshotError { message: format!("expected {} loops of {} samples in {}, got {} samples",
+                                                        loop_count, self.loop_length, wav_path.display(), samples.len()) });
+        }
+
+        self.loops = samples.chunks(self.loop_length.max(1))
+            .take(loop_count)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        info!("Loaded {}: {} loops of {} samples", wav_path.display(), loop_count, self.loop_length);
+        return Ok(());
+    }
+
+    fn sidecar(self: &Self) -> String {
+        return format!("# Boucle loop snapshot\n{}", self.settings());
+    }
+
+    // One `key = value` line for each setting.
+    pub(crate) fn settings(self: &Self) -> String {
+        return format!("sample_rate = {}\n\
+                        bpm = {}\n\
+                        loop_length = {}\n\
+                        loops = {}\n\
+                        dry_wet = {}\n\
+                        seed = {}\n",
+                       self.sample_rate, self.bpm, self.loop_length, self.loops.len(), self.dry_wet, self.seed);
+    }
+
+    // Settings from the sidecar, with empty loops, one for each loop in the
+    // WAV file. Reading stops at the first `[section]`, so this also reads
+    // the settings of a session.
+    pub(crate) fn parse_sidecar(text: &str) -> Result<Self, SnapshotError> {
+        let mut sample_rate = None;
+        let mut loop_length = None;
+        let mut loop_count = None;
+        let mut snapshot = Snapshot {
+            sample_rate: 0,
+            bpm: DEFAULT_BPM,
+            loop_length: 0,
+            dry_wet: DEFAULT_DRY_WET,
+            seed: 0,
+            loops: Vec::new(),
+        };
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line == "[automation]" || line == "[patterns]" || line == "[mapping]" {
+                break;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(SnapshotError { message: format!("expected 'KEY = VALUE', got '{}'", line) }),
+            };
+            match key {
+                "sample_rate" => sample_rate = Some(value.parse::<u32>()?),
+                "bpm" => snapshot.bpm = value.parse::<f32>()?,
+                "loop_length" => loop_length = Some(value.parse::<SamplePosition>()?),
+                "loops" => loop_count = Some(value.parse::<usize>()?),
+                "dry_wet" => snapshot.dry_wet = value.parse::<f32>()?,
+                "seed" => snapshot.seed = value.parse::<u64>()?,
+                // Sessions are versioned, see session.rs.
+                "version" => {},
+                _ => warn!("Ignoring unknown snapshot setting '{}'", key),
+            }
+        }
+
+        let missing = |key: &str| SnapshotError { message: format!("missing '{}'", key) };
+        snapshot.sample_rate = sample_rate.ok_or_else(|| missing("sample_rate"))?;
+        snapshot.loop_length = loop_length.ok_or_else(|| missing("loop_length"))?;
+        snapshot.loops = vec!(Buffer::new(); loop_count.ok_or_else(|| missing("loops"))?);
+        if snapshot.bpm <= 0.0 {
+            return Err(SnapshotError { message: format!("bad bpm {}", snapshot.bpm) });
+        }
+        if snapshot.loop_length == 0 || snapshot.loops.is_empty() {
+            return Err(SnapshotError { message: "snapshot has no audio".to_string() });
+        }
+        return Ok(snapshot);
+    }
+}
@@ -4,6 +4,7 @@ mod event_recorder {
 
     use crate::BeatFraction;
     use crate::event::StateChange;
+    use crate::event_recorder::MAX_AUTOMATION_ENTRIES;
     use crate::EventRecorder;
     use crate::Operation;
     use crate::SamplePosition;
@@ -102,6 +103,83 @@ mod event_recorder {
         assert_eq!(ops_third[0].duration, Some(ONE_SECOND));
         assert_eq!(ops_third[0].operation, op_2);
     }
+
+    fn random_op_choices(seed: u64) -> Vec<Operation> {
+        let instant = Instant::now();
+        let mut recorder = EventRecorder::new(TEST_SAMPLE_RATE);
+        recorder.set_seed(seed);
+        recorder.set_event_sync_point(instant, 0);
+
+        let mut choices = Vec::new();
+        for i in 0..16 {
+            let start = instant + Duration::from_secs(i * 2);
+            recorder.record_event(start, StateChange::On, Operation::Random);
+            recorder.record_event(start + Duration::from_secs(1), StateChange::Off, Operation::Random);
+
+            let period_start = ONE_SECOND * i as SamplePosition * 2;
+            let ops_on = recorder.ops_for_period(period_start, ONE_SECOND / 2);
+            assert_eq!(ops_on.len(), 1);
+            assert_eq!(ops_on[0].duration, None);
+
+            // The same op is released when the key is released.
+            let ops_off = recorder.ops_for_period(period_start, ONE_SECOND * 2);
+            assert_eq!(ops_off.len(), 1);
+            assert_eq!(ops_off[0].operation, ops_on[0].operation);
+            assert_eq!(ops_off[0].duration, Some(ONE_SECOND));
+            choices.push(ops_on[0].operation);
+        }
+        return choices;
+    }
+
+    #[test]
+    fn random_op() {
+        let choices = random_op_choices(1);
+        assert!(choices.iter().all(|op| *op != Operation::Random && *op != Operation::NoOp));
+        assert!(choices.iter().any(|op| *op != choices[0]));
+        assert_eq!(choices, random_op_choices(1));
+    }
+
+    #[test]
+    fn random_op_weights() {
+        let instant = Instant::now();
+        let mut recorder = EventRecorder::new(TEST_SAMPLE_RATE);
+        recorder.set_event_sync_point(instant, 0);
+        recorder.set_random_ops(vec!((Operation::Reverse, 0.0), (Operation::Jump { offset: BeatFraction::from(1.0) }, 1.0)));
+
+        recorder.record_event(instant, StateChange::On, Operation::Random);
+        let ops = recorder.ops_for_period(0, ONE_SECOND);
+        assert_eq!(ops[0].operation, Operation::Jump { offset: BeatFraction::from(1.0) });
+    }
+
+    #[test]
+    fn random_ops_from_string() {
+        let random_ops = crate::ops::random_ops_from_string("2 reverse, 0.5 jump -1").unwrap();
+        assert_eq!(random_ops.len(), 2);
+        assert!(random_ops[0] == (Operation::Reverse, 2.0));
+        assert!(random_ops[1] == (Operation::Jump { offset: BeatFraction::from(-1.0) }, 0.5));
+
+        assert!(crate::ops::random_ops_from_string("reverse").is_err());
+        assert!(crate::ops::random_ops_from_string("-1 reverse").is_err());
+        assert!(crate::ops::random_ops_from_string("1 wobble").is_err());
+    }
+
+    #[test]
+    fn automation_drops_oldest() {
+        let mut recorder = EventRecorder::new(TEST_SAMPLE_RATE);
+        let capacity = recorder.automation().capacity();
+        for i in 0..MAX_AUTOMATION_ENTRIES + 2 {
+            recorder.record_event_at(i * 2, StateChange::On, Operation::Reverse, 1.0);
+            recorder.record_event_at(i * 2 + 1, StateChange::Off, Operation::Reverse, 1.0);
+        }
+        recorder.ops_for_period(0, (MAX_AUTOMATION_ENTRIES + 2) * 2);
+
+        let automation = recorder.automation();
+        assert_eq!(automation.len(), MAX_AUTOMATION_ENTRIES);
+        // Made with room for them all.
+        assert_eq!(automation.capacity(), capacity);
+        assert_eq!(automation.front().unwrap().start, 4);
+        assert_eq!(automation.back().unwrap().duration, Some(1));
+    }
 }
 
 #[cfg(test)]
@@ -118,6 +196,7 @@ mod operations {
         sample_rate: 44100,
         // Map 1:1 beats to samples.
         beat_fraction_to_samples: 1.0 / 16.0,
+        seed: 0,
     };
 
     fn make_buffer(data: &[i16]) -> Vec<Sample> {
@@ -130,7 +209,7 @@ mod operations {
         let boucle: Boucle = Boucle::new(&TEST_CONFIG, input.len());
 
         let ops: OpSequence = vec!(
-            op_sequence::Entry { start: 3, duration: Some(10), operation: Operation::Reverse },
+            op_sequence::Entry { start: 3, duration: Some(10), operation: Operation::Reverse, probability: 1.0 },
         );
         let expected_output = make_buffer(&[1,2,3,4,3,2,1,8,7,6,5,4,3,6,7,8]);
 
@@ -145,8 +224,8 @@ mod operations {
         let boucle: Boucle = Boucle::new(&TEST_CONFIG, input.len());
 
         let ops: OpSequence = vec!(
-            op_sequence::Entry { start: 4, duration: Some(4), operation: Operation::Jump { offset: BeatFraction::from(-4.0) } },
-            op_sequence::Entry { start: 12, duration: Some(4), operation: Operation::Jump { offset: BeatFraction::from(8.0) } },
+            op_sequence::Entry { start: 4, duration: Some(4), operation: Operation::Jump { offset: BeatFraction::from(-4.0) }, probability: 1.0 },
+            op_sequence::Entry { start: 12, duration: Some(4), operation: Operation::Jump { offset: BeatFraction::from(8.0) }, probability: 1.0 },
         );
         let expected_output = make_buffer(&[1,2,3,4, 1,2,3,4, 9,10,11,12, 5,6,7,8]);
 
@@ -161,7 +240,7 @@ mod operations {
         let boucle: Boucle = Boucle::new(&TEST_CONFIG, input.len());
 
         let ops: OpSequence = vec!(
-            op_sequence::Entry { start: 0, duration: Some(20), operation: Operation::Repeat { loop_size: BeatFraction::from(8.0) } },
+            op_sequence::Entry { start: 0, duration: Some(20), operation: Operation::Repeat { loop_size: BeatFraction::from(8.0) }, probability: 1.0 },
         );
         let expected_output = make_buffer(&[1,2,3,4, 5,6,7,8, 1,2,3,4, 5,6,7,8, 1,2,3,4, 21,22,23,24]);
 
@@ -169,4 +248,1868 @@ mod operations {
         boucle.process_buffer(&input, 0, input.len(), &ops, &mut |s| output.push(s));
         assert_eq!(output, expected_output);
     }
+
+    #[test]
+    fn zero_repeat_size() {
+        // Repeats of less than a 16th of a beat repeat single samples, rather than panic.
+        let input = make_buffer(&[1,2,3,4]);
+        let boucle: Boucle = Boucle::new(&TEST_CONFIG, input.len());
+        let ops: OpSequence = vec!(
+            op_sequence::Entry { start: 1, duration: Some(2), operation: Operation::Repeat { loop_size: BeatFraction::from(0.0) }, probability: 1.0 },
+        );
+
+        let mut output: Vec<Sample> = Vec::new();
+        boucle.process_buffer(&input, 0, input.len(), &ops, &mut |s| output.push(s));
+        assert_eq!(output, make_buffer(&[1,2,2,4]));
+    }
+
+    #[test]
+    fn probability() {
+        let input: Vec<Sample> = (0..64).map(|s| s as Sample).collect();
+        let boucle: Boucle = Boucle::new(&TEST_CONFIG, input.len());
+
+        let never: OpSequence = vec!(
+            op_sequence::Entry { start: 0, duration: Some(64), operation: Operation::Reverse, probability: 0.0 },
+        );
+        let mut output: Vec<Sample> = Vec::new();
+        boucle.process_buffer(&input, 0, input.len(), &never, &mut |s| output.push(s));
+        assert_eq!(output, input);
+
+        // Each cycle of the repeat fires or not, the same way each time,
+        // however the output is split into buffers.
+        let sometimes: OpSequence = vec!(
+            op_sequence::Entry { start: 0, duration: Some(64), operation: Operation::Repeat { loop_size: BeatFraction::from(2.0) }, probability: 0.5 },
+        );
+        let mut output: Vec<Sample> = Vec::new();
+        boucle.process_buffer(&input, 0, input.len(), &sometimes, &mut |s| output.push(s));
+        let mut split_output: Vec<Sample> = Vec::new();
+        for block in 0..8 {
+            boucle.process_buffer(&input, block * 8, 8, &sometimes, &mut |s| split_output.push(s));
+        }
+        assert_eq!(output, split_output);
+        assert_ne!(output, input);
+
+        let repeated_cycles = (1..32).filter(|cycle| output[cycle * 2] == 0.0).count();
+        assert!(repeated_cycles > 0 && repeated_cycles < 31);
+
+        // Ops starting together decide separately.
+        let fires = |operation: Operation, start: usize| {
+            let entry = op_sequence::Entry { start, duration: Some(1), operation, probability: 0.5 };
+            op_sequence::op_fires(&entry, start, TEST_CONFIG.beat_fraction_to_samples, 0)
+        };
+        let jump = Operation::Jump { offset: BeatFraction::from(1.0) };
+        assert!((0..64).any(|start| fires(Operation::Reverse, start) != fires(jump, start)));
+    }
+}
+
+#[cfg(test)]
+mod patterns {
+    use crate::BeatFraction;
+    use crate::Operation;
+    use crate::PatternPlayer;
+    use crate::patterns::CheckersReverse;
+    use crate::patterns::Euclidean;
+    use crate::patterns::EuclideanRhythm;
+    use crate::patterns::RandomOps;
+
+    #[test]
+    fn checkers_reverse() {
+        // Map 1:1 beats to samples.
+        let mut player = PatternPlayer::new(1.0);
+        player.add_pattern(Box::new(CheckersReverse::new(2.0, 1.0)));
+
+        let ops = player.ops_for_period(0, 4);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].start, 0);
+        assert_eq!(ops[0].duration, Some(1));
+        assert_eq!(ops[0].operation, Operation::Reverse);
+        assert_eq!(ops[1].start, 2);
+
+        // Finished ops are dropped, ops are generated for new beats only.
+        let ops = player.ops_for_period(4, 2);
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].start, 4);
+    }
+
+    fn random_player(seed: u64) -> PatternPlayer {
+        let mut pattern = RandomOps::new(seed, BeatFraction::from(0.5), BeatFraction::from(2.0));
+        pattern.add_op(Operation::Reverse, 0.3);
+        pattern.add_op(Operation::Repeat { loop_size: BeatFraction::from(0.25) }, 0.3);
+
+        // Map 1:1 beat fractions to samples.
+        let mut player = PatternPlayer::new(16.0);
+        player.add_pattern(Box::new(pattern));
+        return player;
+    }
+
+    #[test]
+    fn random_ops_same_seed() {
+        // Live mode asks for ops in small blocks, batch mode all at once.
+        let mut live_player = random_player(42);
+        let mut live_ops = Vec::new();
+        for block in 0..64 {
+            for entry in live_player.ops_for_period(block * 16, 16) {
+                if entry.start >= block * 16 {
+                    live_ops.push(entry.clone());
+                }
+            }
+        }
+
+        let batch_ops = random_player(42).ops_for_period(0, 64 * 16).to_vec();
+        assert!(batch_ops.len() > 0);
+        assert_eq!(live_ops.len(), batch_ops.len());
+        for (live, batch) in live_ops.iter().zip(batch_ops.iter()) {
+            assert_eq!(live.start, batch.start);
+            assert_eq!(live.duration, batch.duration);
+            assert_eq!(live.operation, batch.operation);
+            assert!(live.duration.unwrap() >= 8 && live.duration.unwrap() <= 32);
+        }
+
+        let other_ops = random_player(43).ops_for_period(0, 64 * 16).to_vec();
+        let starts: Vec<usize> = batch_ops.iter().map(|e| e.start).collect();
+        let other_starts: Vec<usize> = other_ops.iter().map(|e| e.start).collect();
+        assert_ne!(starts, other_starts);
+    }
+
+    #[test]
+    fn euclidean_rhythm() {
+        let rhythm = EuclideanRhythm::new(16, 5, 0).unwrap();
+        let pulses: Vec<u32> = (0..16).filter(|step| rhythm.is_pulse(*step)).collect();
+        assert_eq!(pulses, vec!(0, 4, 7, 10, 13));
+
+        let rotated = EuclideanRhythm::new(16, 5, 2).unwrap();
+        let pulses: Vec<u32> = (0..16).filter(|step| rotated.is_pulse(*step)).collect();
+        assert_eq!(pulses, vec!(2, 6, 9, 12, 15));
+
+        assert!(EuclideanRhythm::new(0, 0, 0).is_err());
+        assert!(EuclideanRhythm::new(8, 9, 0).is_err());
+        assert!(EuclideanRhythm::new(8, 8, 0).is_ok());
+    }
+
+    #[test]
+    fn euclidean_layers() {
+        let repeat = Operation::Repeat { loop_size: BeatFraction::from(0.25) };
+
+        // Map 1:1 beat fractions to samples.
+        let mut player = PatternPlayer::new(16.0);
+        player.add_pattern(Box::new(Euclidean::new(EuclideanRhythm::new(16, 5, 0).unwrap(), BeatFraction::from(0.25), repeat)));
+        player.add_pattern(Box::new(Euclidean::new(EuclideanRhythm::new(4, 1, 3).unwrap(), BeatFraction::from(1.0), Operation::Reverse)));
+
+        // One bar of 4 beats.
+        let mut ops = player.ops_for_period(0, 64).to_vec();
+        ops.sort_by_key(|entry| entry.start);
+        let starts: Vec<usize> = ops.iter().map(|entry| entry.start).collect();
+        assert_eq!(starts, vec!(0, 16, 28, 40, 48, 52));
+        assert_eq!(ops[0].duration, Some(4));
+        assert_eq!(ops[0].operation, repeat);
+        assert_eq!(ops[4].duration, Some(16));
+        assert_eq!(ops[4].operation, Operation::Reverse);
+    }
+
+    #[test]
+    fn pattern_names() {
+        for name in crate::patterns::PATTERN_NAMES {
+            assert!(crate::patterns::new_from_name(name, 0).is_some());
+        }
+        assert!(crate::patterns::new_from_name("no-such-pattern", 0).is_none());
+    }
+}
+
+#[cfg(test)]
+mod mini_notation {
+    use crate::BeatFraction;
+    use crate::Operation;
+    use crate::PatternPlayer;
+    use crate::mini_notation::MiniNotation;
+    use crate::mini_notation::Step;
+
+    #[test]
+    fn parse() {
+        let pattern = MiniNotation::parse("r ~ [j-1 j+1] x4:1/8", 4.0).unwrap();
+        assert_eq!(pattern.steps(), &[
+            Step::Op { operation: Operation::Reverse, duration: None, probability: 1.0 },
+            Step::Rest,
+            Step::Group(vec!(
+                Step::Op { operation: Operation::Jump { offset: BeatFraction::from(-1.0) }, duration: None, probability: 1.0 },
+                Step::Op { operation: Operation::Jump { offset: BeatFraction::from(1.0) }, duration: None, probability: 1.0 },
+            )),
+            Step::Op { operation: Operation::Repeat { loop_size: BeatFraction::from(0.125) }, duration: Some(0.5), probability: 1.0 },
+        ]);
+
+        assert!(MiniNotation::parse("", 4.0).is_err());
+        assert!(MiniNotation::parse("r [j1", 4.0).is_err());
+        assert!(MiniNotation::parse("r ]", 4.0).is_err());
+        assert!(MiniNotation::parse("q", 4.0).is_err());
+        assert!(MiniNotation::parse("x1/0", 4.0).is_err());
+        assert!(MiniNotation::parse("x0", 4.0).is_err());
+        assert!(MiniNotation::parse("x-1", 4.0).is_err());
+        assert!(MiniNotation::parse("x4:1/32", 4.0).is_err());
+        assert!(MiniNotation::parse("x1/16", 4.0).is_ok());
+    }
+
+    #[test]
+    fn parse_probability() {
+        let pattern = MiniNotation::parse("r? [j1 x1/4]?1/4 r*2?0.1", 4.0).unwrap();
+        let probabilities: Vec<f32> = pattern.ops_for_bar(0.0, 16.0).iter().map(|entry| entry.probability).collect();
+        assert_eq!(probabilities, vec!(0.5, 0.25, 0.25, 0.1, 0.1));
+
+        assert!(MiniNotation::parse("r?x", 4.0).is_err());
+        assert!(MiniNotation::parse("r?1.5", 4.0).is_err());
+        assert!(MiniNotation::parse("r?-1", 4.0).is_err());
+        assert!(MiniNotation::parse("r?0 r?1", 4.0).is_ok());
+        assert!(MiniNotation::parse("[r]x", 4.0).is_err());
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert!(MiniNotation::parse(&"[".repeat(100000), 4.0).is_err());
+        assert!(MiniNotation::parse(&format!("{}r{}", "[".repeat(16), "]".repeat(16)), 4.0).is_ok());
+        assert!(MiniNotation::parse(&format!("{}r{}", "[".repeat(17), "]".repeat(17)), 4.0).is_err());
+
+        assert!(MiniNotation::parse("r*100000000", 4.0).is_err());
+        assert!(MiniNotation::parse("[r*32]*32", 4.0).is_ok());
+        assert!(MiniNotation::parse("[r*32]*32*2", 4.0).is_err());
+        assert!(MiniNotation::parse("[[r*32]*32]*2", 4.0).is_err());
+        assert!(MiniNotation::parse("[r*32]*32 [r*32]*32", 4.0).is_err());
+    }
+
+    #[test]
+    fn compile_bar() {
+        let pattern = MiniNotation::parse("r ~ [j-1 j+1] x4:1/8", 4.0).unwrap();
+        // Map 1:1 beat fractions to samples.
+        let ops = pattern.ops_for_bar(4.0, 16.0);
+        let starts: Vec<usize> = ops.iter().map(|entry| entry.start).collect();
+        let durations: Vec<Option<usize>> = ops.iter().map(|entry| entry.duration).collect();
+        assert_eq!(starts, vec!(64, 96, 104, 112));
+        assert_eq!(durations, vec!(Some(16), Some(8), Some(8), Some(8)));
+    }
+
+    #[test]
+    fn euclidean_suffix() {
+        // 3 of 8 eighth notes, shifted by one.
+        let pattern = MiniNotation::parse("r(3,8,1)", 4.0).unwrap();
+        let ops = pattern.ops_for_bar(0.0, 16.0);
+        let starts: Vec<usize> = ops.iter().map(|entry| entry.start).collect();
+        assert_eq!(starts, vec!(8, 32, 56));
+        assert!(ops.iter().all(|entry| entry.duration == Some(8) && entry.operation == Operation::Reverse));
+
+        let pattern = MiniNotation::parse("[r j1](1,2)?0.5 x1/4", 4.0).unwrap();
+        let ops = pattern.ops_for_bar(0.0, 16.0);
+        let starts: Vec<usize> = ops.iter().map(|entry| entry.start).collect();
+        assert_eq!(starts, vec!(0, 8, 32));
+        assert_eq!(ops[0].probability, 0.5);
+
+        assert!(MiniNotation::parse("r(3,0)", 4.0).is_err());
+        assert!(MiniNotation::parse("r(9,8)", 4.0).is_err());
+        assert!(MiniNotation::parse("r(3)", 4.0).is_err());
+        assert!(MiniNotation::parse("r(3,8", 4.0).is_err());
+        assert!(MiniNotation::parse("r(1,2000)", 4.0).is_err());
+    }
+
+    #[test]
+    fn repeat_suffix() {
+        let pattern = MiniNotation::parse("r*2 [~ x1/4]*2", 4.0).unwrap();
+        let ops = pattern.ops_for_bar(0.0, 16.0);
+        let starts: Vec<usize> = ops.iter().map(|entry| entry.start).collect();
+        assert_eq!(starts, vec!(0, 16, 40, 56));
+    }
+
+    #[test]
+    fn hot_swap() {
+        let mut player = PatternPlayer::new(16.0);
+        player.add_pattern(crate::patterns::new_from_string("r", 0).unwrap());
+        assert_eq!(player.ops_for_period(0, 16).len(), 1);
+
+        // The bar that has started plays out, the new pattern starts on the next bar.
+        player.replace_patterns(vec!(crate::patterns::new_from_string("x1/4 ~", 0).unwrap()));
+        let ops = player.ops_for_period(16, 64);
+        let starts: Vec<usize> = ops.iter().map(|entry| entry.start).collect();
+        assert_eq!(starts, vec!(0, 64));
+        assert_eq!(ops[1].operation, Operation::Repeat { loop_size: BeatFraction::from(0.25) });
+    }
+}
+
+#[cfg(test)]
+mod modulation {
+    use crate::BeatFraction;
+    use crate::Modulator;
+    use crate::Operation;
+    use crate::op_sequence;
+    use crate::OpSequence;
+    use crate::modulation::Lfo;
+    use crate::modulation::Waveform;
+
+    #[test]
+    fn waveforms() {
+        let mut sine = Lfo::new(Waveform::Sine, BeatFraction::from(4.0), 0);
+        assert_eq!(sine.value_at(0.0), 0.5);
+        assert_eq!(sine.value_at(1.0), 1.0);
+
+        let mut triangle = Lfo::new(Waveform::Triangle, BeatFraction::from(4.0), 0);
+        assert_eq!(triangle.value_at(0.0), 0.0);
+        assert_eq!(triangle.value_at(1.0), 0.5);
+        assert_eq!(triangle.value_at(2.0), 1.0);
+        assert_eq!(triangle.value_at(4.0), 0.0);
+
+        let mut square = Lfo::new(Waveform::Square, BeatFraction::from(4.0), 0);
+        assert_eq!(square.value_at(1.0), 1.0);
+        assert_eq!(square.value_at(3.0), 0.0);
+
+        // Random waveforms hold their value for a period, and repeat for the same seed.
+        let mut sample_and_hold = Lfo::new(Waveform::SampleAndHold, BeatFraction::from(1.0), 5);
+        let values: Vec<f32> = (0..32).map(|beat| sample_and_hold.value_at(beat as f32)).collect();
+        assert_eq!(sample_and_hold.value_at(3.5), values[3]);
+        assert!(values.iter().any(|value| *value != values[0]));
+
+        let mut walk = Lfo::new(Waveform::RandomWalk, BeatFraction::from(1.0), 5);
+        let walk_values: Vec<f32> = (0..32).map(|beat| walk.value_at(beat as f32)).collect();
+        assert!(walk_values.iter().all(|value| *value >= 0.0 && *value <= 1.0));
+        assert_eq!(walk.value_at(7.0), walk_values[7]);
+        assert!(walk_values.windows(2).all(|pair| (pair[1] - pair[0]).abs() <= 0.25));
+    }
+
+    #[test]
+    fn routing() {
+        // Map 1:1 beats to samples.
+        let mut modulator = Modulator::new(1.0);
+        modulator.add_routing(crate::modulation::new_from_string("repeat-size triangle 4 0 2", 0).unwrap());
+        modulator.add_routing(crate::modulation::new_from_string("probability square 4 0 1", 0).unwrap());
+
+        let ops: OpSequence = vec!(
+            op_sequence::Entry { start: 0, duration: None, operation: Operation::Repeat { loop_size: BeatFraction::from(0.25) }, probability: 1.0 },
+            op_sequence::Entry { start: 0, duration: None, operation: Operation::Reverse, probability: 0.5 },
+        );
+
+        let mut block = ops.clone();
+        modulator.apply(&mut block, 1);
+        assert_eq!(block[0].operation, Operation::Repeat { loop_size: BeatFraction::from(1.0) });
+        assert_eq!(block[1].operation, Operation::Reverse);
+        assert_eq!(block[1].probability, 0.5);
+
+        let mut block = ops.clone();
+        modulator.apply(&mut block, 4);
+        assert_eq!(block[0].operation, Operation::Repeat { loop_size: BeatFraction::from(0.0625) });
+
+        let mut block = ops.clone();
+        modulator.apply(&mut block, 3);
+        assert_eq!(block[1].probability, 0.0);
+
+        assert!(crate::modulation::new_from_string("repeat-size wobble 4 0 2", 0).is_err());
+        assert!(crate::modulation::new_from_string("volume sine 4 0 2", 0).is_err());
+        assert!(crate::modulation::new_from_string("repeat-size sine 4", 0).is_err());
+        assert!(crate::modulation::new_from_string("speed-start sine 4 0 2", 0).is_err());
+    }
+}
+
+#[cfg(test)]
+mod audio_trigger {
+    use crate::BeatFraction;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::audio_trigger::AudioTrigger;
+    use crate::audio_trigger::AudioTriggers;
+    use crate::audio_trigger::TriggerMode;
+
+    const TEST_SAMPLE_RATE: u32 = 44100;
+
+    // Quiet noise, with loud hits every half second.
+    fn drum_hits() -> Vec<Sample> {
+        (0..TEST_SAMPLE_RATE * 2).map(|i| {
+            let position = i % (TEST_SAMPLE_RATE / 2);
+            let noise = if i % 2 == 0 { 0.001 } else { -0.001 };
+            if position < 2000 { noise + 0.8 * (1.0 - position as Sample / 2000.0) } else { noise }
+        }).collect()
+    }
+
+    fn trigger_times(mode: TriggerMode, threshold: f32) -> Vec<usize> {
+        let mut triggers = AudioTriggers::new();
+        triggers.add_trigger(AudioTrigger::new(mode, threshold, Operation::Reverse, BeatFraction::from(1.0), TEST_SAMPLE_RATE));
+        for (i, s) in drum_hits().iter().enumerate() {
+            triggers.process_sample(*s, i);
+        }
+        return triggers.take_ops(1.0).iter().map(|entry| entry.start).collect();
+    }
+
+    #[test]
+    fn threshold() {
+        let times = trigger_times(TriggerMode::Threshold, 0.2);
+        assert_eq!(times.len(), 4);
+        for (i, time) in times.iter().enumerate() {
+            assert!(*time >= i * 22050 && *time < i * 22050 + 500);
+        }
+    }
+
+    #[test]
+    fn onset() {
+        let times = trigger_times(TriggerMode::Onset, 2.0);
+        assert_eq!(times.len(), 4);
+        for (i, time) in times.iter().enumerate() {
+            assert!(*time >= i * 22050 && *time < i * 22050 + 500);
+        }
+    }
+
+    #[test]
+    fn take_ops() {
+        let mut triggers = AudioTriggers::new();
+        triggers.add_trigger(crate::audio_trigger::new_from_string("threshold 0.5 0.5 repeat 0.125", TEST_SAMPLE_RATE).unwrap());
+        for i in 0..100 {
+            triggers.process_sample(1.0, 1000 + i);
+        }
+
+        // Map 1:1 beat fractions to samples.
+        let ops = triggers.take_ops(1.0);
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].duration, Some(8));
+        assert_eq!(ops[0].operation, Operation::Repeat { loop_size: BeatFraction::from(0.125) });
+        assert!(triggers.take_ops(1.0).is_empty());
+
+        assert!(crate::audio_trigger::new_from_string("loud 0.5 0.5 reverse", TEST_SAMPLE_RATE).is_err());
+        assert!(crate::audio_trigger::new_from_string("onset 2 0.5 repeat", TEST_SAMPLE_RATE).is_err());
+    }
+}
+
+#[cfg(test)]
+mod accompanist {
+    use crate::Boucle;
+    use crate::Config;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::accompanist::Accompanist;
+    use crate::event::StateChange;
+
+    const TEST_SAMPLE_RATE: u32 = 44100;
+    const TEST_BEATS: usize = 32;
+
+    // A drum hit on every half beat, at one beat per second.
+    fn busy_input() -> Vec<Sample> {
+        (0..TEST_SAMPLE_RATE as usize * TEST_BEATS).map(|i| {
+            let position = i % (TEST_SAMPLE_RATE as usize / 2);
+            if position < 2000 { 0.8 * (1.0 - position as Sample / 2000.0) } else { 0.0 }
+        }).collect()
+    }
+
+    fn run(input: &[Sample], intensity: f32, seed: u64) -> Vec<String> {
+        let mut accompanist = Accompanist::new(intensity, TEST_SAMPLE_RATE as f32, TEST_SAMPLE_RATE, seed);
+        let mut ops = Vec::new();
+        for (block, samples) in input.chunks(512).enumerate() {
+            let block_start = block * 512;
+            for (i, s) in samples.iter().enumerate() {
+                accompanist.process_sample(*s, block_start + i);
+            }
+            ops.extend(accompanist.take_ops().map(|entry| format!("{}", entry)));
+        }
+        return ops;
+    }
+
+    #[test]
+    fn deterministic() {
+        let input = busy_input();
+        let ops = run(&input, 0.5, 1);
+        assert!(!ops.is_empty());
+        assert_eq!(ops, run(&input, 0.5, 1));
+        assert_ne!(ops, run(&input, 0.5, 2));
+    }
+
+    #[test]
+    fn intensity() {
+        let input = busy_input();
+        assert!(run(&input, 0.0, 1).is_empty());
+        assert!(run(&input, 1.0, 1).len() > run(&input, 0.2, 1).len());
+    }
+
+    #[test]
+    fn keeps_to_itself() {
+        let config = Config { sample_rate: TEST_SAMPLE_RATE, beat_fraction_to_samples: TEST_SAMPLE_RATE as f32 / 16.0, seed: 0 };
+        let mut boucle = Boucle::new(&config, TEST_SAMPLE_RATE as usize);
+        let mut accompanist = Accompanist::new(1.0, TEST_SAMPLE_RATE as f32, TEST_SAMPLE_RATE, 1);
+        boucle.event_recorder.record_event_at(0, StateChange::On, Operation::Reverse, 1.0);
+
+        let input = busy_input();
+        let mut accompanist_ops = 0;
+        for (block, samples) in input.chunks(512).enumerate() {
+            let block_start = block * 512;
+            for (i, s) in samples.iter().enumerate() {
+                accompanist.process_sample(*s, block_start + i);
+            }
+            for entry in accompanist.take_ops() {
+                accompanist_ops += 1;
+                boucle.pattern_player.schedule(entry);
+            }
+            let ops = boucle.ops_for_period(block_start, samples.len());
+            // The performer's reverse is held, whatever the accompanist does.
+            assert!(ops.iter().any(|entry| entry.operation == Operation::Reverse && entry.duration.is_none()));
+        }
+        assert!(accompanist_ops > 0);
+    }
+
+    #[test]
+    fn activity() {
+        let mut accompanist = Accompanist::new(0.5, TEST_SAMPLE_RATE as f32, TEST_SAMPLE_RATE, 1);
+        for i in 0..TEST_SAMPLE_RATE as usize * 2 {
+            accompanist.process_sample(0.0, i);
+        }
+        assert_eq!(accompanist.activity(), 0.0);
+
+        for (i, s) in busy_input().iter().enumerate().take(TEST_SAMPLE_RATE as usize * 4) {
+            accompanist.process_sample(*s, TEST_SAMPLE_RATE as usize * 2 + i);
+        }
+        assert!(accompanist.activity() > 0.3);
+    }
+}
+
+#[cfg(test)]
+mod history {
+    use crate::Boucle;
+    use crate::Config;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::buffers::create_buffers_with_history;
+    use crate::op_sequence;
+    use crate::OpSequence;
+
+    const TEST_CONFIG: Config = Config {
+        sample_rate: 44100,
+        // Map 1:1 beats to samples.
+        beat_fraction_to_samples: 1.0 / 16.0,
+        seed: 0,
+    };
+
+    #[test]
+    fn playing_without_recording() {
+        // Playback can catch up with the loop being recorded, if there is no input.
+        let mut buffers = create_buffers_with_history(4, 2);
+        buffers.next_output();
+        assert_eq!(buffers.current_output, buffers.current_input);
+        assert_eq!(buffers.output_history().len(), 2);
+    }
+
+    #[test]
+    fn ring() {
+        let mut buffers = create_buffers_with_history(4, 3);
+
+        // Record loops 1, 2 and 3, playing each loop as the next is recorded.
+        for take in 1..4 {
+            let current_input = buffers.current_input;
+            for i in 0..4 {
+                buffers.history[current_input][i] = take as Sample;
+            }
+            buffers.next_input();
+            buffers.next_output();
+        }
+
+        // The oldest loop was overwritten, and the ring only has room
+        // for two loops that aren't being recorded.
+        let history = buffers.output_history();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0], &[3.0; 4]);
+        assert_eq!(history[1], &[2.0; 4]);
+    }
+
+    #[test]
+    fn depth_is_bounded() {
+        assert_eq!(create_buffers_with_history(4, 0).history.len(), crate::buffers::DEFAULT_HISTORY_DEPTH);
+        assert_eq!(create_buffers_with_history(4, 1000).history.len(), crate::buffers::MAX_HISTORY_DEPTH);
+    }
+
+    #[test]
+    fn past_loop() {
+        let current: Vec<Sample> = vec!(1.0, 2.0, 3.0, 4.0);
+        let previous: Vec<Sample> = vec!(5.0, 6.0, 7.0, 8.0);
+        let history = [current.as_slice(), previous.as_slice()];
+        let boucle: Boucle = Boucle::new(&TEST_CONFIG, current.len());
+
+        let ops: OpSequence = vec!(
+            op_sequence::Entry { start: 1, duration: Some(2), operation: Operation::PastLoop { loops_back: 1 }, probability: 1.0 },
+            // Older than the history we have, so plays the oldest loop.
+            op_sequence::Entry { start: 6, duration: Some(1), operation: Operation::PastLoop { loops_back: 3 }, probability: 1.0 },
+        );
+
+        let mut output: Vec<Sample> = Vec::new();
+        boucle.process_history(&history, 0, 8, &ops, &mut |s| output.push(s));
+        assert_eq!(output, vec!(1.0, 6.0, 7.0, 4.0, 1.0, 2.0, 7.0, 4.0));
+
+        assert_eq!(crate::ops::operation_from_parts("past-loop", &["2"]).unwrap(), Operation::PastLoop { loops_back: 2 });
+    }
+}
+
+#[cfg(test)]
+mod freeze {
+    use crate::Sample;
+    use crate::buffers::LoopBuffers;
+    use crate::buffers::create_buffers;
+    use crate::control_surface::midi::MidiControlSurface;
+    use crate::control_surface::midi::op1::Op1;
+    use crate::event::Command;
+
+    // Record one loop of `value`, as the input callback would, and move on.
+    fn record_loop(buffers: &mut LoopBuffers, value: Sample) {
+        for i in 0..buffers.buffer_length() {
+            if buffers.is_recording() {
+                let current_input = buffers.current_input;
+                buffers.history[current_input][i] = value;
+            }
+        }
+        buffers.next_input();
+        buffers.next_output();
+    }
+
+    #[test]
+    fn hold_and_release() {
+        let mut buffers = create_buffers(4);
+        record_loop(&mut buffers, 1.0);
+        assert_eq!(buffers.output_buffer(), &[1.0; 4]);
+
+        buffers.freeze();
+        record_loop(&mut buffers, 2.0);
+        record_loop(&mut buffers, 3.0);
+        assert!(buffers.is_frozen());
+        assert_eq!(buffers.output_buffer(), &[1.0; 4]);
+
+        // Unfreezing waits for the loop boundary, and the frozen loop
+        // plays until the next loop has been recorded.
+        buffers.unfreeze();
+        assert!(!buffers.is_recording());
+        record_loop(&mut buffers, 4.0);
+        assert!(!buffers.is_frozen());
+        assert_eq!(buffers.output_buffer(), &[1.0; 4]);
+
+        record_loop(&mut buffers, 5.0);
+        assert_eq!(buffers.output_buffer(), &[5.0; 4]);
+    }
+
+    #[test]
+    fn sustain_pedal() {
+        let op1 = Op1 {};
+        assert_eq!(op1.map_midi_command(0xB0, 64, 127), Some(Command::Freeze));
+        assert_eq!(op1.map_midi_command(0xB0, 64, 0), Some(Command::Unfreeze));
+        assert_eq!(op1.map_midi_command(0x90, 64, 127), None);
+    }
+}
+
+#[cfg(test)]
+mod overdub {
+    use crate::buffers::Overdub;
+    use crate::buffers::create_buffers;
+    use crate::buffers::soft_limit;
+
+    #[test]
+    fn feedback() {
+        let mut buffers = create_buffers(4);
+        buffers.history[0] = vec!(0.4; 4);
+
+        buffers.start_overdub(Overdub { feedback: 0.5, print: 0.0 });
+        for i in 0..4 {
+            buffers.record_sample(i, 0.1);
+        }
+        buffers.next_input();
+        buffers.next_output();
+
+        // Mixed into the loop being played, which is still playing.
+        assert_eq!(buffers.output_buffer(), &[0.3; 4]);
+        assert_eq!(buffers.history[1], &[0.0; 4]);
+    }
+
+    #[test]
+    fn loud_input_unchanged() {
+        let mut buffers = create_buffers(4);
+        buffers.start_overdub(Overdub { feedback: 0.0, print: 0.0 });
+        for i in 0..4 {
+            buffers.record_sample(i, 0.7);
+        }
+        buffers.next_input();
+        buffers.next_output();
+
+        assert_eq!(buffers.output_buffer(), &[0.7; 4]);
+    }
+
+    #[test]
+    fn print_output() {
+        let mut buffers = create_buffers(4);
+        buffers.print_output(0, &[0.5; 4]);
+        assert_eq!(buffers.output_buffer(), &[0.0; 4]);
+
+        buffers.start_overdub(Overdub { feedback: 1.0, print: 0.5 });
+        assert!(buffers.is_printing());
+        buffers.print_output(2, &[0.2, 0.4]);
+        assert_eq!(buffers.output_buffer(), &[0.0, 0.0, 0.1, 0.2]);
+    }
+
+    #[test]
+    fn limiter() {
+        assert_eq!(soft_limit(0.25), 0.25);
+        assert_eq!(soft_limit(-0.5), -0.5);
+        assert_eq!(soft_limit(0.9), 0.9);
+        assert!(soft_limit(0.95) < 0.95);
+        assert!(soft_limit(-100.0) >= -1.0);
+
+        // Runaway feedback stays below full scale.
+        let mut buffers = create_buffers(1);
+        buffers.start_overdub(Overdub { feedback: 1.0, print: 1.0 });
+        for _ in 0..1000 {
+            buffers.record_sample(0, 0.9);
+            let output = buffers.output_buffer()[0];
+            buffers.print_output(0, &[output]);
+        }
+        assert!(buffers.output_buffer()[0] <= 1.0);
+        assert!(buffers.output_buffer()[0] > 0.9);
+    }
+}
+
+#[cfg(test)]
+mod bounce {
+    use crate::Sample;
+    use crate::buffers::LoopBuffers;
+    use crate::buffers::create_buffers;
+
+    // Play one loop, as the output callback would, with `value` as the
+    // processed output.
+    fn play_loop(buffers: &mut LoopBuffers, value: Sample) {
+        if buffers.is_printing() {
+            buffers.print_output(0, &[value; 4]);
+        }
+        buffers.next_input();
+        buffers.next_output();
+    }
+
+    #[test]
+    fn quantized_capture() {
+        let mut buffers = create_buffers(4);
+        buffers.history[0] = vec!(1.0; 4);
+
+        // Nothing is captured until the next loop boundary.
+        buffers.bounce();
+        assert!(buffers.is_bouncing());
+        assert!(!buffers.is_printing());
+        play_loop(&mut buffers, 1.0);
+
+        assert!(buffers.is_printing());
+        play_loop(&mut buffers, 2.0);
+
+        // The captured output is now held as the loop being played.
+        assert!(!buffers.is_bouncing());
+        assert!(buffers.is_frozen());
+        assert_eq!(buffers.output_buffer(), &[2.0; 4]);
+        play_loop(&mut buffers, 3.0);
+        assert_eq!(buffers.output_buffer(), &[2.0; 4]);
+    }
+}
+
+#[cfg(test)]
+mod resize {
+    use crate::Boucle;
+    use crate::Config;
+    use crate::Sample;
+    use crate::buffers::LoopBuffers;
+    use crate::buffers::LoopSpace;
+    use crate::buffers::ResizeFill;
+    use crate::buffers::create_buffers;
+
+    fn resized(fill: ResizeFill) -> LoopBuffers {
+        let mut buffers = create_buffers(4);
+        buffers.history[0] = vec!(1.0, 2.0, 3.0, 4.0);
+        buffers.resize_loop(6, fill);
+        // Nothing changes until the loop boundary.
+        assert_eq!(buffers.play_length(), 4);
+        assert_eq!(buffers.record_length(), 4);
+        buffers.next_input();
+        buffers.next_output();
+        assert_eq!(buffers.record_length(), 6);
+        assert_eq!(buffers.play_length(), 6);
+        return buffers;
+    }
+
+    #[test]
+    fn extend() {
+        let buffers = resized(ResizeFill::Repeat);
+        assert_eq!(buffers.history[0], &[1.0, 2.0, 3.0, 4.0, 1.0, 2.0]);
+
+        let buffers = resized(ResizeFill::Silence);
+        assert_eq!(buffers.history[0], &[1.0, 2.0, 3.0, 4.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn extend_while_recording() {
+        let mut buffers = create_buffers(4);
+        buffers.resize_loop(6, ResizeFill::Repeat);
+        // The loop recorded before the boundary is repeated, as recorded.
+        for i in 0..4 {
+            buffers.record_sample(i, 5.0 + i as Sample);
+        }
+        buffers.next_input();
+        buffers.next_output();
+        assert_eq!(buffers.output_buffer(), &[5.0, 6.0, 7.0, 8.0, 5.0, 6.0]);
+
+        // Recording at the new length isn't repeated.
+        for i in 0..6 {
+            buffers.record_sample(i, 1.0);
+        }
+        assert_eq!(buffers.output_buffer(), &[5.0, 6.0, 7.0, 8.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn extend_from_input() {
+        let mut buffers = resized(ResizeFill::Input);
+        let current_output = buffers.current_output;
+        for i in 0..6 {
+            buffers.record_sample(i, 9.0);
+        }
+        // The new part of the loop being played gets the input.
+        assert_eq!(buffers.history[current_output][..4], [0.0; 4]);
+        assert_eq!(buffers.history[current_output][4..], [9.0; 2]);
+    }
+
+    #[test]
+    fn extend_into_space() {
+        let mut buffers = create_buffers(4);
+        buffers.history[0] = vec!(1.0, 2.0, 3.0, 4.0);
+        let space = LoopSpace::new(6, buffers.loop_count());
+        let old_space = buffers.resize_loop_into(6, ResizeFill::Repeat, space);
+        buffers.next_input();
+        buffers.next_output();
+        assert_eq!(buffers.history[0], &[1.0, 2.0, 3.0, 4.0, 1.0, 2.0]);
+
+        // Space that isn't needed comes back.
+        let space = buffers.resize_loop_into(2, ResizeFill::Repeat, old_space);
+        assert_eq!(buffers.buffer_length(), 6);
+        // The wrong size of space is replaced.
+        buffers.resize_loop_into(8, ResizeFill::Silence, space);
+        assert_eq!(buffers.buffer_length(), 8);
+    }
+
+    #[test]
+    fn shrink() {
+        let mut buffers = create_buffers(4);
+        buffers.resize_loop(2, ResizeFill::Repeat);
+        buffers.next_input();
+        buffers.next_output();
+        assert_eq!(buffers.play_length(), 2);
+        assert_eq!(buffers.buffer_length(), 4);
+    }
+
+    #[test]
+    fn restart_loop() {
+        let config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+        let mut boucle = Boucle::new(&config, 4);
+        assert_eq!(boucle.loop_position(10), 2);
+
+        // The loop restarts at the boundary, without the clock jumping.
+        boucle.restart_loop(6, 12);
+        assert_eq!(boucle.loop_position(12), 0);
+        assert_eq!(boucle.loop_position(19), 1);
+
+        let input: Vec<Sample> = vec!(1.0, 2.0, 3.0, 4.0, 5.0, 6.0);
+        let mut output: Vec<Sample> = Vec::new();
+        boucle.process_buffer(&input, 12, 8, &Vec::new(), &mut |s| output.push(s));
+        assert_eq!(output, vec!(1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 1.0, 2.0));
+    }
+}
+
+#[cfg(test)]
+mod punch {
+    use crate::buffers::LoopBuffers;
+    use crate::buffers::LoopSpace;
+    use crate::buffers::create_buffers;
+    use crate::punch::Punch;
+
+    // Feed the clock as input, from `from` up to `to`.
+    fn feed(buffers: &mut LoopBuffers, from: usize, to: usize) {
+        for clock in from..to {
+            buffers.punch_sample(clock, clock as f32);
+        }
+    }
+
+    #[test]
+    fn count_in_and_auto_stop() {
+        // Bars of 4 samples: 1 bar count-in, then record 2 bars.
+        let mut buffers = create_buffers(4);
+        buffers.arm_punch(Punch::new(2, 2, 1, Some(2), 1000));
+        assert_eq!(buffers.buffer_length(), 8);
+
+        // Count-in starts at the next bar, at 4, so we punch in at 8.
+        feed(&mut buffers, 1, 20);
+        assert!(!buffers.is_punching());
+        assert_eq!(buffers.take_punched_loop(), Some((16, 8)));
+        assert_eq!(buffers.take_punched_loop(), None);
+
+        let expected: Vec<f32> = (8..16).map(|clock| clock as f32).collect();
+        assert_eq!(buffers.output_buffer(), &expected);
+        assert_eq!(buffers.play_length(), 8);
+        assert_eq!(buffers.record_length(), 8);
+        assert!(buffers.is_frozen());
+        assert_eq!(buffers.take_record_restart(), Some(16));
+    }
+
+    #[test]
+    fn punch_out() {
+        let mut buffers = create_buffers(16);
+        buffers.arm_punch(Punch::new(2, 2, 0, None, 1000));
+        feed(&mut buffers, 0, 6);
+        buffers.punch_out();
+        // Recording carries on to the end of the bar.
+        feed(&mut buffers, 6, 12);
+        assert_eq!(buffers.take_punched_loop(), Some((8, 8)));
+        assert_eq!(buffers.output_buffer()[..8], [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+    }
+
+    #[test]
+    fn stops_when_full() {
+        let mut buffers = create_buffers(10);
+        buffers.arm_punch(Punch::new(2, 2, 0, None, 1000));
+        feed(&mut buffers, 0, 20);
+        // Only whole bars that fit in the buffer.
+        assert_eq!(buffers.take_punched_loop(), Some((8, 8)));
+    }
+
+    #[test]
+    fn grow_into_space() {
+        let mut buffers = create_buffers(4);
+        let punch = Punch::new(2, 2, 0, Some(4), 1000);
+        let space = LoopSpace::new(punch.buffer_length(), buffers.loop_count());
+        let old_loops = buffers.arm_punch_into(punch, space);
+        assert_eq!(buffers.buffer_length(), 16);
+        assert!(buffers.history.iter().all(|buffer| buffer.capacity() == 16));
+        drop(old_loops);
+
+        feed(&mut buffers, 0, 20);
+        assert_eq!(buffers.take_punched_loop(), Some((16, 16)));
+    }
+
+    #[test]
+    fn click() {
+        let mut punch = Punch::new(100, 2, 1, None, 1000);
+        assert_eq!(punch.process(1, 1000), None);
+        // Count-in is from 200 to 400, with a 20ms click on each beat.
+        assert_eq!(punch.click(150), 0.0);
+        assert_ne!(punch.click(201), 0.0);
+        assert_eq!(punch.click(250), 0.0);
+        assert_ne!(punch.click(305), 0.0);
+        assert_eq!(punch.process(400, 1000), Some(0));
+        assert_eq!(punch.click(401), 0.0);
+    }
+}
+
+#[cfg(test)]
+mod loop_station {
+    use crate::Config;
+    use crate::Sample;
+    use crate::buses::Frame;
+    use crate::event::StateChange;
+    use crate::loop_station::LoopStation;
+    use crate::loop_station::Route;
+    use crate::ops::Operation;
+    use crate::units::BeatFraction;
+
+    use std::time::Instant;
+
+    fn station() -> LoopStation {
+        let config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0, seed: 0 };
+        let mut station = LoopStation::new(4);
+        station.add_track(&config, 1.0, 2);
+        station.add_track(&config, 2.0, 2);
+        return station;
+    }
+
+    #[test]
+    fn track_lengths() {
+        let station = station();
+        assert_eq!(station.tracks[0].buffers.buffer_length(), 4);
+        assert_eq!(station.tracks[1].buffers.buffer_length(), 8);
+        assert_eq!(station.tracks[1].boucle.loop_length(), 8);
+    }
+
+    #[test]
+    fn only_armed_tracks_record() {
+        let mut station = station();
+        assert!(station.tracks[0].is_armed());
+        assert!(!station.tracks[1].is_armed());
+
+        station.record(&[1.0; 4]);
+        assert_eq!(station.tracks[0].buffers.history[1], &[1.0; 4]);
+        assert_eq!(station.tracks[1].buffers.history[1], &[0.0; 8]);
+    }
+
+    #[test]
+    fn mix() {
+        let mut station = station();
+        station.tracks[0].buffers.history[0] = vec!(1.0; 4);
+        station.tracks[1].buffers.history[0] = vec!(2.0; 8);
+        station.tracks[1].volume = 0.5;
+
+        let mut output = [Frame::default(); 4];
+        station.play(&mut output);
+        assert_eq!(output.iter().map(|frame| frame.main).collect::<Vec<Sample>>(), [2.0; 4]);
+
+        station.tracks[0].mute = true;
+        station.play(&mut output);
+        assert_eq!(output.iter().map(|frame| frame.main).collect::<Vec<Sample>>(), [1.0; 4]);
+        // Muted tracks keep time.
+        assert_eq!(station.tracks[0].buffers.play_clock, 8);
+    }
+
+    #[test]
+    fn dry_once() {
+        let mut station = station();
+        station.tracks[0].buffers.history[0] = vec!(1.0; 4);
+        station.tracks[1].buffers.history[0] = vec!(2.0; 8);
+        station.tracks[1].volume = 0.5;
+        let input = [0.25, 0.5, 0.75, 1.0];
+
+        let mut output = [Frame::default(); 4];
+        station.record(&input);
+        station.play(&mut output);
+        assert_eq!(output.iter().map(|frame| frame.dry).collect::<Vec<Sample>>(), input);
+        assert_eq!(output.iter().map(|frame| frame.main).collect::<Vec<Sample>>(), [2.0; 4]);
+
+        // Only the input, however many tracks there are, whatever their volume.
+        station.set_dry_wet(0.0);
+        station.tracks[0].mute = true;
+        station.record(&input);
+        station.play(&mut output);
+        assert_eq!(output.iter().map(|frame| frame.dry).collect::<Vec<Sample>>(), input);
+        assert_eq!(output.iter().map(|frame| frame.main).collect::<Vec<Sample>>(), input);
+        assert_eq!(output.iter().map(|frame| frame.wet).collect::<Vec<Sample>>(), [1.0; 4]);
+    }
+
+    #[test]
+    fn realignment() {
+        let config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0, seed: 0 };
+        let mut station = LoopStation::new(4);
+        station.add_track(&config, 1.0, 2);
+        station.add_track(&config, 0.75, 2);
+        assert_eq!(station.tracks[1].boucle.loop_length(), 3);
+        assert_eq!(station.next_realignment(), Some(0));
+
+        let mut output = [Frame::default(); 5];
+        station.play(&mut output);
+        assert_eq!(station.next_realignment(), Some(12));
+
+        // A loop that restarted part way through shifts where they meet.
+        station.tracks[0].boucle.restart_loop(4, 7);
+        assert_eq!(station.next_realignment(), Some(15));
+
+        // Loops the same length but out of phase never meet.
+        station.tracks[1].boucle.restart_loop(4, 8);
+        assert_eq!(station.next_realignment(), None);
+    }
+
+    #[test]
+    fn route_events() {
+        let mut station = station();
+        let now = Instant::now();
+        station.set_event_sync_point(now);
+        station.record_event(Route::Track(1), now, StateChange::On, Operation::Reverse, 1.0);
+        // Turned off on the track it was turned on for, wherever it is routed.
+        station.record_event(Route::All, now, StateChange::Off, Operation::Reverse, 1.0);
+        station.record_event(Route::All, now, StateChange::On, Operation::Jump { offset: BeatFraction::from(1.0) }, 1.0);
+
+        let ops_0 = station.tracks[0].boucle.event_recorder.ops_for_period(0, 4);
+        let ops_1 = station.tracks[1].boucle.event_recorder.ops_for_period(0, 4);
+        assert_eq!(ops_0.len(), 1);
+        assert_eq!(ops_1.len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod passthrough {
+    use crate::BeatFraction;
+    use crate::Config;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::buffers::create_buffers;
+    use crate::loop_station::Track;
+    use crate::op_sequence;
+    use crate::passthrough::Passthrough;
+
+    // Map 1:1 beats to samples.
+    const TEST_CONFIG: Config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+
+    fn ramp(from: usize, to: usize) -> Vec<Sample> {
+        return (from..to).map(|i| i as Sample).collect();
+    }
+
+    #[test]
+    fn ring() {
+        let mut passthrough = Passthrough::new(4);
+        for s in ramp(0, 6) {
+            passthrough.write(s);
+        }
+        assert_eq!(passthrough.read(5), 5.0);
+        assert_eq!(passthrough.read(2), 2.0);
+        // Too old, and not written yet.
+        assert_eq!(passthrough.read(1), 0.0);
+        assert_eq!(passthrough.read(6), 0.0);
+    }
+
+    #[test]
+    fn made_beforehand() {
+        let mut buffers = create_buffers(4);
+        let length = buffers.passthrough_length();
+        assert!(buffers.start_passthrough_with(Passthrough::new(length)).is_none());
+        assert_eq!(buffers.passthrough.as_ref().map(|passthrough| passthrough.length()), Some(length));
+        // Already on, so it comes back.
+        assert!(buffers.start_passthrough_with(Passthrough::new(length)).is_some());
+        assert!(buffers.take_passthrough().is_some());
+        assert!(buffers.passthrough.is_none());
+    }
+
+    #[test]
+    fn no_delay() {
+        let mut track = Track::new(&TEST_CONFIG, 8, 2);
+        track.buffers.start_passthrough();
+
+        let mut output = Vec::new();
+        for block in 0..3 {
+            track.record(&ramp(block * 4, block * 4 + 4));
+            track.play(4, &mut |frame| output.push(frame.main));
+        }
+        assert_eq!(output, ramp(0, 12));
+    }
+
+    #[test]
+    fn repeat() {
+        let mut track = Track::new(&TEST_CONFIG, 8, 2);
+        track.buffers.start_passthrough();
+        track.boucle.pattern_player.schedule(op_sequence::Entry {
+            start: 4,
+            duration: Some(6),
+            operation: Operation::Repeat { loop_size: BeatFraction::from(2.0) },
+            probability: 1.0,
+        });
+
+        let mut output = Vec::new();
+        for block in 0..3 {
+            track.record(&ramp(block * 4, block * 4 + 4));
+            track.play(4, &mut |frame| output.push(frame.main));
+        }
+        // Live, then repeating the first 2 samples after the op started.
+        assert_eq!(output, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 4.0, 5.0, 4.0, 5.0, 10.0, 11.0]);
+    }
+
+    #[test]
+    fn jump_forward_goes_round_the_loop() {
+        let mut track = Track::new(&TEST_CONFIG, 8, 2);
+        track.buffers.start_passthrough();
+        track.boucle.pattern_player.schedule(op_sequence::Entry {
+            start: 8,
+            duration: Some(4),
+            operation: Operation::Jump { offset: BeatFraction::from(2.0) },
+            probability: 1.0,
+        });
+
+        let mut output = Vec::new();
+        for block in 0..3 {
+            track.record(&ramp(block * 4, block * 4 + 4));
+            track.play(4, &mut |frame| output.push(frame.main));
+        }
+        // 2 samples ahead is 6 behind, in a loop of 8.
+        assert_eq!(output[8..], [2.0, 3.0, 4.0, 5.0]);
+    }
+}
+
+#[cfg(test)]
+mod buses {
+    use crate::BeatFraction;
+    use crate::Config;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::buses::Frame;
+    use crate::loop_station::Track;
+    use crate::op_sequence;
+    use crate::punch::Punch;
+
+    // Map 1:1 beats to samples.
+    const TEST_CONFIG: Config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+
+    fn play(track: &mut Track, input: &[Sample]) -> Vec<Frame> {
+        let mut output = Vec::new();
+        track.record(input);
+        track.play(input.len(), &mut |frame| output.push(frame));
+        return output;
+    }
+
+    #[test]
+    fn dry_wet() {
+        let frame = Frame::new(1.0, 0.5, false, 0.25);
+        assert_eq!(frame.main, 0.875);
+        assert_eq!(frame.buses(), [0.875, 1.0, 0.5, 0.0]);
+        assert_eq!(Frame::new(1.0, 0.5, true, 1.0).ops, 0.5);
+    }
+
+    #[test]
+    fn ops_bus() {
+        let mut track = Track::new(&TEST_CONFIG, 4, 2);
+        track.buffers.history[0] = vec!(1.0, 2.0, 3.0, 4.0);
+        track.boucle.pattern_player.schedule(op_sequence::Entry {
+            start: 2,
+            duration: Some(2),
+            operation: Operation::Jump { offset: BeatFraction::from(-2.0) },
+            probability: 1.0,
+        });
+
+        let output = play(&mut track, &[9.0; 4]);
+        // The station adds the dry input.
+        assert_eq!(output.iter().map(|frame| frame.dry).collect::<Vec<Sample>>(), [0.0; 4]);
+        assert_eq!(output.iter().map(|frame| frame.wet).collect::<Vec<Sample>>(), [1.0, 2.0, 1.0, 2.0]);
+        // Silent until the op starts.
+        assert_eq!(output.iter().map(|frame| frame.ops).collect::<Vec<Sample>>(), [0.0, 0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn punch_passes_input_through() {
+        let mut track = Track::new(&TEST_CONFIG, 4, 2);
+        track.buffers.arm_punch(Punch::new(2, 2, 0, None, 44100));
+        let output = play(&mut track, &[0.5; 4]);
+        assert_eq!(output.iter().map(|frame| frame.main).collect::<Vec<Sample>>(), [0.5; 4]);
+        assert_eq!(output.iter().map(|frame| frame.wet).collect::<Vec<Sample>>(), [0.0; 4]);
+    }
+}
+
+#[cfg(test)]
+mod snapshot {
+    use crate::Config;
+    use crate::Sample;
+    use crate::loop_station::Track;
+    use crate::snapshot::Snapshot;
+
+    // Map 1:1 beats to samples.
+    const TEST_CONFIG: Config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+
+    fn play(track: &mut Track, input: &[Sample]) -> Vec<Sample> {
+        let mut output = Vec::new();
+        track.record(input);
+        track.play(input.len(), &mut |frame| output.push(frame.main));
+        return output;
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut track = Track::new(&TEST_CONFIG, 4, 3);
+        play(&mut track, &[1.0, 2.0, 3.0, 4.0]);
+        play(&mut track, &[5.0, 6.0, 7.0, 8.0]);
+        track.buffers.dry_wet = 0.5;
+
+        let snapshot = Snapshot::take(&track.boucle, &track.buffers);
+        assert_eq!(snapshot.loops, [vec!(5.0, 6.0, 7.0, 8.0), vec!(1.0, 2.0, 3.0, 4.0)]);
+
+        let path = std::env::temp_dir().join(format!("boucle-test-{}", std::process::id())).join("snapshot");
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(loaded, snapshot);
+        assert_eq!(loaded.config().beat_fraction_to_samples, TEST_CONFIG.beat_fraction_to_samples);
+    }
+
+    #[test]
+    fn restore() {
+        let snapshot = Snapshot {
+            sample_rate: 44100,
+            bpm: 60.0,
+            loop_length: 3,
+            dry_wet: 1.0,
+            seed: 0,
+            loops: vec!(vec!(1.0, 2.0, 3.0)),
+        };
+        let mut track = Track::new(&TEST_CONFIG, 4, 2);
+        play(&mut track, &[9.0, 9.0]);
+        snapshot.restore(&mut track.boucle, &mut track.buffers);
+
+        // The loop plays from the start, and is held.
+        assert_eq!(play(&mut track, &[0.0; 4]), [1.0, 2.0, 3.0, 1.0]);
+        assert_eq!(play(&mut track, &[0.0; 4]), [2.0, 3.0, 1.0, 2.0]);
+        assert!(!track.is_armed());
+    }
+
+    #[test]
+    fn take_and_restore_in_steps() {
+        let mut track = Track::new(&TEST_CONFIG, 4, 3);
+        play(&mut track, &[1.0, 2.0, 3.0, 4.0]);
+        play(&mut track, &[5.0, 6.0, 7.0, 8.0]);
+        let mut snapshot = Snapshot::with_room(track.buffers.play_length(), track.buffers.output_history().len());
+        snapshot.take_settings(&track.boucle);
+        snapshot.copy_loops(&track.buffers);
+        assert_eq!(snapshot, Snapshot::take(&track.boucle, &track.buffers));
+
+        // A longer loop grows the buffers, swapping the old ones out.
+        snapshot.loop_length = 6;
+        snapshot.loops = vec!(vec!(1.0, 2.0, 3.0, 4.0, 5.0, 6.0));
+        let space = snapshot.loop_space(track.buffers.buffer_length(), track.buffers.loop_count());
+        let _old_loops = snapshot.restore_from(space, &mut track.boucle, &mut track.buffers);
+        assert_eq!(track.buffers.buffer_length(), 6);
+        assert_eq!(play(&mut track, &[0.0; 6]), [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn missing_loop_length() {
+        let path = std::env::temp_dir().join(format!("boucle-test-missing-{}", std::process::id()));
+        std::fs::write(path.with_extension("txt"), "sample_rate = 44100\nloops = 1\n").unwrap();
+        let result = Snapshot::load(&path);
+        std::fs::remove_file(path.with_extension("txt")).unwrap();
+        assert_eq!(result.unwrap_err().message, "missing 'loop_length'");
+    }
+}
+
+#[cfg(test)]
+mod session {
+    use crate::BeatFraction;
+    use crate::Config;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::control_surface::midi::MidiControlSurface;
+    use crate::control_surface::midi::NoteMap;
+    use crate::control_surface::midi::op1::Op1;
+    use crate::event::StateChange;
+    use crate::loop_station::Track;
+    use crate::op_sequence;
+    use crate::session;
+    use crate::session::Session;
+    use crate::snapshot::Snapshot;
+
+    // Map 1:1 beats to samples.
+    const TEST_CONFIG: Config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+
+    fn play(track: &mut Track, input: &[Sample]) -> Vec<Sample> {
+        let mut output = Vec::new();
+        track.record(input);
+        track.play(input.len(), &mut |frame| output.push(frame.main));
+        return output;
+    }
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        return std::env::temp_dir().join(format!("boucle-test-session-{}-{}", name, std::process::id()));
+    }
+
+    fn remove(path: &std::path::Path) {
+        std::fs::remove_file(path.with_extension("wav")).ok();
+        std::fs::remove_file(path.with_extension("txt")).ok();
+    }
+
+    fn test_session() -> Session {
+        Session {
+            snapshot: Snapshot {
+                sample_rate: 44100,
+                bpm: 120.0,
+                loop_length: 4,
+                dry_wet: 0.75,
+                seed: 3,
+                loops: vec!(vec!(0.5, -0.5, 0.25, 0.0)),
+            },
+            automation: vec!(
+                op_sequence::Entry { start: 1, duration: Some(2), operation: Operation::Reverse, probability: 1.0 },
+                op_sequence::Entry {
+                    start: 2,
+                    duration: Some(1),
+                    operation: Operation::Repeat { loop_size: BeatFraction::from(0.25) },
+                    probability: 0.5,
+                },
+            ),
+            patterns: vec!("[r ~] j-1".to_string(), "euclid".to_string()),
+            mapping: Some(NoteMap::from_surface(&Op1 {})),
+        }
+    }
+
+    #[test]
+    fn operation_text() {
+        for operation in [Operation::Reverse,
+                          Operation::Repeat { loop_size: BeatFraction::from(0.0625) },
+                          Operation::Jump { offset: BeatFraction::from(-8.0) },
+                          Operation::SpeedRamp { start_speed: 0.5, end_speed: 1.0 },
+                          Operation::PastLoop { loops_back: 2 }] {
+            let text = operation.to_string();
+            let parts: Vec<&str> = text.split_ascii_whitespace().collect();
+            assert_eq!(crate::ops::operation_from_parts(parts[0], &parts[1..]).unwrap(), operation);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let session = test_session();
+        let path = test_path("round-trip");
+        session.save(&path).unwrap();
+        let loaded = Session::load(&path);
+        remove(&path);
+        assert_eq!(loaded.unwrap(), session);
+    }
+
+    #[test]
+    fn open_ended_round_trip() {
+        let mut session = test_session();
+        session.automation.push(op_sequence::Entry { start: 3, duration: None, operation: Operation::Reverse, probability: 1.0 });
+        session.automation.push(op_sequence::Entry { start: 3, duration: Some(0), operation: Operation::Reverse, probability: 1.0 });
+        let path = test_path("open-ended");
+        session.save(&path).unwrap();
+        let loaded = Session::load(&path);
+        remove(&path);
+        assert_eq!(loaded.unwrap(), session);
+    }
+
+    #[test]
+    fn mapping() {
+        let mapping = test_session().mapping.unwrap();
+        for note in 0..=127 {
+            assert_eq!(mapping.map_midi_note(note), Op1 {}.map_midi_note(note));
+        }
+    }
+
+    #[test]
+    fn migrate_snapshot() {
+        let session = test_session();
+        let path = test_path("migrate");
+        session.snapshot.save(&path).unwrap();
+        let loaded = Session::load(&path);
+        remove(&path);
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.snapshot, session.snapshot);
+        assert!(loaded.automation.is_empty());
+        assert!(loaded.patterns.is_empty());
+        assert_eq!(loaded.mapping, None);
+    }
+
+    #[test]
+    fn newer_version() {
+        let error = session::migrate("version = 99\n").unwrap_err();
+        assert!(error.message.contains("newer"), "{}", error.message);
+    }
+
+    #[test]
+    fn take_and_restore() {
+        let mut track = Track::new(&TEST_CONFIG, 4, 2);
+        track.boucle.event_recorder.record_event_at(5, StateChange::On, Operation::Reverse, 1.0);
+        track.boucle.event_recorder.record_event_at(7, StateChange::Off, Operation::Reverse, 1.0);
+        for _ in 0..3 {
+            play(&mut track, &[1.0, 2.0, 3.0, 4.0]);
+        }
+
+        // The op is kept relative to the start of the loop it was played in.
+        let session = Session::take(&track.boucle, &track.buffers, &[], None);
+        assert_eq!(session.automation, [op_sequence::Entry {
+            start: 1, duration: Some(2), operation: Operation::Reverse, probability: 1.0,
+        }]);
+
+        let mut track = Track::new(&TEST_CONFIG, 4, 2);
+        session.restore(&mut track.boucle, &mut track.buffers).unwrap();
+        assert_eq!(play(&mut track, &[0.0; 4]), [1.0, 2.0, 1.0, 4.0]);
+    }
+
+    #[test]
+    fn made_beforehand() {
+        let session = Session {
+            snapshot: Snapshot {
+                sample_rate: 44100,
+                bpm: 60.0,
+                loop_length: 4,
+                dry_wet: 1.0,
+                seed: 0,
+                loops: vec!(vec!(1.0, 2.0, 3.0, 4.0)),
+            },
+            automation: vec!(op_sequence::Entry {
+                start: 1, duration: Some(2), operation: Operation::Reverse, probability: 1.0,
+            }),
+            patterns: vec!("r".to_string()),
+            mapping: None,
+        };
+        let mut track = Track::new(&TEST_CONFIG, 4, 2);
+        play(&mut track, &[9.0; 2]);
+        track.boucle.pattern_player.schedule(op_sequence::Entry {
+            start: 100, duration: None, operation: Operation::Reverse, probability: 1.0,
+        });
+
+        let space = session.space(0, track.buffers.buffer_length(), track.buffers.loop_count(),
+                                  track.boucle.pattern_player.active_count()).unwrap();
+        let _old_space = session.restore_from(space, &mut track.boucle, &mut track.buffers);
+        // The ops already playing carry on alongside the automation.
+        assert_eq!(track.boucle.pattern_player.active_count(), 2);
+        assert!(!track.boucle.pattern_player.is_empty());
+        assert_eq!(track.buffers.output_history()[0][..4], [1.0, 2.0, 3.0, 4.0]);
+    }
+}
+
+#[cfg(test)]
+mod op_sequence {
+    use crate::BeatFraction;
+    use crate::Operation;
+    use crate::op_sequence;
+    use crate::op_sequence::Entry;
+    use crate::op_sequence::OpSequence;
+
+    fn entry(start: usize, duration: Option<usize>, operation: Operation) -> Entry {
+        return Entry { start, duration, operation, probability: 1.0 };
+    }
+
+    fn test_sequence() -> OpSequence {
+        return vec!(
+            entry(10, Some(20), Operation::Reverse),
+            entry(40, Some(10), Operation::Repeat { loop_size: BeatFraction::from(1.0) }),
+            entry(45, None, Operation::Jump { offset: BeatFraction::from(-1.0) }),
+        );
+    }
+
+    fn spans(sequence: &[Entry]) -> Vec<(usize, Option<usize>)> {
+        return sequence.iter().map(|entry| (entry.start, entry.duration)).collect();
+    }
+
+    #[test]
+    fn shift() {
+        assert_eq!(spans(&op_sequence::shift(&test_sequence(), 5)),
+                   vec!((15, Some(20)), (45, Some(10)), (50, None)));
+        // Cut at 0, or dropped if over by then.
+        assert_eq!(spans(&op_sequence::shift(&test_sequence(), -42)),
+                   vec!((0, Some(8)), (3, None)));
+    }
+
+    #[test]
+    fn scale() {
+        assert_eq!(spans(&op_sequence::scale(&test_sequence(), 0.5)),
+                   vec!((5, Some(10)), (20, Some(5)), (23, None)));
+    }
+
+    #[test]
+    fn quantize() {
+        // A 16th of a beat is 8 samples.
+        let quantized = op_sequence::quantize(&test_sequence(), BeatFraction::from(1.0 / 16.0), 8.0);
+        assert_eq!(spans(&quantized), vec!((8, Some(24)), (40, Some(8)), (48, None)));
+    }
+
+    #[test]
+    fn merge() {
+        let a = vec!(entry(30, Some(1), Operation::Reverse), entry(10, Some(1), Operation::Reverse));
+        let b = vec!(entry(20, Some(1), Operation::NoOp), entry(10, Some(2), Operation::NoOp));
+        let merged = op_sequence::merge(&[&a, &b]);
+        assert_eq!(spans(&merged), vec!((10, Some(1)), (10, Some(2)), (20, Some(1)), (30, Some(1))));
+    }
+
+    #[test]
+    fn trim() {
+        assert_eq!(spans(&op_sequence::trim(&test_sequence(), 20, 48)),
+                   vec!((20, Some(10)), (40, Some(8)), (45, Some(3))));
+    }
+
+    #[test]
+    fn filter() {
+        let repeats = op_sequence::filter(&test_sequence(), |op| matches!(op, Operation::Repeat { .. }));
+        assert_eq!(spans(&repeats), vec!((40, Some(10))));
+    }
+
+    #[test]
+    fn mirror() {
+        let mirrored = op_sequence::mirror(&test_sequence(), 60);
+        assert_eq!(spans(&mirrored), vec!((0, Some(15)), (10, Some(10)), (30, Some(20))));
+        assert_eq!(mirrored[0].operation, Operation::Jump { offset: BeatFraction::from(-1.0) });
+        assert_eq!(mirrored[2].operation, Operation::Reverse);
+    }
+
+    #[test]
+    fn overlaps_and_conflicts() {
+        let mut sequence = test_sequence();
+        sequence.push(entry(0, Some(10), Operation::Reverse));
+        sequence.push(entry(25, Some(10), Operation::Reverse));
+        assert_eq!(op_sequence::overlaps(&sequence), vec!((0, 4), (1, 2)));
+        assert_eq!(op_sequence::conflicts(&sequence), vec!((0, 4)));
+    }
+}
+
+#[cfg(test)]
+mod slices {
+    use crate::BeatFraction;
+    use crate::Boucle;
+    use crate::Config;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::control_surface::midi::MidiControlSurface;
+    use crate::control_surface::midi::NoteMap;
+    use crate::control_surface::midi::slicer::Slicer;
+    use crate::op_sequence;
+    use crate::ops;
+    use crate::slices;
+    use crate::slices::SliceMode;
+
+    // Map 1:1 beats to samples.
+    const TEST_CONFIG: Config = Config { sample_rate: 44100, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+
+    fn play(operation: Operation, start: usize, duration: usize) -> Vec<Sample> {
+        let input: Vec<Sample> = (1..=16).map(|s| s as Sample).collect();
+        let boucle = Boucle::new(&TEST_CONFIG, input.len());
+        let ops = vec!(op_sequence::Entry { start, duration: Some(duration), operation, probability: 1.0 });
+        let mut output = Vec::new();
+        boucle.process_buffer(&input, 0, input.len(), &ops, &mut |s| output.push(s));
+        return output;
+    }
+
+    fn samples(data: &[i16]) -> Vec<Sample> {
+        return data.iter().map(|&s| Sample::from(s)).collect();
+    }
+
+    #[test]
+    fn play_slice() {
+        let operation = Operation::PlaySlice { size: BeatFraction::from(4.0), index: 2 };
+        assert_eq!(play(operation, 4, 8), samples(&[1,2,3,4, 9,10,11,12, 9,10,11,12, 13,14,15,16]));
+    }
+
+    #[test]
+    fn reverse_slices() {
+        let operation = Operation::ReverseSlices { size: BeatFraction::from(4.0) };
+        assert_eq!(play(operation, 0, 16), samples(&[13,14,15,16, 9,10,11,12, 5,6,7,8, 1,2,3,4]));
+    }
+
+    #[test]
+    fn stretch_slice() {
+        let operation = Operation::StretchSlice { size: BeatFraction::from(4.0), index: 1, slices: 2 };
+        assert_eq!(play(operation, 0, 8), samples(&[5,5,6,6, 7,7,8,8, 9,10,11,12, 13,14,15,16]));
+    }
+
+    #[test]
+    fn shuffle_slices() {
+        let output = play(Operation::ShuffleSlices { size: BeatFraction::from(2.0) }, 0, 16);
+        // Every slice plays once, whole.
+        for slice in output.chunks(2) {
+            assert_eq!(slice[1], slice[0] + 1.0);
+        }
+        let mut sorted = output.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(sorted, samples(&[1,2,3,4, 5,6,7,8, 9,10,11,12, 13,14,15,16]));
+    }
+
+    #[test]
+    fn play_transient_slice() {
+        let positions = |index: usize, onsets: &[usize]| -> Vec<isize> {
+            (0..8).map(|clock| clock as isize + slices::get_transform(
+                Operation::PlayTransientSlice { index }, 1.0 / 16.0, clock, 0, clock, 16, 0, onsets)).collect()
+        };
+        assert_eq!(positions(1, &[2, 7, 12]), vec!(7, 8, 9, 10, 11, 7, 8, 9));
+        // The last slice goes round to the first transient.
+        assert_eq!(positions(2, &[2, 7, 12]), vec!(12, 13, 14, 15, 0, 1, 12, 13));
+        assert_eq!(positions(4, &[2, 7, 12]), positions(1, &[2, 7, 12]));
+        // No transients found yet.
+        assert_eq!(positions(1, &[]), vec!(0, 1, 2, 3, 4, 5, 6, 7));
+    }
+
+    #[test]
+    fn parse() {
+        for (text, operation) in [
+            ("play-slice 0.5 3", Operation::PlaySlice { size: BeatFraction::from(0.5), index: 3 }),
+            ("shuffle-slices 1", Operation::ShuffleSlices { size: BeatFraction::from(1.0) }),
+            ("reverse-slices 0.25", Operation::ReverseSlices { size: BeatFraction::from(0.25) }),
+            ("stretch-slice 1 2 4", Operation::StretchSlice { size: BeatFraction::from(1.0), index: 2, slices: 4 }),
+            ("play-transient-slice 5", Operation::PlayTransientSlice { index: 5 }),
+        ].iter() {
+            let parts: Vec<&str> = text.split(' ').collect();
+            assert_eq!(ops::operation_from_parts(parts[0], &parts[1..]).unwrap(), *operation);
+            assert_eq!(operation.to_string(), *text);
+        }
+
+        assert_eq!(slices::slice_mode_from_string("0.5").unwrap(), SliceMode::Beats(BeatFraction::from(0.5)));
+        assert_eq!(slices::slice_mode_from_string("transients").unwrap(), SliceMode::Transients);
+        assert!(slices::slice_mode_from_string("0").is_err());
+        assert!(slices::slice_mode_from_string("grid").is_err());
+    }
+
+    #[test]
+    fn slicer_keys() {
+        let slicer = Slicer { mode: SliceMode::Beats(BeatFraction::from(0.5)), first_note: 36, slices: 8 };
+        assert_eq!(slicer.map_midi_note(35), Operation::NoOp);
+        assert_eq!(slicer.map_midi_note(36), Operation::PlaySlice { size: BeatFraction::from(0.5), index: 0 });
+        assert_eq!(slicer.map_midi_note(43), Operation::PlaySlice { size: BeatFraction::from(0.5), index: 7 });
+        assert_eq!(slicer.map_midi_note(44), Operation::NoOp);
+
+        let mapping = NoteMap::new(vec!((36, Operation::Reverse), (60, Operation::Reverse)))
+            .overlay(&NoteMap::from_surface(&slicer));
+        assert_eq!(mapping.map_midi_note(36), Operation::PlaySlice { size: BeatFraction::from(0.5), index: 0 });
+        assert_eq!(mapping.map_midi_note(60), Operation::Reverse);
+
+        let slicer = Slicer { mode: SliceMode::Transients, first_note: 36, slices: 8 };
+        assert_eq!(slicer.map_midi_note(38), Operation::PlayTransientSlice { index: 2 });
+    }
+}
+
+#[cfg(test)]
+mod transients {
+    use crate::BeatFraction;
+    use crate::Boucle;
+    use crate::Config;
+    use crate::MarkerRequest;
+    use crate::Operation;
+    use crate::Sample;
+    use crate::SnapMode;
+    use crate::op_sequence;
+    use crate::ops;
+    use crate::transients;
+
+    const TEST_SAMPLE_RATE: u32 = 44100;
+
+    // Map 1:1 beats to samples.
+    const TEST_CONFIG: Config = Config { sample_rate: TEST_SAMPLE_RATE, beat_fraction_to_samples: 1.0 / 16.0, seed: 0 };
+
+    // Decaying notes at `hits`, and silence between them.
+    fn hits(length: usize, hits: &[usize]) -> Vec<Sample> {
+        let mut buffer = vec!(0.0; length);
+        for &hit in hits {
+            for i in 0..4000.min(length - hit) {
+                let t = i as f32 / TEST_SAMPLE_RATE as f32;
+                buffer[hit + i] = 0.8 * (-t * 20.0).exp() * (2.0 * std::f32::consts::PI * 220.0 * t).sin();
+            }
+        }
+        return buffer;
+    }
+
+    #[test]
+    fn detect_onsets() {
+        let onsets = transients::detect_onsets(&hits(44100, &[0, 10000, 30000]), transients::DEFAULT_ONSET_THRESHOLD, TEST_SAMPLE_RATE);
+        assert_eq!(onsets.len(), 3, "{:?}", onsets);
+        for (&onset, &hit) in onsets.iter().zip([0, 10000, 30000].iter()) {
+            assert!(onset >= hit && onset < hit + 50, "onset {} for hit at {}", onset, hit);
+        }
+
+        assert!(transients::detect_onsets(&vec!(0.0; 1000), 2.0, TEST_SAMPLE_RATE).is_empty());
+    }
+
+    #[test]
+    fn zero_crossings() {
+        assert_eq!(transients::zero_crossings(&[-1.0, 1.0, 1.0, -1.0, -0.5, 0.0, 1.0]), vec!(1, 5));
+    }
+
+    #[test]
+    fn nearest() {
+        let points = [10, 50];
+        assert_eq!(transients::nearest(&points, 29, 100), 10);
+        assert_eq!(transients::nearest(&points, 31, 100), 50);
+        // Round the end of the loop.
+        assert_eq!(transients::nearest(&points, 95, 100), 10);
+        assert_eq!(transients::nearest(&[], 95, 100), 95);
+    }
+
+    #[test]
+    fn snapped_transform() {
+        let snap = ops::Snap { points: &[4, 9], loop_start: 0 };
+        let jump = Operation::Jump { offset: BeatFraction::from(-3.0) };
+        // Lands on 4 rather than 6.
+        assert_eq!(ops::get_transform(jump, 1.0 / 16.0, 10, 9, 16, Some(&snap)), -5);
+        assert_eq!(ops::get_transform(jump, 1.0 / 16.0, 10, 9, 16, None), -3);
+
+        // Repeats 4 to 9, rather than 5 to 9.
+        let repeat = Operation::Repeat { loop_size: BeatFraction::from(4.0) };
+        let positions: Vec<isize> = (5..16)
+            .map(|clock| clock + ops::get_transform(repeat, 1.0 / 16.0, clock as usize, 5, 16, Some(&snap)))
+            .collect();
+        assert_eq!(positions, vec!(4, 5, 6, 7, 8, 4, 5, 6, 7, 8, 4));
+    }
+
+    #[test]
+    fn snap_to_zero_crossings() {
+        let input: Vec<Sample> = vec!(-1.0, 1.0, 2.0, 3.0, -1.0, -2.0, 6.0, 7.0);
+        let mut boucle = Boucle::new(&TEST_CONFIG, input.len());
+        let ops = vec!(op_sequence::Entry {
+            start: 4, duration: Some(2), operation: Operation::Jump { offset: BeatFraction::from(-2.0) }, probability: 1.0,
+        });
+
+        let mut output = Vec::new();
+        boucle.process_buffer(&input, 0, input.len(), &ops, &mut |s| output.push(s));
+        assert_eq!(output, vec!(-1.0, 1.0, 2.0, 3.0, 2.0, 3.0, 6.0, 7.0));
+
+        // Jumps to the zero crossing at 1 instead.
+        boucle.set_snap(Some(SnapMode::ZeroCrossings), &input);
+        output.clear();
+        boucle.process_buffer(&input, 0, input.len(), &ops, &mut |s| output.push(s));
+        assert_eq!(output, vec!(-1.0, 1.0, 2.0, 3.0, 1.0, 2.0, 6.0, 7.0));
+
+        assert_eq!(transients::snap_mode_from_string("transients").unwrap(), SnapMode::Transients);
+        assert!(transients::snap_mode_from_string("grid").is_err());
+    }
+
+    #[test]
+    fn marker_handoff() {
+        let input: Vec<Sample> = vec!(-1.0, 1.0, 2.0, 3.0, -1.0, -2.0, 6.0, 7.0);
+        let mut boucle = Boucle::new(&TEST_CONFIG, input.len());
+        boucle.loop_changed();
+        assert_eq!(boucle.take_marker_request(), None);
+        boucle.set_snap(Some(SnapMode::ZeroCrossings), &input);
+        assert_eq!(boucle.take_marker_request(), None);
+
+        // The loop changes on the audio thread, and the markers are found in
+        // a copy of it elsewhere.
+        boucle.loop_changed();
+        let request = boucle.take_marker_request().unwrap();
+        assert_eq!(request, MarkerRequest { snap: Some(SnapMode::ZeroCrossings), onsets: false });
+        assert_eq!(boucle.take_marker_request(), None);
+        let markers = transients::find_markers(&[-1.0, 1.0, -1.0, 1.0], request, TEST_SAMPLE_RATE);
+        assert_eq!(markers.snap_points, vec!(1, 3));
+        assert_eq!(boucle.replace_markers(markers).snap_points, vec!(1, 6));
+
+        let request = MarkerRequest { snap: Some(SnapMode::Transients), onsets: true };
+        let markers = transients::find_markers(&hits(44100, &[0, 10000]), request, TEST_SAMPLE_RATE);
+        assert_eq!(markers.onsets.len(), 2);
+        assert_eq!(markers.snap_points, markers.onsets);
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serialization {
+    use crate::BeatFraction;
+    use crate::Config;
+    use crate::Operation;
+    use crate::control_surface::midi::MidiControlSurface;
+    use crate::control_surface::midi::NoteMap;
+    use crate::op_sequence;
+
+    use serde_json::json;
+
+    #[test]
+    fn operation() {
+        let ops = [
+            (Operation::NoOp, json!({"op": "no-op"})),
+            (Operation::Reverse, json!({"op": "reverse"})),
+            (Operation::Repeat { loop_size: BeatFraction::from(0.25) }, json!({"op": "repeat", "loop_size": 0.25})),
+            (Operation::Jump { offset: BeatFraction::from(-8.0) }, json!({"op": "jump", "offset": -8.0})),
+            (Operation::SpeedRamp { start_speed: 1.0, end_speed: 0.5 },
+             json!({"op": "speed-ramp", "start_speed": 1.0, "end_speed": 0.5})),
+            (Operation::Random, json!({"op": "random"})),
+            (Operation::PastLoop { loops_back: 2 }, json!({"op": "past-loop", "loops_back": 2})),
+        ];
+        for (operation, value) in ops.iter() {
+            assert_eq!(serde_json::to_value(operation).unwrap(), *value);
+            assert_eq!(serde_json::from_value::<Operation>(value.clone()).unwrap(), *operation);
+        }
+    }
+
+    #[test]
+    fn entry() {
+        let entry = op_sequence::Entry {
+            start: 44100,
+            duration: Some(22050),
+            operation: Operation::Reverse,
+            probability: 0.5,
+        };
+        let value = json!({"start": 44100, "duration": 22050, "operation": {"op": "reverse"}, "probability": 0.5});
+        assert_eq!(serde_json::to_value(&entry).unwrap(), value);
+        assert_eq!(serde_json::from_value::<op_sequence::Entry>(value).unwrap(), entry);
+
+        let entry: op_sequence::Entry = serde_json::from_str(r#"{"start": 10, "operation": {"op": "reverse"}}"#).unwrap();
+        assert_eq!(entry.duration, None);
+        assert_eq!(entry.probability, 1.0);
+    }
+
+    #[test]
+    fn config() {
+        let config: Config = serde_json::from_str(r#"{"sample_rate": 48000}"#).unwrap();
+        assert_eq!(config.sample_rate, 48000);
+        assert_eq!(config.beat_fraction_to_samples, Config::default().beat_fraction_to_samples);
+        assert_eq!(serde_json::to_value(&config).unwrap(),
+                   json!({"sample_rate": 48000, "beat_fraction_to_samples": 2756.25, "seed": 0}));
+    }
+
+    #[test]
+    fn mapping() {
+        let value = json!({"notes": [
+            {"note": 60, "op": "reverse"},
+            {"note": 53, "op": "jump", "offset": -8.0},
+        ]});
+        let mapping: NoteMap = serde_json::from_value(value).unwrap();
+        assert_eq!(mapping.map_midi_note(53), Operation::Jump { offset: BeatFraction::from(-8.0) });
+        assert_eq!(mapping.map_midi_note(60), Operation::Reverse);
+
+        // Written sorted by note.
+        assert_eq!(serde_json::to_value(&mapping).unwrap(), json!({"notes": [
+            {"note": 53, "op": "jump", "offset": -8.0},
+            {"note": 60, "op": "reverse"},
+        ]}));
+    }
 }
@@ -0,0 +1,143 @@
+//! Find the hits in a loop that was played without a click.
+//!
+//! An onset detection pass over the loop gives slice markers at transients,
+//! for slicing material that doesn't follow the tempo grid. `Jump` and
+//! `Repeat` ops can also snap to the transients, or to zero crossings, so
+//! glitches of free-time playing land on the hits.
+//!
+//! Finding the markers is too slow for the audio thread. When the loop
+//! changes, `Boucle` asks for them again, and a control thread finds them in
+//! a copy of the loop and hands them back, see `Boucle::take_marker_request()`.
+
+use crate::BeatFraction;
+use crate::Sample;
+use crate::SamplePosition;
+use crate::audio_trigger::AudioTrigger;
+use crate::audio_trigger::TriggerMode;
+use crate::ops::Operation;
+use crate::ops::ParseError;
+
+/// How far the short-term level has to jump above the long-term level to
+/// count as a transient.
+pub const DEFAULT_ONSET_THRESHOLD: f32 = 2.0;
+
+// Look back this far from where an onset is detected for where the hit starts.
+const BACKTRACK_MS: f32 = 5.0;
+
+/// What `Jump` and `Repeat` ops snap to.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum SnapMode {
+    Transients,
+    ZeroCrossings,
+}
+
+/// Parse a snap mode, `transients` or `zero-crossings`.
+pub fn snap_mode_from_string(text: &str) -> Result<SnapMode, ParseError> {
+    return match text {
+        "transients" => Ok(SnapMode::Transients),
+        "zero-crossings" => Ok(SnapMode::ZeroCrossings),
+        _ => Err(ParseError { message: format!("unknown snap mode '{}', expected 'transients' or 'zero-crossings'", text) }),
+    };
+}
+
+fn crosses_zero(before: Sample, after: Sample) -> bool {
+    return (before < 0.0) != (after < 0.0);
+}
+
+/// Positions of the transients in `loop_buffer`, in order. Each marks the
+/// start of a slice.
+///
+/// The loop is played through once to settle the level detection, so a hit
+/// at the start of the loop is found the same as any other.
+pub fn detect_onsets(loop_buffer: &[Sample], threshold: f32, sample_rate: u32) -> Vec<SamplePosition> {
+    let mut trigger = AudioTrigger::new(TriggerMode::Onset, threshold, Operation::NoOp, BeatFraction::from(0.0), sample_rate);
+    for &s in loop_buffer {
+        trigger.process(s);
+    }
+
+    let backtrack = (BACKTRACK_MS / 1000.0 * sample_rate as f32) as SamplePosition;
+    let mut onsets = Vec::new();
+    for (position, &s) in loop_buffer.iter().enumerate() {
+        if trigger.process(s) {
+            // The level rises after the hit starts, so start from the zero
+            // crossing just before it, if there is one.
+            let earliest = position - backtrack.min(position);
+            let start = (earliest + 1..=position).rev()
+                .find(|&i| crosses_zero(loop_buffer[i - 1], loop_buffer[i]))
+                .unwrap_or(position);
+            onsets.push(start);
+        }
+    }
+    return onsets;
+}
+
+/// Positions where `loop_buffer` crosses zero going up, in order.
+pub fn zero_crossings(loop_buffer: &[Sample]) -> Vec<SamplePosition> {
+    return (1..loop_buffer.len())
+        .filter(|&i| loop_buffer[i - 1] < 0.0 && loop_buffer[i] >= 0.0)
+        .collect();
+}
+
+/// What to look for in a loop, see `find_markers()`.
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+pub struct MarkerRequest {
+    pub snap: Option<SnapMode>,
+    pub onsets: bool,
+}
+
+/// Points found in a loop: where `Jump` and `Repeat` snap to, and the
+/// transients that `PlayTransientSlice` plays from.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+pub struct Markers {
+    pub snap_points: Vec<SamplePosition>,
+    pub onsets: Vec<SamplePosition>,
+}
+
+/// Find the points `request` asks for in `loop_buffer`, in order. This plays
+/// through the loop twice, so it's for a control thread rather than the
+/// audio thread.
+pub fn find_markers(loop_buffer: &[Sample], request: MarkerRequest, sample_rate: u32) -> Markers {
+    let onsets = match request.onsets || request.snap == Some(SnapMode::Transients) {
+        true => detect_onsets(loop_buffer, DEFAULT_ONSET_THRESHOLD, sample_rate),
+        false => Vec::new(),
+    };
+    return Markers {
+        snap_points: match request.snap {
+            Some(SnapMode::Transients) => onsets.clone(),
+            Some(SnapMode::ZeroCrossings) => zero_crossings(loop_buffer),
+            None => Vec::new(),
+        },
+        onsets: if request.onsets { onsets } else { Vec::new() },
+    };
+}
+
+/// The point nearest to `position`, going round the end of the loop, or
+/// `position` if there are no points.
+pub fn nearest(points: &[SamplePosition], position: SamplePosition, loop_length: SamplePosition) -> SamplePosition {
+    if points.is_empty() {
+        return position;
+    }
+    let distance = |point: SamplePosition| {
+        let d = if point > position { point - position } else { position - point };
+        d.min(loop_length - d.min(loop_length))
+    };
+    // The nearest is either side of where `position` would go, or at an end.
+    let index = points.partition_point(|&point| point < position);
+    let candidates = [
+        points[index.min(points.len() - 1)],
+        points[index.saturating_sub(1)],
+        points[0],
+        points[points.len() - 1],
+    ];
+    return *candidates.iter().min_by_key(|&&point| distance(point)).unwrap();
+}
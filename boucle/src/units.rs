@@ -12,12 +12,17 @@ pub type SampleOffset = isize;
 /// Corresponds to 𝅘𝅥𝅱 (64th note / hemidemisemiquaver)
 ///
 /// We use this instead of f32, as the latter cannot be a valid hashmap key.
+///
+/// With the `serde` feature it is serialized as a number of beats, for
+/// example `0.25` for a 16th note.
 #[derive(Clone)]
 #[derive(Copy)]
 #[derive(Debug)]
 #[derive(Eq)]
 #[derive(Hash)]
 #[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "f32", into = "f32"))]
 pub struct BeatFraction {
     value: i32,
 }
@@ -30,6 +35,10 @@ impl BeatFraction {
     pub fn as_sample_position(self: &Self, beat_fraction_to_samples: f32) -> SamplePosition {
         (self.value as f32 * beat_fraction_to_samples).floor() as SamplePosition
     }
+
+    pub fn as_beats(self: &Self) -> f32 {
+        self.value as f32 / 16.0
+    }
 }
 
 impl From<f32> for BeatFraction {
@@ -38,6 +47,12 @@ impl From<f32> for BeatFraction {
     }
 }
 
+impl From<BeatFraction> for f32 {
+    fn from(value: BeatFraction) -> Self {
+        value.as_beats()
+    }
+}
+
 impl fmt::Display for BeatFraction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.value)
@@ -1,10 +1,30 @@
+use boucle::Operation;
+
+/// Tempo when none is given.
+pub const DEFAULT_BPM: f32 = 60.0;
+
 pub struct AppConfig {
     pub sample_rate: u32,
     pub loop_time: f32,
+    // Tempo, which patterns and ops in beats follow.
+    pub bpm: f32,
+    // Seed for patterns that make random choices.
+    pub seed: u64,
+    // Candidate ops for `random` ops, and their relative weights.
+    pub random_ops: Vec<(Operation, f32)>,
 }
 
 impl AppConfig {
     pub fn new(sample_rate: u32, loop_time: f32) -> Self {
-        AppConfig { sample_rate, loop_time }
+        AppConfig { sample_rate, loop_time, bpm: DEFAULT_BPM, seed: 0, random_ops: boucle::ops::default_random_ops() }
+    }
+
+    // Config for the engine, the same in batch and live mode.
+    pub fn boucle_config(self: &Self) -> boucle::Config {
+        boucle::Config {
+            sample_rate: self.sample_rate,
+            beat_fraction_to_samples: (60.0 / self.bpm / 16.0) * (self.sample_rate as f32),
+            seed: self.seed,
+        }
     }
 }
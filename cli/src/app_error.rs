@@ -42,3 +42,11 @@ impl From<hound::Error> for AppError {
         }
     }
 }
+
+impl From<boucle::session::SessionError> for AppError {
+    fn from(error: boucle::session::SessionError) -> Self {
+        AppError {
+            message: error.to_string(),
+        }
+    }
+}
@@ -1,59 +1,148 @@
 use std::io;
 use std::io::Read;
 use std::fs::File;
+use std::path::Path;
 
 use dasp::{Sample};
 use log::*;
 
-use boucle::buffers::create_buffers;
+use boucle::buffers::{LoopBuffers, create_buffers};
+use boucle::mini_notation;
+use boucle::mini_notation::MiniNotation;
 use boucle::op_sequence;
 use boucle::OpSequence;
+use boucle::Operation;
+use boucle::session::Session;
 
 use crate::app_config::AppConfig;
 use crate::wav::input_wav_to_buffer;
 
-fn read_ops(sample_rate: u32, file_name: &str) -> Result<OpSequence, io::Error> {
+const BLOCK_SIZE: usize = 512;
+
+// Parse a line of the form `pattern START DURATION MINI-NOTATION`.
+fn read_pattern_line(sample_rate: u32, beats_to_samples: f32, args: &str) -> OpSequence {
+    let parts: Vec<&str> = args.trim().splitn(3, char::is_whitespace).collect();
+    if parts.len() < 3 {
+        panic!("Pattern line needs start, duration and pattern: '{}'", args);
+    }
+    let seconds_to_beats = sample_rate as f32 / beats_to_samples;
+    let start_seconds = parts[0].parse::<f32>().expect("Failed to parse pattern start");
+    let duration_seconds = parts[1].parse::<f32>().expect("Failed to parse pattern duration");
+    let pattern = MiniNotation::parse(parts[2], mini_notation::DEFAULT_BEATS_PER_BAR)
+        .unwrap_or_else(|e| panic!("Failed to parse pattern '{}': {}", parts[2], e));
+    return pattern.ops_between(start_seconds * seconds_to_beats, duration_seconds * seconds_to_beats, beats_to_samples);
+}
+
+// Read ops from a file. LFO routings in the file are added to `boucle`, and
+// `random` ops are picked from the candidates given by `random =` lines
+// before them.
+fn read_ops(sample_rate: u32, boucle: &mut boucle::Boucle, file_name: &str) -> Result<OpSequence, io::Error> {
+    let beats_to_samples = boucle.beat_fraction_to_samples * 16.0;
     let mut text = String::new();
     let mut op_sequence = OpSequence::new();
     let mut file = File::open(file_name)?;
     file.read_to_string(&mut text)?;
     for line in text.lines() {
-        let (start_seconds, duration_seconds, operation) = boucle::ops::new_from_string(line).expect("Failed to parse line");
+        if let Some(args) = line.strip_prefix("pattern ") {
+            op_sequence.extend(read_pattern_line(sample_rate, beats_to_samples, args));
+            continue;
+        }
+
+        if let Some(args) = line.strip_prefix("lfo ") {
+            let routing = boucle::modulation::new_from_string(args, boucle.seed)
+                .unwrap_or_else(|e| panic!("Failed to parse LFO '{}': {}", args, e));
+            boucle.modulator.add_routing(routing);
+            continue;
+        }
+
+        if let Some(args) = line.strip_prefix("random =") {
+            let random_ops = boucle::ops::random_ops_from_string(args)
+                .unwrap_or_else(|e| panic!("Failed to parse random ops '{}': {}", args, e));
+            boucle.event_recorder.set_random_ops(random_ops);
+            continue;
+        }
+
+        let (start_seconds, duration_seconds, mut operation) = boucle::ops::new_from_string(line).expect("Failed to parse line");
+        if operation == Operation::Random {
+            operation = boucle.event_recorder.pick_random_op();
+        }
         op_sequence.push(op_sequence::Entry {
             start: (start_seconds * sample_rate as f64) as usize,
             duration: Some((duration_seconds * sample_rate as f64) as usize),
-            operation
+            operation,
+            probability: 1.0,
         });
     }
     return Ok(op_sequence);
 }
 
-pub fn run_batch(config: &AppConfig, audio_in_path: &str, audio_out: &str, operations_file: &str) {
-    let op_sequence = read_ops(config.sample_rate, &operations_file).expect("Failed to read ops");
-    for op in &op_sequence {
-        debug!("{}", op);
-    }
+pub fn run_batch(config: &AppConfig, audio_in_path: &str, audio_out: &str, operations_file: &str, pattern_names: &[&str]) {
+    let boucle_config = config.boucle_config();
 
     let buffer_size_samples: usize = (config.loop_time * config.sample_rate as f32)
         .floor() as usize;
 
+    let mut boucle: boucle::Boucle = boucle::Boucle::new(&boucle_config, buffer_size_samples);
+    boucle.event_recorder.set_random_ops(config.random_ops.clone());
+    let mut op_sequence = read_ops(config.sample_rate, &mut boucle, &operations_file).expect("Failed to read ops");
+    for text in pattern_names {
+        let pattern = boucle::patterns::new_from_string(text, config.seed)
+            .unwrap_or_else(|e| panic!("Invalid pattern '{}': {}", text, e));
+        boucle.pattern_player.add_pattern(pattern);
+    }
+    op_sequence.extend_from_slice(boucle.pattern_player.ops_for_period(0, buffer_size_samples));
+
     let mut buffers = create_buffers(buffer_size_samples);
 
     input_wav_to_buffer(audio_in_path, &mut buffers).expect("Failed to read input");
 
+    render(&mut boucle, &buffers, &op_sequence, audio_out, config.sample_rate);
+}
+
+// Render one loop of a session, with its automation and patterns, and any
+// patterns given as options.
+pub fn run_batch_session(session_path: &str, audio_out: &str, pattern_names: &[&str]) {
+    let session = Session::load(Path::new(session_path))
+        .unwrap_or_else(|e| panic!("Failed to load session '{}': {}", session_path, e));
+    let boucle_config = session.snapshot.config();
+
+    let mut boucle: boucle::Boucle = boucle::Boucle::new(&boucle_config, session.snapshot.loop_length);
+    let mut buffers = create_buffers(session.snapshot.loop_length);
+    session.restore(&mut boucle, &mut buffers).unwrap_or_else(|e| panic!("{}", e));
+    for text in pattern_names {
+        let pattern = boucle::patterns::new_from_string(text, boucle.seed)
+            .unwrap_or_else(|e| panic!("Invalid pattern '{}': {}", text, e));
+        boucle.pattern_player.add_pattern(pattern);
+    }
+    let op_sequence = boucle.pattern_player.ops_for_period(0, buffers.play_length()).to_vec();
+
+    render(&mut boucle, &buffers, &op_sequence, audio_out, session.snapshot.sample_rate);
+}
+
+fn render(boucle: &mut boucle::Boucle, buffers: &LoopBuffers, op_sequence: &OpSequence, audio_out: &str, sample_rate: u32) {
+    for op in op_sequence {
+        debug!("{}", op);
+    }
+
     let out_spec = hound::WavSpec {
         channels: 1,
-        sample_rate: 44100,
+        sample_rate,
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int
     };
     let mut writer = hound::WavWriter::create(audio_out, out_spec).unwrap();
 
-    let boucle: boucle::Boucle = boucle::Boucle::new(&boucle::Config::default(), buffer_size_samples);
-    boucle.process_buffer(&buffers.input_a, 0, buffers.input_a.len(), &op_sequence, &mut |s| {
-        let s_i16 = s.to_sample::<i16>();
-        writer.write_sample(s_i16).unwrap();
-    });
+    // Render in blocks, so modulation is applied the same way as in live mode.
+    let mut play_clock = 0;
+    while play_clock < buffers.play_length() {
+        let block_length = std::cmp::min(BLOCK_SIZE, buffers.play_length() - play_clock);
+        let mut block_ops = op_sequence.clone();
+        boucle.modulator.apply(&mut block_ops, play_clock);
+        boucle.process_history(&buffers.output_history(), play_clock, block_length, &block_ops, &mut |s| {
+            let s_i16 = s.to_sample::<i16>();
+            writer.write_sample(s_i16).unwrap();
+        });
+        play_clock += block_length;
+    }
     writer.finalize().unwrap();
 }
-
@@ -1,20 +1,343 @@
+use std::io;
+use std::io::BufRead;
+use std::path::Path;
+use std::thread;
 use std::thread::sleep;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, mpsc};
 use std::time::{Duration, Instant};
 
 use cpal::traits::{DeviceTrait, HostTrait};
+use log::*;
 use portmidi::{PortMidi};
 
 use boucle;
-use boucle::buffers::{LoopBuffers, create_buffers};
+use boucle::accompanist::Accompanist;
+use boucle::buses::BUS_COUNT;
+use boucle::buffers::{DEFAULT_OVERDUB_FEEDBACK, LoopBuffers, LoopSpace, Overdub, ResizeFill};
 use boucle::cpal_helpers;
-use boucle::control_surface::midi::MidiControlSurface;
-use boucle::Boucle;
+use boucle::control_surface::midi;
+use boucle::control_surface::midi::{MidiControlSurface, NoteMap};
+use boucle::control_surface::midi::slicer;
+use boucle::control_surface::midi::slicer::Slicer;
+use boucle::event::Command;
+use boucle::loop_station::{LoopStation, Route};
+use boucle::patterns::Pattern;
+use boucle::punch::{DEFAULT_COUNT_IN_BARS, Punch};
+use boucle::session::{Session, SessionError};
+use boucle::slices::{SliceMode, slice_count};
+use boucle::snapshot::Snapshot;
+use boucle::transients;
+use boucle::Sample;
+use boucle::SamplePosition;
+use boucle::SnapMode;
 
 use crate::app_config::AppConfig;
 use crate::app_error::AppError;
 use crate::wav::input_wav_to_buffer;
 
+// Split a list of patterns, separated by ';'.
+fn pattern_texts(text: &str) -> Vec<String> {
+    return text.split(';').map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect();
+}
+
+// Parse a list of patterns, separated by ';'.
+fn parse_patterns(text: &str, seed: u64) -> Result<Vec<Box<dyn Pattern>>, AppError> {
+    let mut patterns = Vec::new();
+    for pattern_text in pattern_texts(text) {
+        match boucle::patterns::new_from_string(&pattern_text, seed) {
+            Ok(pattern) => patterns.push(pattern),
+            Err(error) => return Err(AppError { message: format!("Invalid pattern '{}': {}", pattern_text, error) }),
+        }
+    }
+    return Ok(patterns);
+}
+
+fn parse_command(text: &str, beats_to_samples: f32) -> Option<Command> {
+    let parts: Vec<&str> = text.split_ascii_whitespace().collect();
+    match parts.as_slice() {
+        ["freeze"] => Some(Command::Freeze),
+        ["unfreeze"] => Some(Command::Unfreeze),
+        ["overdub", args @ ..] if args.len() <= 2 => {
+            let feedback = args.get(0).and_then(|s| s.parse::<f32>().ok()).unwrap_or(DEFAULT_OVERDUB_FEEDBACK);
+            let print = args.get(1).and_then(|s| s.parse::<f32>().ok()).unwrap_or(0.0);
+            Some(Command::Overdub(Overdub { feedback, print }))
+        },
+        ["stop-overdub"] => Some(Command::StopOverdub),
+        ["bounce"] => Some(Command::Bounce),
+        ["punch", args @ ..] if args.len() <= 2 => {
+            let count_in_bars = match args.get(0) {
+                Some(text) => text.parse::<u32>().ok()?,
+                None => DEFAULT_COUNT_IN_BARS,
+            };
+            let bars = match args.get(1) {
+                Some(text) => Some(text.parse::<u32>().ok().filter(|bars| *bars > 0)?),
+                None => None,
+            };
+            Some(Command::Punch { count_in_bars, bars })
+        },
+        ["punch-out"] => Some(Command::PunchOut),
+        ["passthrough"] => Some(Command::Passthrough),
+        ["stop-passthrough"] => Some(Command::StopPassthrough),
+        ["dry-wet", mix] => Some(Command::DryWet(mix.parse::<f32>().ok().filter(|mix| (0.0..=1.0).contains(mix))?)),
+        ["loop-beats", beats, fill @ ..] if fill.len() <= 1 => {
+            let beats = beats.parse::<f32>().ok().filter(|beats| *beats > 0.0)?;
+            let fill = match fill.get(0) {
+                None | Some(&"repeat") => ResizeFill::Repeat,
+                Some(&"silence") => ResizeFill::Silence,
+                Some(&"input") => ResizeFill::Input,
+                Some(_) => return None,
+            };
+            Some(Command::ResizeLoop { loop_length: (beats * beats_to_samples) as usize, fill })
+        },
+        _ => None,
+    }
+}
+
+// What a punch needs to know to count bars.
+struct Meter {
+    beat_length: SamplePosition,
+    beats_per_bar: u32,
+    sample_rate: u32,
+}
+
+fn run_command(command: Command, buffers: &mut LoopBuffers, meter: &Meter) {
+    match command {
+        Command::Freeze => buffers.freeze(),
+        Command::Unfreeze => buffers.unfreeze(),
+        Command::Overdub(overdub) => buffers.start_overdub(overdub),
+        Command::StopOverdub => buffers.stop_overdub(),
+        Command::Bounce => buffers.bounce(),
+        Command::ResizeLoop { loop_length, fill } => buffers.resize_loop(loop_length, fill),
+        Command::Punch { count_in_bars, bars } => buffers.arm_punch(
+            Punch::new(meter.beat_length, meter.beats_per_bar, count_in_bars, bars, meter.sample_rate)),
+        Command::PunchOut => buffers.punch_out(),
+        Command::Passthrough => buffers.start_passthrough(),
+        Command::StopPassthrough => buffers.stop_passthrough(),
+        Command::DryWet(mix) => buffers.dry_wet = mix,
+    }
+}
+
+fn run_station_command(command: Command, station_rc: &Mutex<LoopStation>, route: Route, meter: &Meter) {
+    if let Command::Punch { count_in_bars, bars } = command {
+        let punch = Punch::new(meter.beat_length, meter.beats_per_bar, count_in_bars, bars, meter.sample_rate);
+        // Make room for the recording without holding the station, which
+        // the audio callback needs.
+        let sizes: Vec<(usize, usize, usize)> = {
+            let station = station_rc.lock().unwrap();
+            station.route_tracks(route).map(|index| {
+                let buffers = &station.tracks[index].buffers;
+                (index, buffers.buffer_length(), buffers.loop_count())
+            }).collect()
+        };
+        let spaces: Vec<(usize, LoopSpace)> = sizes.into_iter().map(|(index, buffer_length, loop_count)| {
+            match punch.buffer_length() > buffer_length {
+                true => (index, LoopSpace::new(punch.buffer_length(), loop_count)),
+                false => (index, LoopSpace::default()),
+            }
+        }).collect();
+        let _old_loops: Vec<LoopSpace> = {
+            let mut station = station_rc.lock().unwrap();
+            spaces.into_iter().map(|(index, space)| station.tracks[index].buffers.arm_punch_into(punch, space)).collect()
+        };
+        return;
+    }
+
+    let mut station = station_rc.lock().unwrap();
+    if let Command::DryWet(mix) = command {
+        // The dry input is played once for the station, so the mix is too.
+        station.set_dry_wet(mix);
+        return;
+    }
+    for index in station.route_tracks(route) {
+        run_command(command, &mut station.tracks[index].buffers, meter);
+    }
+}
+
+// Controls for tracks of the loop station.
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum TrackCommand {
+    Select,
+    Mute(bool),
+    Arm(bool),
+    Volume(f32),
+}
+
+fn parse_track_command(text: &str) -> Option<(Route, TrackCommand)> {
+    let parts: Vec<&str> = text.split_ascii_whitespace().collect();
+    match parts.as_slice() {
+        ["track", index, args @ ..] => {
+            let route = match *index {
+                "all" => Route::All,
+                _ => Route::Track(index.parse::<usize>().ok()?),
+            };
+            let command = match args {
+                [] | ["select"] => TrackCommand::Select,
+                ["mute"] => TrackCommand::Mute(true),
+                ["unmute"] => TrackCommand::Mute(false),
+                ["arm"] => TrackCommand::Arm(true),
+                ["disarm"] => TrackCommand::Arm(false),
+                ["volume", volume] => TrackCommand::Volume(volume.parse::<f32>().ok().filter(|v| *v >= 0.0)?),
+                _ => return None,
+            };
+            Some((route, command))
+        },
+        _ => None,
+    }
+}
+
+fn run_track_command(command: TrackCommand, station: &mut LoopStation, route: Route, selected: &mut Route) {
+    info!("Track {:?}: {:?}", route, command);
+    if command == TrackCommand::Select {
+        *selected = route;
+        return;
+    }
+    for index in station.route_tracks(route) {
+        let track = &mut station.tracks[index];
+        match command {
+            TrackCommand::Select => {},
+            TrackCommand::Mute(mute) => track.mute = mute,
+            TrackCommand::Arm(armed) => track.arm(armed),
+            TrackCommand::Volume(volume) => track.volume = volume,
+        }
+    }
+}
+
+// Save or load the loops of a track, or the whole session.
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum SnapshotCommand<'a> {
+    Save(&'a str),
+    Load(&'a str),
+    SaveSession(&'a str),
+    LoadSession(&'a str),
+}
+
+fn parse_snapshot_command(text: &str) -> Option<SnapshotCommand<'_>> {
+    let text = text.trim();
+    let (command, path) = text.split_once(char::is_whitespace)?;
+    let path = path.trim();
+    match command {
+        "save" => Some(SnapshotCommand::Save(path)),
+        "load" => Some(SnapshotCommand::Load(path)),
+        "save-session" => Some(SnapshotCommand::SaveSession(path)),
+        "load-session" => Some(SnapshotCommand::LoadSession(path)),
+        _ => None,
+    }
+}
+
+// What a session holds besides the track: the patterns entered for each
+// track, and the MIDI mapping.
+struct SessionState {
+    patterns: Vec<Vec<String>>,
+    mapping: NoteMap,
+}
+
+// Snapshots and sessions are of one track, the first one given by `route`.
+// Files are read and written, and loops laid out, without holding the lock,
+// so the audio doesn't wait.
+fn run_snapshot_command(command: SnapshotCommand, station_rc: &Mutex<LoopStation>, route: Route,
+                        state: &mut SessionState) -> Result<(), SessionError> {
+    let index = match station_rc.lock().unwrap().route_tracks(route).next() {
+        Some(index) => index,
+        None => return Err(SessionError { message: format!("no track {:?}", route) }),
+    };
+    match command {
+        SnapshotCommand::Save(path) => {
+            info!("Track {}: saving snapshot {}", index, path);
+            let snapshot = {
+                let station = station_rc.lock().unwrap();
+                let track = &station.tracks[index];
+                Snapshot::take(&track.boucle, &track.buffers)
+            };
+            snapshot.save(Path::new(path))?;
+        },
+        SnapshotCommand::Load(path) => {
+            info!("Track {}: loading snapshot {}", index, path);
+            let snapshot = Snapshot::load(Path::new(path))?;
+            let (buffer_length, loop_count) = {
+                let station = station_rc.lock().unwrap();
+                let buffers = &station.tracks[index].buffers;
+                (buffers.buffer_length(), buffers.loop_count())
+            };
+            let space = snapshot.loop_space(buffer_length, loop_count);
+            let _old_loops = {
+                let mut station = station_rc.lock().unwrap();
+                let track = &mut station.tracks[index];
+                let old_loops = snapshot.restore_from(space, &mut track.boucle, &mut track.buffers);
+                station.set_dry_wet(snapshot.dry_wet);
+                old_loops
+            };
+        },
+        SnapshotCommand::SaveSession(path) => {
+            info!("Track {}: saving session {}", index, path);
+            let session = {
+                let station = station_rc.lock().unwrap();
+                let track = &station.tracks[index];
+                Session::take(&track.boucle, &track.buffers, &state.patterns[index], Some(state.mapping.clone()))
+            };
+            session.save(Path::new(path))?;
+        },
+        SnapshotCommand::LoadSession(path) => {
+            info!("Track {}: loading session {}", index, path);
+            let session = Session::load(Path::new(path))?;
+            let (seed, buffer_length, loop_count, active_count) = {
+                let station = station_rc.lock().unwrap();
+                let track = &station.tracks[index];
+                (track.boucle.seed, track.buffers.buffer_length(), track.buffers.loop_count(),
+                 track.boucle.pattern_player.active_count())
+            };
+            let space = session.space(seed, buffer_length, loop_count, active_count)?;
+            let _old_space = {
+                let mut station = station_rc.lock().unwrap();
+                let track = &mut station.tracks[index];
+                let old_space = session.restore_from(space, &mut track.boucle, &mut track.buffers);
+                station.set_dry_wet(session.snapshot.dry_wet);
+                old_space
+            };
+            state.patterns[index] = session.patterns;
+            if let Some(mapping) = session.mapping {
+                state.mapping = mapping;
+            }
+        },
+    }
+    return Ok(());
+}
+
+// Show each track, and when they next all start their loops together.
+fn print_status(station: &LoopStation, selected: Route, beat_length: f32) {
+    for (index, track) in station.tracks.iter().enumerate() {
+        let marker = match selected {
+            Route::All => "*",
+            Route::Track(selected_index) if selected_index == index => "*",
+            _ => " ",
+        };
+        println!("{} track {}: {} beats, {}{}volume {}", marker, index,
+                 track.boucle.loop_length() as f32 / beat_length,
+                 if track.is_armed() { "armed, " } else { "" },
+                 if track.mute { "muted, " } else { "" },
+                 track.volume);
+    }
+    match station.next_realignment() {
+        Some(clock) => println!("Tracks realign in {} beats", (clock - station.clock()) as f32 / beat_length),
+        None => println!("Tracks never realign"),
+    }
+}
+
+// Read lines from stdin in a separate thread, as there is no non-blocking read.
+fn spawn_stdin_reader() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(text) => if sender.send(text).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    return receiver;
+}
+
 fn open_midi_in<'a>(midi_context: &'a portmidi::PortMidi, midi_in_port: i32) -> Result<Box<portmidi::InputPort<'a>>, portmidi::Error> {
     let midi_info = midi_context.device(midi_in_port)?;
     match midi_context.input_port(midi_info, 1024) {
@@ -23,8 +346,68 @@ fn open_midi_in<'a>(midi_context: &'a portmidi::PortMidi, midi_in_port: i32) ->
     };
 }
 
-pub fn run_live(app_config: &AppConfig, midi_in_port: i32, audio_in_path: Option<&str>, input_device_name: Option<&str>,
-                output_device_name: Option<&str>, loop_time_seconds: f32, bpm: f32) -> Result<(), AppError> {
+// Find the snap points and transients of loops that changed, for the tracks
+// that use them. The lock is only held to copy the loop and to hand the
+// markers back, so the audio thread doesn't wait for the detection.
+fn update_markers(station_rc: &Mutex<LoopStation>, loop_copy: &mut Vec<Sample>, sample_rate: u32) {
+    let track_count = station_rc.lock().unwrap().tracks.len();
+    for index in 0..track_count {
+        let request = {
+            let mut station = station_rc.lock().unwrap();
+            let track = &mut station.tracks[index];
+            let request = match track.boucle.take_marker_request() {
+                Some(request) => request,
+                None => continue,
+            };
+            let loop_buffer = track.buffers.output_history()[0];
+            loop_copy.clear();
+            loop_copy.extend_from_slice(&loop_buffer[..track.boucle.loop_length().min(loop_buffer.len())]);
+            request
+        };
+        let markers = transients::find_markers(loop_copy, request, sample_rate);
+        let _old_markers = station_rc.lock().unwrap().tracks[index].boucle.replace_markers(markers);
+    }
+}
+
+/// Options for live mode, on top of the `AppConfig` that batch mode uses too.
+pub struct LiveOptions<'a> {
+    pub midi_in_port: i32,
+    // Play from a file instead of an input device.
+    pub audio_in_path: Option<&'a str>,
+    pub input_device_name: Option<&'a str>,
+    pub output_device_name: Option<&'a str>,
+    pub pattern_names: Vec<&'a str>,
+    // Note velocity sets how likely an op is to fire.
+    pub velocity_probability: bool,
+    pub lfo_routings: Vec<&'a str>,
+    pub audio_triggers: Vec<&'a str>,
+    // Intensity of the accompanist, if it plays.
+    pub auto_intensity: Option<f32>,
+    pub history_depth: usize,
+    pub beats_per_bar: u32,
+    // Punch-in recording to start with.
+    pub punch: Option<Command>,
+    // Length of each track, as a multiple of the master loop length.
+    pub track_lengths: Vec<f32>,
+    pub passthrough: bool,
+    pub dry_wet: f32,
+    // Play each bus on its own output channel.
+    pub buses: bool,
+    pub slice: Option<SliceMode>,
+    pub snap: Option<SnapMode>,
+    pub session: Option<Session>,
+}
+
+pub fn run_live(app_config: &AppConfig, options: LiveOptions) -> Result<(), AppError> {
+    let LiveOptions {
+        midi_in_port, audio_in_path, input_device_name, output_device_name, pattern_names,
+        velocity_probability, lfo_routings, audio_triggers, auto_intensity, history_depth,
+        beats_per_bar, punch, track_lengths, passthrough, dry_wet, buses, slice, snap, session,
+    } = options;
+    if track_lengths.is_empty() {
+        return Err(AppError { message: "Live mode needs at least one track".to_string() });
+    }
+
     let midi_context = match PortMidi::new() {
         Ok(value) => value,
         Err(error) => return Err(AppError { message: format!("Cannot open PortMIDI: {}", error) }),
@@ -36,17 +419,65 @@ pub fn run_live(app_config: &AppConfig, midi_in_port: i32, audio_in_path: Option
 
     let audio_host = cpal::default_host();
 
-    let config = boucle::Config {
+    let config = app_config.boucle_config();
+
+    let master_length: usize = (app_config.loop_time * app_config.sample_rate as f32).floor() as usize;
+    let mut station = LoopStation::new(master_length);
+    for &length in &track_lengths {
+        let index = station.add_track(&config, length, history_depth);
+        let track = &mut station.tracks[index];
+        track.boucle.set_snap(snap, track.buffers.output_history()[0]);
+        track.boucle.set_transient_slices(slice == Some(SliceMode::Transients), track.buffers.output_history()[0]);
+        track.boucle.event_recorder.set_random_ops(app_config.random_ops.clone());
+    }
+
+    // Options set up the first track; others are controlled from stdin.
+    let track = &mut station.tracks[0];
+    for text in &audio_triggers {
+        match boucle::audio_trigger::new_from_string(text, app_config.sample_rate) {
+            Ok(trigger) => track.buffers.audio_triggers.add_trigger(trigger),
+            Err(error) => return Err(AppError { message: format!("Invalid trigger '{}': {}", text, error) }),
+        }
+    }
+    if let Some(intensity) = auto_intensity {
+        info!("Auto mode, intensity {}", intensity);
+        track.buffers.accompanist = Some(Accompanist::new(intensity, config.beat_fraction_to_samples * 16.0,
+                                                          app_config.sample_rate, app_config.seed));
+    }
+    let meter = Meter {
+        beat_length: (config.beat_fraction_to_samples * 16.0) as SamplePosition,
+        beats_per_bar,
         sample_rate: app_config.sample_rate,
-        beat_fraction_to_samples: (60.0 / bpm / 16.0) * (app_config.sample_rate as f32)
     };
+    if let Some(command) = punch {
+        if audio_in_path.is_some() {
+            return Err(AppError { message: "Punch-in recording needs an input device, not a file".to_string() });
+        }
+        run_command(command, &mut track.buffers, &meter);
+    }
 
-    let buffer_size_samples: usize = (loop_time_seconds * app_config.sample_rate as f32).floor() as usize;
-    let buffers = create_buffers(buffer_size_samples);
-    let buf_rc: Arc<Mutex<LoopBuffers>> = Arc::new(Mutex::new(buffers));
-
-    let boucle: boucle::Boucle = boucle::Boucle::new(&config, buffer_size_samples);
-    let boucle_rc: Arc<Mutex<Boucle>> = Arc::new(Mutex::new(boucle));
+    for pattern in parse_patterns(&pattern_names.join(";"), app_config.seed)? {
+        track.boucle.pattern_player.add_pattern(pattern);
+    }
+    for text in &lfo_routings {
+        match boucle::modulation::new_from_string(text, app_config.seed) {
+            Ok(routing) => track.boucle.modulator.add_routing(routing),
+            Err(error) => return Err(AppError { message: format!("Invalid LFO '{}': {}", text, error) }),
+        }
+    }
+    station.set_dry_wet(dry_wet);
+    if passthrough {
+        if audio_in_path.is_some() {
+            return Err(AppError { message: "Passthrough needs an input device, not a file".to_string() });
+        }
+        for track in station.tracks.iter_mut() {
+            track.buffers.start_passthrough();
+        }
+    }
+    if session.is_some() && audio_in_path.is_some() {
+        return Err(AppError { message: "Load a session or an input file, not both".to_string() });
+    }
+    let station_rc: Arc<Mutex<LoopStation>> = Arc::new(Mutex::new(station));
 
     let audio_in_device;
     let _audio_in_stream;
@@ -58,13 +489,13 @@ pub fn run_live(app_config: &AppConfig, midi_in_port: i32, audio_in_path: Option
             .expect("no output device available"),
     };
 
-    let supported_audio_config = cpal_helpers::get_audio_config(&boucle_rc.lock().unwrap(), &audio_out_device);
+    let channels = if buses { BUS_COUNT as u16 } else { 1 };
+    let supported_audio_config = cpal_helpers::get_audio_config(&station_rc.lock().unwrap().tracks[0].boucle, &audio_out_device, channels);
     let sample_format = supported_audio_config.sample_format();
-    let input_audio_config: cpal::StreamConfig = supported_audio_config.clone().into();
     let output_audio_config: cpal::StreamConfig = supported_audio_config.into();
 
     if let Some(filename) = audio_in_path {
-        input_wav_to_buffer(filename, &mut buf_rc.lock().unwrap())
+        input_wav_to_buffer(filename, &mut station_rc.lock().unwrap().tracks[0].buffers)
             .expect("Failed to read input");
     } else {
         audio_in_device = match input_device_name {
@@ -75,41 +506,153 @@ pub fn run_live(app_config: &AppConfig, midi_in_port: i32, audio_in_path: Option
                 .expect("no input device available"),
         };
 
-        let mut buffers = buf_rc.lock().unwrap();
-        // We start playing wet A while recording B, so set A to silence.
-        for i in 0..buffers.input_a.len() {
-            buffers.input_a[i] = 0.0;
+        // We start playing one loop while recording the next, so set the
+        // loops being played to silence.
+        for track in station_rc.lock().unwrap().tracks.iter_mut() {
+            let current_output = track.buffers.current_output;
+            for i in 0..track.buffers.buffer_length() {
+                track.buffers.history[current_output][i] = 0.0;
+            }
         }
 
-        _audio_in_stream = match sample_format {
-            cpal::SampleFormat::F32 => cpal_helpers::open_in_stream::<f32>(audio_in_device, input_audio_config, buf_rc.clone()),
-            cpal::SampleFormat::I16 => cpal_helpers::open_in_stream::<i16>(audio_in_device, input_audio_config, buf_rc.clone()),
-            cpal::SampleFormat::U16 => cpal_helpers::open_in_stream::<u16>(audio_in_device, input_audio_config, buf_rc.clone()),
+        let supported_input_config = cpal_helpers::get_input_audio_config(&station_rc.lock().unwrap().tracks[0].boucle, &audio_in_device);
+        let input_sample_format = supported_input_config.sample_format();
+        let input_audio_config: cpal::StreamConfig = supported_input_config.into();
+
+        _audio_in_stream = match input_sample_format {
+            cpal::SampleFormat::F32 => cpal_helpers::open_in_stream::<f32>(audio_in_device, input_audio_config, station_rc.clone()),
+            cpal::SampleFormat::I16 => cpal_helpers::open_in_stream::<i16>(audio_in_device, input_audio_config, station_rc.clone()),
+            cpal::SampleFormat::U16 => cpal_helpers::open_in_stream::<u16>(audio_in_device, input_audio_config, station_rc.clone()),
         };
     };
 
+    let mut session_state = SessionState {
+        patterns: vec!(Vec::new(); station_rc.lock().unwrap().tracks.len()),
+        mapping: NoteMap::from_surface(&boucle::control_surface::midi::op1::Op1 {}),
+    };
+    session_state.patterns[0] = pattern_texts(&pattern_names.join(";"));
+
+    // Play the session instead of the silence we would start with. Its
+    // patterns play alongside the ones given as options.
+    if let Some(session) = session {
+        let mut station = station_rc.lock().unwrap();
+        let track = &mut station.tracks[0];
+        session.restore(&mut track.boucle, &mut track.buffers)?;
+        for pattern in parse_patterns(&pattern_names.join(";"), app_config.seed)? {
+            track.boucle.pattern_player.add_pattern(pattern);
+        }
+        station.set_dry_wet(session.snapshot.dry_wet);
+        session_state.patterns[0].splice(0..0, session.patterns);
+        if let Some(mapping) = session.mapping {
+            session_state.mapping = mapping;
+        }
+    }
+
+    if let Some(mode) = slice {
+        let slices = match mode {
+            SliceMode::Beats(size) => slice_count(size, config.beat_fraction_to_samples, master_length).1,
+            SliceMode::Transients => slicer::DEFAULT_TRANSIENT_SLICES,
+        };
+        let slicer = Slicer {
+            mode,
+            first_note: slicer::DEFAULT_FIRST_NOTE,
+            slices: slices.min(128 - slicer::DEFAULT_FIRST_NOTE as usize),
+        };
+        info!("Slicing the loop into {} slices", slicer.slices);
+        session_state.mapping = session_state.mapping.overlay(&NoteMap::from_surface(&slicer));
+    }
 
     let _audio_out_stream = match sample_format {
-        cpal::SampleFormat::F32 => cpal_helpers::open_out_stream::<f32>(audio_out_device, output_audio_config, boucle_rc.clone(), buf_rc.clone()),
-        cpal::SampleFormat::I16 => cpal_helpers::open_out_stream::<i16>(audio_out_device, output_audio_config, boucle_rc.clone(), buf_rc.clone()),
-        cpal::SampleFormat::U16 => cpal_helpers::open_out_stream::<u16>(audio_out_device, output_audio_config, boucle_rc.clone(), buf_rc.clone()),
+        cpal::SampleFormat::F32 => cpal_helpers::open_out_stream::<f32>(audio_out_device, output_audio_config, station_rc.clone(), buses),
+        cpal::SampleFormat::I16 => cpal_helpers::open_out_stream::<i16>(audio_out_device, output_audio_config, station_rc.clone(), buses),
+        cpal::SampleFormat::U16 => cpal_helpers::open_out_stream::<u16>(audio_out_device, output_audio_config, station_rc.clone(), buses),
     };
 
-    let interface = boucle::control_surface::midi::op1::Op1 {};
+    // MIDI, commands and patterns go to the selected track, or all tracks.
+    let mut selected = Route::Track(0);
+
+    // Each line typed on stdin replaces the active patterns. Separate
+    // patterns with ';', or enter an empty line to stop all patterns.
+    // The lines 'freeze' and 'unfreeze' hold and release the loop, and
+    // 'overdub [FEEDBACK [PRINT]]' and 'stop-overdub' control sound-on-sound.
+    // 'bounce' captures the next loop of output and holds it.
+    // 'loop-beats BEATS [repeat|silence|input]' changes the loop length.
+    // 'punch [COUNT_IN_BARS [BARS]]' records a new loop from the next bar,
+    // and 'punch-out' stops it at the end of the bar.
+    // 'passthrough' plays the live input, with ops playing from its recent
+    // past, and 'stop-passthrough' goes back to playing the loop.
+    // 'dry-wet MIX' mixes the input with the output, from 0 to 1.
+    // 'track N|all [select|mute|unmute|arm|disarm|volume VOLUME]' controls
+    // tracks of the loop station, and 'status' shows them.
+    // 'save PATH' and 'load PATH' save the loops of the selected track to
+    // PATH.wav and PATH.txt, and load them back. 'save-session PATH' and
+    // 'load-session PATH' do the same with the ops played over the loops,
+    // the patterns and the MIDI mapping too.
+    let stdin_lines = spawn_stdin_reader();
+
+    // Loops are copied here to find their markers in.
+    let mut loop_copy: Vec<Sample> = Vec::new();
 
     while let Ok(_) = midi_in.poll() {
         if let Ok(Some(event)) = midi_in.read_n(1024) {
             let event2: &portmidi::MidiEvent = event.get(0).unwrap();
 
-            let mut boucle = boucle_rc.lock().unwrap();
-            let (state_change, operation) = interface.map_midi_message(
+            if let Some(command) = session_state.mapping.map_midi_command(event2.message.status, event2.message.data1, event2.message.data2) {
+                run_station_command(command, &station_rc, selected, &meter);
+                continue;
+            }
+
+            let (state_change, operation) = session_state.mapping.map_midi_message(
                 event2.message.status,
                 event2.message.data1,
             );
 
-            boucle.event_recorder.record_event(Instant::now(), state_change, operation);
+            let probability = match velocity_probability {
+                true => midi::velocity_to_probability(event2.message.data2),
+                false => 1.0,
+            };
+            station_rc.lock().unwrap().record_event(selected, Instant::now(), state_change, operation, probability);
         }
 
+        while let Ok(text) = stdin_lines.try_recv() {
+            if text.trim() == "status" {
+                print_status(&station_rc.lock().unwrap(), selected, config.beat_fraction_to_samples * 16.0);
+                continue;
+            }
+            if let Some((route, command)) = parse_track_command(&text) {
+                let mut station = station_rc.lock().unwrap();
+                if station.route_tracks(route).is_empty() {
+                    warn!("No track {:?}", route);
+                    continue;
+                }
+                run_track_command(command, &mut station, route, &mut selected);
+                continue;
+            }
+            if let Some(command) = parse_snapshot_command(&text) {
+                if let Err(error) = run_snapshot_command(command, &station_rc, selected, &mut session_state) {
+                    warn!("{}", error);
+                }
+                continue;
+            }
+            if let Some(command) = parse_command(&text, config.beat_fraction_to_samples * 16.0) {
+                run_station_command(command, &station_rc, selected, &meter);
+                continue;
+            }
+            let mut station = station_rc.lock().unwrap();
+            for index in station.route_tracks(selected) {
+                match parse_patterns(&text, app_config.seed) {
+                    Ok(patterns) => {
+                        info!("Track {}: switching to patterns: {}", index, text);
+                        station.tracks[index].boucle.pattern_player.replace_patterns(patterns);
+                        session_state.patterns[index] = pattern_texts(&text);
+                    },
+                    Err(error) => { warn!("{}", error); break },
+                }
+            }
+        }
+
+        update_markers(&station_rc, &mut loop_copy, app_config.sample_rate);
+
         // there is no blocking receive method in PortMidi
         sleep(Duration::from_millis(10));
     }
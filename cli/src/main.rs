@@ -6,10 +6,16 @@ mod cmd_live;
 mod tests;
 mod wav;
 
+use std::path::Path;
+
 use clap::{Arg, App};
 use log::*;
 
+use boucle::event::Command;
+use boucle::session::Session;
+
 use crate::app_config::AppConfig;
+use crate::app_config::DEFAULT_BPM;
 
 fn parse_f32_option(string: Option<&str>) -> Option<f32> {
     return match string {
@@ -18,6 +24,31 @@ fn parse_f32_option(string: Option<&str>) -> Option<f32> {
     };
 }
 
+// Validators for clap, so a bad value is reported like a missing one.
+fn is_number<T: std::str::FromStr>(text: String) -> Result<(), String> {
+    return match text.parse::<T>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("'{}' is not a valid number", text)),
+    };
+}
+
+fn is_fraction(text: String) -> Result<(), String> {
+    return match text.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(()),
+        _ => Err(format!("'{}' is not a number from 0 to 1", text)),
+    };
+}
+
+fn is_track_lengths(text: String) -> Result<(), String> {
+    for length in text.split(',').map(|length| length.trim()).filter(|length| !length.is_empty()) {
+        match length.parse::<f32>() {
+            Ok(value) if value > 0.0 => {},
+            _ => return Err(format!("'{}' is not a track length", length)),
+        }
+    }
+    return Ok(());
+}
+
 fn calculate_loop_time(seconds: Option<f32>, beats: Option<f32>, bpm: Option<f32>) -> Result<f32, String> {
     if let Some(value) = seconds {
         return Ok(value);
@@ -63,7 +94,8 @@ fn main() {
                  .short("p")
                  .help("MIDI port to read from")
                  .takes_value(true)
-                 .value_name("PORT"))
+                 .value_name("PORT")
+                 .validator(is_number::<i32>))
             .arg(Arg::with_name("bpm")
                  .long("bpm")
                  .help("Beats per minute")
@@ -80,7 +112,113 @@ fn main() {
                  .short("b")
                  .help("Loop length, in beats (requires `--bpm`)")
                  .takes_value(true)
-                 .value_name("BEATS")))
+                 .value_name("BEATS"))
+            .arg(Arg::with_name("pattern")
+                 .long("pattern")
+                 .help("Generate operations from a predefined pattern name or mini-notation (can be given more than once)")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .value_name("PATTERN"))
+            .arg(Arg::with_name("seed")
+                 .long("seed")
+                 .help("Seed for random choices made by patterns and ops")
+                 .takes_value(true)
+                 .value_name("SEED")
+                 .validator(is_number::<u64>))
+            .arg(Arg::with_name("random-ops")
+                 .long("random-ops")
+                 .help("Ops that 'random' picks from, with their relative weights, e.g. '2 reverse, 1 jump -1'")
+                 .takes_value(true)
+                 .value_name("OPS"))
+            .arg(Arg::with_name("velocity-probability")
+                 .long("velocity-probability")
+                 .help("Use note velocity as the probability that an operation fires"))
+            .arg(Arg::with_name("lfo")
+                 .long("lfo")
+                 .help("Modulate an operation parameter, e.g. 'repeat-size sine 4 0.125 1' (can be given more than once)")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .value_name("ROUTING"))
+            .arg(Arg::with_name("trigger")
+                 .long("trigger")
+                 .help("Start an operation when the input gets loud, e.g. 'onset 2 0.5 repeat 0.125' (can be given more than once)")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .value_name("TRIGGER"))
+            .arg(Arg::with_name("auto")
+                 .long("auto")
+                 .help("Generate operations automatically, based on how busy the input is"))
+            .arg(Arg::with_name("intensity")
+                 .long("intensity")
+                 .help("How much happens in auto mode, from 0 to 1 (default 0.5)")
+                 .takes_value(true)
+                 .value_name("INTENSITY")
+                 .validator(is_fraction))
+            .arg(Arg::with_name("history")
+                 .long("history")
+                 .help("Number of loops to keep for 'past loop' operations, including the one being recorded (default 2, max 16)")
+                 .takes_value(true)
+                 .value_name("LOOPS")
+                 .validator(is_number::<usize>))
+            .arg(Arg::with_name("punch")
+                 .long("punch")
+                 .help("Pass the input through, and record the first loop from the next bar after a count-in"))
+            .arg(Arg::with_name("count-in")
+                 .long("count-in")
+                 .help("Bars of metronome before punching in (default 1)")
+                 .takes_value(true)
+                 .value_name("BARS")
+                 .validator(is_number::<u32>))
+            .arg(Arg::with_name("bars")
+                 .long("bars")
+                 .help("Punch out after this many bars, instead of on the 'punch-out' command")
+                 .takes_value(true)
+                 .value_name("BARS")
+                 .validator(is_number::<u32>))
+            .arg(Arg::with_name("beats-per-bar")
+                 .long("beats-per-bar")
+                 .help("Beats in each bar, for punch-in recording (default 4)")
+                 .takes_value(true)
+                 .value_name("BEATS")
+                 .validator(is_number::<u32>))
+            .arg(Arg::with_name("tracks")
+                 .long("tracks")
+                 .help("Run several tracks, with loop lengths relative to the loop time, e.g. '1,0.75' for 4 against 3 (default 1)")
+                 .takes_value(true)
+                 .value_name("LENGTHS")
+                 .validator(is_track_lengths))
+            .arg(Arg::with_name("passthrough")
+                 .long("passthrough")
+                 .help("Play the live input as an insert effect, with ops playing from its recent past, instead of the last loop"))
+            .arg(Arg::with_name("dry-wet")
+                 .long("dry-wet")
+                 .help("Mix of the input and the output, from 0 for dry to 1 for wet (default 1)")
+                 .takes_value(true)
+                 .value_name("MIX")
+                 .validator(is_fraction))
+            .arg(Arg::with_name("buses")
+                 .long("buses")
+                 .help("Play the main, dry, wet and ops-only buses on output channels 1 to 4"))
+            .arg(Arg::with_name("slice")
+                 .long("slice")
+                 .help("Play slices of the loop this many beats long, or cut at its 'transients', from the keys going up from C3 (MIDI note 36)")
+                 .takes_value(true)
+                 .value_name("BEATS|transients"))
+            .arg(Arg::with_name("snap")
+                 .long("snap")
+                 .help("Snap jumps and repeats to the 'transients' or 'zero-crossings' of the loop, for loops played without a click")
+                 .takes_value(true)
+                 .possible_values(&["transients", "zero-crossings"])
+                 .value_name("MODE"))
+            .arg(Arg::with_name("session")
+                 .long("session")
+                 .alias("load")
+                 .help("Start from the session saved at FILE with 'save-session', or the loops saved with 'save'. Sets the loop time and BPM unless they are given")
+                 .takes_value(true)
+                 .value_name("FILE")))
         .subcommand(App::new("batch")
             .arg(Arg::with_name("INPUT")
                  .required(true)
@@ -104,13 +242,38 @@ fn main() {
                  .short("b")
                  .help("Loop length, in beats (requires `--bpm`)")
                  .takes_value(true)
-                 .value_name("BEATS")))
+                 .value_name("BEATS"))
+            .arg(Arg::with_name("pattern")
+                 .long("pattern")
+                 .help("Generate operations from a predefined pattern name or mini-notation (can be given more than once)")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)
+                 .value_name("PATTERN"))
+            .arg(Arg::with_name("seed")
+                 .long("seed")
+                 .help("Seed for random choices made by patterns and ops")
+                 .takes_value(true)
+                 .value_name("SEED")
+                 .validator(is_number::<u64>))
+            .arg(Arg::with_name("random-ops")
+                 .long("random-ops")
+                 .help("Ops that 'random' picks from, with their relative weights, e.g. '2 reverse, 1 jump -1'")
+                 .takes_value(true)
+                 .value_name("OPS"))
+            .arg(Arg::with_name("session")
+                 .long("session")
+                 .help("INPUT is a session saved with 'save-session': render one loop of it, with its automation and patterns")))
         .subcommand(App::new("list-ports"))
         .get_matches();
 
 
     const SAMPLE_RATE: u32 = 44100;
     match app_m.subcommand() {
+        ("batch", Some(sub_m)) if sub_m.is_present("session") => {
+            let patterns: Vec<&str> = sub_m.values_of("pattern").map(|v| v.collect()).unwrap_or_default();
+            cmd_batch::run_batch_session(sub_m.value_of("INPUT").unwrap(), sub_m.value_of("OUTPUT").unwrap(), &patterns);
+        },
         ("batch", Some(sub_m)) => {
             let loop_time_seconds: Option<f32> = parse_f32_option(sub_m.value_of("loop-time-seconds"));
             let loop_time_beats: Option<f32> = parse_f32_option(sub_m.value_of("loop-time-beats"));
@@ -120,28 +283,76 @@ fn main() {
                 Err(string) => panic!("{}", string),
             };
 
-            let app_config = AppConfig::new(SAMPLE_RATE, loop_time);
+            let mut app_config = AppConfig::new(SAMPLE_RATE, loop_time);
+            app_config.seed = sub_m.value_of("seed").unwrap_or("0").parse::<u64>().unwrap();
+            app_config.bpm = bpm.unwrap_or(DEFAULT_BPM);
+            if let Some(text) = sub_m.value_of("random-ops") {
+                app_config.random_ops = boucle::ops::random_ops_from_string(text).unwrap();
+            }
             let audio_in = sub_m.value_of("INPUT").unwrap();
             let audio_out = sub_m.value_of("OUTPUT").unwrap();
             let operations_file = "ops.test";
-            cmd_batch::run_batch(&app_config, audio_in, audio_out, operations_file);
+            let patterns: Vec<&str> = sub_m.values_of("pattern").map(|v| v.collect()).unwrap_or_default();
+            cmd_batch::run_batch(&app_config, audio_in, audio_out, operations_file, &patterns);
         },
         ("live", Some(sub_m)) => {
             let loop_time_seconds: Option<f32> = parse_f32_option(sub_m.value_of("loop-time-seconds"));
             let loop_time_beats: Option<f32> = parse_f32_option(sub_m.value_of("loop-time-beats"));
-            let bpm: Option<f32> = parse_f32_option(sub_m.value_of("bpm"));
-            let loop_time = match calculate_loop_time(loop_time_seconds, loop_time_beats, bpm) {
-                Ok(value) => value,
-                Err(string) => panic!("{}", string),
+            let session = sub_m.value_of("session").map(|path| match Session::load(Path::new(path)) {
+                Ok(session) => session,
+                Err(error) => panic!("{}", error),
+            });
+            let snapshot = session.as_ref().map(|session| &session.snapshot);
+            let bpm: Option<f32> = parse_f32_option(sub_m.value_of("bpm"))
+                .or(snapshot.map(|snapshot| snapshot.bpm));
+            let loop_time = match (loop_time_seconds, loop_time_beats, snapshot) {
+                (None, None, Some(snapshot)) => snapshot.loop_length as f32 / snapshot.sample_rate as f32,
+                _ => match calculate_loop_time(loop_time_seconds, loop_time_beats, bpm) {
+                    Ok(value) => value,
+                    Err(string) => panic!("{}", string),
+                },
             };
 
-            let app_config = AppConfig::new(SAMPLE_RATE, loop_time);
-            let midi_port: i32 = sub_m.value_of("midi-port").unwrap_or("0").
-                                    parse::<i32>().unwrap();
-            let input_file = sub_m.value_of("input-file");
-            let input_device_name = sub_m.value_of("input-device");
-            let output_device_name = sub_m.value_of("output-device");
-            cmd_live::run_live(&app_config, midi_port, input_file, input_device_name, output_device_name, loop_time, bpm.unwrap_or(60.0)).unwrap();
+            let mut app_config = AppConfig::new(SAMPLE_RATE, loop_time);
+            app_config.seed = sub_m.value_of("seed").unwrap_or("0").parse::<u64>().unwrap();
+            app_config.bpm = bpm.unwrap_or(DEFAULT_BPM);
+            if let Some(text) = sub_m.value_of("random-ops") {
+                app_config.random_ops = boucle::ops::random_ops_from_string(text).unwrap();
+            }
+            let punch = match sub_m.is_present("punch") {
+                true => Some(Command::Punch {
+                    count_in_bars: sub_m.value_of("count-in").unwrap_or("1").parse::<u32>().unwrap(),
+                    bars: sub_m.value_of("bars").map(|bars| bars.parse::<u32>().unwrap()),
+                }),
+                false => None,
+            };
+            let options = cmd_live::LiveOptions {
+                midi_in_port: sub_m.value_of("midi-port").unwrap_or("0").parse::<i32>().unwrap(),
+                audio_in_path: sub_m.value_of("input-file"),
+                input_device_name: sub_m.value_of("input-device"),
+                output_device_name: sub_m.value_of("output-device"),
+                pattern_names: sub_m.values_of("pattern").map(|v| v.collect()).unwrap_or_default(),
+                velocity_probability: sub_m.is_present("velocity-probability"),
+                lfo_routings: sub_m.values_of("lfo").map(|v| v.collect()).unwrap_or_default(),
+                audio_triggers: sub_m.values_of("trigger").map(|v| v.collect()).unwrap_or_default(),
+                auto_intensity: match sub_m.is_present("auto") {
+                    true => Some(sub_m.value_of("intensity").unwrap_or("0.5").parse::<f32>().unwrap()),
+                    false => None,
+                },
+                history_depth: sub_m.value_of("history").unwrap_or("2").parse::<usize>().unwrap(),
+                beats_per_bar: sub_m.value_of("beats-per-bar").unwrap_or("4").parse::<u32>().unwrap(),
+                punch,
+                track_lengths: sub_m.value_of("tracks").unwrap_or("1").split(',')
+                    .map(|length| length.trim()).filter(|length| !length.is_empty())
+                    .map(|length| length.parse::<f32>().unwrap()).collect(),
+                passthrough: sub_m.is_present("passthrough"),
+                dry_wet: sub_m.value_of("dry-wet").unwrap_or("1").parse::<f32>().unwrap(),
+                buses: sub_m.is_present("buses"),
+                slice: sub_m.value_of("slice").map(|mode| boucle::slices::slice_mode_from_string(mode).unwrap()),
+                snap: sub_m.value_of("snap").map(|mode| boucle::transients::snap_mode_from_string(mode).unwrap()),
+                session,
+            };
+            cmd_live::run_live(&app_config, options).unwrap();
         },
         ("list-ports", Some(_)) => {
             cmd_list_ports::run_list_ports().unwrap();
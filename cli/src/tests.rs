@@ -3,8 +3,13 @@ mod tests {
     use std::path::Path;
     use std::path::PathBuf;
 
+    use boucle::Operation;
+    use boucle::op_sequence;
+    use boucle::session::Session;
+    use boucle::snapshot::Snapshot;
+
     use crate::app_config::AppConfig;
-    use crate::cmd_batch::run_batch;
+    use crate::cmd_batch::{run_batch, run_batch_session};
 
     fn get_test_data_path(filename: &str) -> String {
         let mut path = PathBuf::from(file!());
@@ -33,7 +38,7 @@ mod tests {
         let ops_path = get_test_data_path("ops.test");
         let input_path = get_test_data_path("chirp.i16.wav");
         let output_path = get_test_output_path("out.i16.wav");
-        run_batch(&app_config, &input_path, &output_path, &ops_path);
+        run_batch(&app_config, &input_path, &output_path, &ops_path, &[]);
 
         assert!(Path::new(&output_path).exists(),
             "Output {} does not exist", output_path);
@@ -45,8 +50,95 @@ mod tests {
         let ops_path = get_test_data_path("ops.test");
         let input_path = get_test_data_path("chirp.f32.wav");
         let output_path = get_test_output_path("out.f32.wav");
-        run_batch(&app_config, &input_path, &output_path, &ops_path);
+        run_batch(&app_config, &input_path, &output_path, &ops_path, &[]);
+
+        assert!(Path::new(&output_path).exists());
+    }
+
+    #[test]
+    fn test_batch_pattern() {
+        let app_config = AppConfig::new(44100, 2.0);
+        let ops_path = get_test_data_path("ops.test");
+        let input_path = get_test_data_path("chirp.i16.wav");
+        let output_path = get_test_output_path("out.pattern.wav");
+        run_batch(&app_config, &input_path, &output_path, &ops_path, &["checkers-reverse", "random"]);
+
+        assert!(Path::new(&output_path).exists());
+    }
+
+    #[test]
+    fn test_batch_mini_notation() {
+        let app_config = AppConfig::new(44100, 2.0);
+        let ops_path = get_test_data_path("ops-pattern.test");
+        let input_path = get_test_data_path("chirp.i16.wav");
+        let output_path = get_test_output_path("out.mini-notation.wav");
+        run_batch(&app_config, &input_path, &output_path, &ops_path, &["r ~ [j-1 j+1] x4:1/8"]);
+
+        assert!(Path::new(&output_path).exists());
+    }
+
+    #[test]
+    fn test_batch_bpm() {
+        // Patterns follow the tempo, as they do in live mode.
+        let mut outputs = Vec::new();
+        for &bpm in [60.0, 120.0].iter() {
+            let mut app_config = AppConfig::new(44100, 2.0);
+            app_config.bpm = bpm;
+            let ops_path = get_test_data_path("ops-pattern.test");
+            let input_path = get_test_data_path("chirp.f32.wav");
+            let output_path = get_test_output_path(&format!("out.bpm-{}.wav", bpm));
+            run_batch(&app_config, &input_path, &output_path, &ops_path, &["r ~ ~ ~"]);
+            outputs.push(std::fs::read(&output_path).unwrap());
+        }
+        assert_ne!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_batch_lfo() {
+        let app_config = AppConfig::new(44100, 2.0);
+        let ops_path = get_test_data_path("ops-lfo.test");
+        let input_path = get_test_data_path("chirp.i16.wav");
+        let output_path = get_test_output_path("out.lfo.wav");
+        run_batch(&app_config, &input_path, &output_path, &ops_path, &[]);
+
+        assert!(Path::new(&output_path).exists());
+    }
+
+    #[test]
+    fn test_batch_random() {
+        let mut app_config = AppConfig::new(44100, 2.0);
+        app_config.random_ops = boucle::ops::random_ops_from_string("1 reverse").unwrap();
+        let ops_path = get_test_data_path("ops-random.test");
+        let input_path = get_test_data_path("chirp.i16.wav");
+        let output_path = get_test_output_path("out.random.wav");
+        run_batch(&app_config, &input_path, &output_path, &ops_path, &[]);
 
         assert!(Path::new(&output_path).exists());
     }
+
+    #[test]
+    fn test_batch_session() {
+        let session = Session {
+            snapshot: Snapshot {
+                sample_rate: 44100,
+                bpm: 120.0,
+                loop_length: 44100,
+                dry_wet: 1.0,
+                seed: 0,
+                loops: vec!((0..44100).map(|i| (i as f32 / 10.0).sin() * 0.5).collect()),
+            },
+            automation: vec!(op_sequence::Entry {
+                start: 11025, duration: Some(11025), operation: Operation::Reverse, probability: 1.0,
+            }),
+            patterns: vec!("~ r".to_string()),
+            mapping: None,
+        };
+        let session_path = get_test_output_path("session");
+        session.save(Path::new(&session_path)).unwrap();
+        let output_path = get_test_output_path("out.session.wav");
+        run_batch_session(&session_path, &output_path, &[]);
+
+        let reader = hound::WavReader::open(&output_path).unwrap();
+        assert_eq!(reader.duration(), 44100);
+    }
 }
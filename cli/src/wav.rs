@@ -30,15 +30,15 @@ pub fn input_wav_to_buffer(audio_in_path: &str, buffers: &mut LoopBuffers) -> Re
         },
     };
 
-    for i in 0..buffers.input_a.len() {
-        // Sin wave
-        //buffer[i] = f32::sin((i as f32) / 10.0) * 0.2;
-        if i < wav_buffer.len() {
-            buffers.input_a[i] = wav_buffer[i];
-            buffers.input_b[i] = wav_buffer[i];
-        } else {
-            buffers.input_a[i] = 0.0;
-            buffers.input_b[i] = 0.0;
+    for buffer in buffers.history.iter_mut() {
+        for i in 0..buffer.len() {
+            // Sin wave
+            //buffer[i] = f32::sin((i as f32) / 10.0) * 0.2;
+            if i < wav_buffer.len() {
+                buffer[i] = wav_buffer[i];
+            } else {
+                buffer[i] = 0.0;
+            }
         }
     };
 
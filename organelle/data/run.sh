@@ -12,7 +12,7 @@ echo "Started jackd as $(cat /tmp/pids/jack.pid)"
 jack_wait --wait
 
 export RUST_LOG=warn
-./boucle_organelle &
+./boucle_organelle "$@" &
 echo $! > /tmp/pids/boucle_organelle.pid
 echo "Started boucle_organelle as $(cat /tmp/pids/boucle_organelle.pid)"
 
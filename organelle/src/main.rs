@@ -1,5 +1,6 @@
 mod patch_error;
 
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -8,10 +9,16 @@ use log::*;
 use nannou_osc as osc;
 
 use boucle::BeatFraction;
+use boucle::accompanist::Accompanist;
 use boucle::Boucle;
-use boucle::buffers::{Buffer, InputBuffer, LoopBuffers};
+use boucle::buffers::{DEFAULT_OVERDUB_FEEDBACK, LoopBuffers, LoopSpace, Overdub, ResizeFill};
+use boucle::buses::Frame;
+use boucle::control_surface::midi::{MidiControlSurface, MidiNote, NoteMap};
 use boucle::event::StateChange;
 use boucle::Operation;
+use boucle::Passthrough;
+use boucle::session::Session;
+use boucle::snapshot::Snapshot;
 use crate::patch_error::PatchError;
 
 // These ports are defined in
@@ -32,6 +39,30 @@ const MIN_LOOP_BEATS: f32 = 1.0;
 const MAX_LOOP_BEATS: f32 = 32.0;
 const DEFAULT_LOOP_BEATS: f32 = 8.0;
 
+const DEFAULT_AUTO_INTENSITY: f32 = 0.5;
+
+// Keep the loop being played plus an older loop, for the 'past loop' key.
+// Each loop buffer is as long as the longest loop so far, up to about 5.6MB
+// for 32 beats at 60 BPM.
+const HISTORY_DEPTH: usize = 3;
+
+// MIDI note of key 1, for mappings loaded from a session.
+const KEY_1_NOTE: MidiNote = 48;
+
+// Keys that save and load the loop while Aux is held.
+const LOAD_KEY: i32 = 1;
+const SAVE_KEY: i32 = 24;
+
+// Where the loop is saved, on the USB drive or SD card that Organelle OS
+// keeps patches and user data on.
+const DEFAULT_USER_DIR: &str = "/usbdrive";
+const SNAPSHOT_NAME: &str = "Data/boucle/snapshot";
+
+fn snapshot_path() -> PathBuf {
+    let user_dir = std::env::var("USER_DIR").unwrap_or(DEFAULT_USER_DIR.to_string());
+    return Path::new(&user_dir).join(SNAPSHOT_NAME);
+}
+
 struct Patch {
     boucle_rc: Arc<Mutex<Boucle>>,
     buffers_rc: Arc<Mutex<LoopBuffers>>,
@@ -42,32 +73,48 @@ struct Patch {
     // FIXME: hardcoded for now - should be changable via the knobs.
     bpm: f32,
     loop_beats: f32,
+
+    // Intensity of the autonomous accompanist, which the Aux key toggles.
+    auto_intensity: f32,
+
+    // Aux toggles auto mode when released, unless it was used with another
+    // key to save or load, which is `combo_key`.
+    aux_held: bool,
+    aux_combo: bool,
+    combo_key: Option<i32>,
+
+    // Mapping from a session, used instead of `map_key()`.
+    mapping: Option<NoteMap>,
 }
 
 type UpdateScreenFlag = bool;
 
+fn loop_beats_to_samples(beat_fraction_to_samples: f32, loop_beats: f32) -> usize {
+    return (beat_fraction_to_samples * 16.0 * loop_beats) as usize;
+}
+
 fn map_key(key: i32) -> Operation {
     match key {
-        0  /* Aux */ => Operation::NoOp,
+        0  /* Aux */ => Operation::NoOp,   /* Toggles auto mode, or saves and loads, see handle_key() */
         1  /* C4 */  => Operation::Jump { offset: BeatFraction::from(-8.0) },
         2            => Operation::Jump { offset: BeatFraction::from(-4.0) },
         3            => Operation::Jump { offset: BeatFraction::from(-2.0) },
         4            => Operation::Jump { offset: BeatFraction::from(-1.0) },
         5  /* E4 */  => Operation::Jump { offset: BeatFraction::from(-0.5) },
         6  /* F4 */  => Operation::Jump { offset: BeatFraction::from(-0.25) },
-        7            => Operation::NoOp,
+        7            => Operation::PastLoop { loops_back: 1 },
         8            => Operation::Repeat { loop_size: BeatFraction::from(0.0625) },
         9  /* G#4 */ => Operation::Repeat { loop_size: BeatFraction::from(0.125) },
         10           => Operation::Repeat { loop_size: BeatFraction::from(0.25) },
         11 /* Bb4 */ => Operation::Repeat { loop_size: BeatFraction::from(0.5) },
         12 /* B4 */  => Operation::Reverse,
-        13 /* C5 */  => Operation::NoOp,
+        13 /* C5 */  => Operation::Random,
         14           => Operation::Repeat { loop_size: BeatFraction::from(1.0) },
         15 /* D5 */  => Operation::Repeat { loop_size: BeatFraction::from(2.0) },
         16           => Operation::Repeat { loop_size: BeatFraction::from(4.0) },
         17 /* E5 */  => Operation::Repeat { loop_size: BeatFraction::from(8.0) },
         18 /* F5 */  => Operation::Jump { offset: BeatFraction::from(0.25) },
-        19 /* Gb5 */ => Operation::NoOp,
+        19 /* Gb5 */ => Operation::NoOp,   /* Bounce, see handle_key() */
         20           => Operation::Jump { offset: BeatFraction::from(0.5) },
         21           => Operation::Jump { offset: BeatFraction::from(1.0) },
         22           => Operation::Jump { offset: BeatFraction::from(2.0) },
@@ -169,18 +216,43 @@ impl jack::NotificationHandler for JackNotifications {
 }
 
 impl Patch {
-    pub fn new() -> Result<Self, PatchError> {
-        let boucle_config = boucle::Config {
+    /// Start from the session at `session_path` if given, otherwise from the
+    /// last saved loop, if there is one.
+    pub fn new(session_path: Option<PathBuf>) -> Result<Self, PatchError> {
+        let mut boucle_config = boucle::Config {
             sample_rate: SAMPLE_RATE,
-            beat_fraction_to_samples: (60.0 / DEFAULT_BPM / 16.0) * (SAMPLE_RATE as f32)
+            beat_fraction_to_samples: (60.0 / DEFAULT_BPM / 16.0) * (SAMPLE_RATE as f32),
+            seed: 0,
         };
 
-        let initial_loop_size = (boucle_config.beat_fraction_to_samples * DEFAULT_LOOP_BEATS) as usize;
-        let boucle: boucle::Boucle = boucle::Boucle::new(&boucle_config, initial_loop_size);
+        // Pick up where we left off, if a loop was saved.
+        let session = match session_path {
+            Some(path) => Some(Session::load(&path)?),
+            None => match Session::load(&snapshot_path()) {
+                Ok(session) => Some(session),
+                Err(error) => {
+                    info!("No saved loop: {}", error);
+                    None
+                },
+            },
+        };
+        let mut bpm = DEFAULT_BPM;
+        let mut loop_beats = DEFAULT_LOOP_BEATS;
+        if let Some(session) = &session {
+            let snapshot = &session.snapshot;
+            boucle_config = snapshot.config();
+            bpm = snapshot.bpm;
+            loop_beats = snapshot.loop_length as f32 / (boucle_config.beat_fraction_to_samples * 16.0);
+        }
 
-        let max_buffer_time = ((60.0 / MIN_BPM) * MAX_LOOP_BEATS).ceil() as usize;
-        info!("Maximium buffer time: {} seconds", max_buffer_time);
-        let buffers = boucle::buffers::create_buffers(max_buffer_time * SAMPLE_RATE as usize);
+        let initial_loop_size = loop_beats_to_samples(boucle_config.beat_fraction_to_samples, loop_beats);
+        let mut boucle: boucle::Boucle = boucle::Boucle::new(&boucle_config, initial_loop_size);
+
+        // Buffers grow when the loop gets longer, see handle_knobs().
+        let mut buffers = boucle::buffers::create_buffers_with_history(initial_loop_size, HISTORY_DEPTH);
+        if let Some(session) = &session {
+            session.restore(&mut boucle, &mut buffers)?;
+        }
 
         let receiver = osc::receiver(RECEIVE_PORT)?;
         let send_addr = format!("{}:{}", "127.0.0.1", SEND_PORT);
@@ -192,8 +264,13 @@ impl Patch {
             buffers_rc: Arc::new(Mutex::new(buffers)),
             receiver,
             sender,
-            bpm: DEFAULT_BPM,
-            loop_beats: DEFAULT_LOOP_BEATS,
+            bpm,
+            loop_beats,
+            auto_intensity: DEFAULT_AUTO_INTENSITY,
+            aux_held: false,
+            aux_combo: false,
+            combo_key: None,
+            mapping: session.and_then(|session| session.mapping),
         });
     }
 
@@ -207,6 +284,16 @@ impl Patch {
         let mut out_port = client
             .register_port("boucle_out", jack::AudioOut::default())
             .unwrap();
+        // Extra buses, to process the glitches separately.
+        let mut dry_port = client
+            .register_port("boucle_dry", jack::AudioOut::default())
+            .unwrap();
+        let mut wet_port = client
+            .register_port("boucle_wet", jack::AudioOut::default())
+            .unwrap();
+        let mut ops_port = client
+            .register_port("boucle_ops", jack::AudioOut::default())
+            .unwrap();
 
         self.signal_loaded();
         self.update_screen();
@@ -221,74 +308,104 @@ impl Patch {
             let loop_length = boucle.loop_length();
 
             // Read input into buffer
-            {
-                let mut record_buffer: &mut Buffer = match buffers.current_input {
-                    InputBuffer::A => &mut buffers.input_a,
-                    InputBuffer::B => &mut buffers.input_b,
-                };
+            for &s in in_port.as_slice(ps) {
+                buffers.record_sample(record_pos, s);
+                record_pos += 1;
+                if record_pos >= buffers.record_length() {
+                    buffers.next_input();
+                    debug!("Record buffer flip");
+                    record_pos = 0;
+                }
+            }
 
+            buffers.record_pos = record_pos;
+
+            if let Some(passthrough) = &mut buffers.passthrough {
                 for &s in in_port.as_slice(ps) {
-                    record_buffer[record_pos] = s;
-                    record_pos += 1;
-                    if record_pos >= loop_length {
-                        if buffers.current_input == InputBuffer::A {
-                            buffers.current_input = InputBuffer::B;
-                            record_buffer = &mut buffers.input_b;
-                        } else {
-                            buffers.current_input = InputBuffer::A;
-                            record_buffer = &mut buffers.input_a;
-                        }
-                        debug!("Record buffer flip");
-                        record_pos = 0;
-                    }
+                    passthrough.write(s);
                 }
             }
 
-            buffers.record_pos = record_pos;
+            // Input is treated as arriving at the current play position.
+            for (i, &s) in in_port.as_slice(ps).iter().enumerate() {
+                buffers.audio_triggers.process_sample(s, play_clock + i);
+                if let Some(accompanist) = &mut buffers.accompanist {
+                    accompanist.process_sample(s, play_clock + i);
+                }
+            }
+            for entry in buffers.audio_triggers.take_ops(boucle.beat_fraction_to_samples) {
+                boucle.pattern_player.schedule(entry);
+            }
+            if let Some(accompanist) = &mut buffers.accompanist {
+                for entry in accompanist.take_ops() {
+                    boucle.pattern_player.schedule(entry);
+                }
+            }
 
-            let out_buf = out_port.as_mut_slice(ps);
-            {
-                let mut in_buffer = match buffers.current_output {
-                    InputBuffer::A => &buffers.input_a,
-                    InputBuffer::B => &buffers.input_b,
-                };
+            let wet_buf = wet_port.as_mut_slice(ps);
+            let ops_buf = ops_port.as_mut_slice(ps);
+            if let Some(passthrough) = &mut buffers.passthrough {
+                // Input for this period is already written, so there's no delay.
+                let input_position = passthrough.start_block(wet_buf.len());
+                let ops = boucle.ops_for_period(play_clock, wet_buf.len());
+                for i in 0..wet_buf.len() {
+                    let clock = play_clock + i;
+                    wet_buf[i] = boucle.next_passthrough_sample(passthrough, &ops, clock, input_position + i);
+                    ops_buf[i] = if boucle.op_active(&ops, clock) { wet_buf[i] } else { 0.0 };
+                }
 
+                // Keep the loops going, to come back to.
+                let play_pos = boucle.loop_position(play_clock);
+                if play_pos + wet_buf.len() >= loop_length {
+                    buffers.next_output();
+                    if buffers.play_length() != boucle.loop_length() {
+                        boucle.restart_loop(buffers.play_length(), play_clock + loop_length - play_pos);
+                    }
+                }
+                play_clock += wet_buf.len();
+            } else {
                 let mut out_pos = 0;
-                let play_pos = play_clock % loop_length;
-                let span = std::cmp::min(loop_length - play_pos, out_buf.len());
-                debug!("Play clock {} pos {}/{} span {} (total data {})", play_clock, play_pos, loop_length, span, out_buf.len());
+                let play_pos = boucle.loop_position(play_clock);
+                let span = std::cmp::min(loop_length - play_pos, wet_buf.len());
+                debug!("Play clock {} pos {}/{} span {} (total data {})", play_clock, play_pos, loop_length, span, wet_buf.len());
 
-                let ops = boucle.event_recorder.ops_for_period(play_clock, span);
-                boucle.process_buffer(&in_buffer, play_clock, span,
+                let ops = boucle.ops_for_period(play_clock, span);
+                boucle.process_history(&buffers.output_history(), play_clock, span,
                                       &ops, &mut |s| {
-                    out_buf[out_pos] = s;
+                    wet_buf[out_pos] = s;
+                    ops_buf[out_pos] = if boucle.op_active(&ops, play_clock + out_pos) { s } else { 0.0 };
                     out_pos += 1;
                 });
+                buffers.print_output(play_pos, &wet_buf[..span]);
                 play_clock += span;
 
-                if out_pos < out_buf.len() {
-                    // Flip buffer and continue
-                    let span_2 = out_buf.len() - span;
+                if play_pos + wet_buf.len() >= loop_length {
+                    // Flip buffer and continue, even if the block ends right at the loop end
+                    let span_2 = wet_buf.len() - span;
                     debug!("play buffer flip");
-
-                    if buffers.current_output == InputBuffer::A {
-                        buffers.current_output = InputBuffer::B;
-                        in_buffer = &mut buffers.input_b;
-                    } else {
-                        buffers.current_output = InputBuffer::A;
-                        in_buffer = &mut buffers.input_a;
+                    buffers.next_output();
+                    if buffers.play_length() != boucle.loop_length() {
+                        boucle.restart_loop(buffers.play_length(), play_clock);
                     }
 
-                    let ops = boucle.event_recorder.ops_for_period(play_clock, span_2);
-                    boucle.process_buffer(&in_buffer, play_clock, span_2,
+                    let ops = boucle.ops_for_period(play_clock, span_2);
+                    boucle.process_history(&buffers.output_history(), play_clock, span_2,
                                           &ops, &mut |s| {
-                        out_buf[out_pos] = s;
+                        wet_buf[out_pos] = s;
+                        ops_buf[out_pos] = if boucle.op_active(&ops, play_clock + out_pos - span) { s } else { 0.0 };
                         out_pos += 1;
                     });
+                    buffers.print_output(0, &wet_buf[span..]);
                     play_clock += span_2;
                 }
             }
 
+            let dry_buf = in_port.as_slice(ps);
+            dry_port.as_mut_slice(ps).copy_from_slice(dry_buf);
+            for (i, out) in out_port.as_mut_slice(ps).iter_mut().enumerate() {
+                *out = Frame::new(dry_buf[i], wet_buf[i], false, buffers.dry_wet).main;
+            }
+
             buffers.play_clock = play_clock;
 
             // Performer responds to what they hear.
@@ -316,8 +433,43 @@ impl Patch {
 
     fn handle_key(self: &mut Self, key: i32, pressed: bool) -> UpdateScreenFlag {
         info!("Key {} {}", key, pressed);
+        if key == 0 {
+            self.aux_held = pressed;
+            if pressed {
+                self.aux_combo = false;
+                return false;
+            }
+            if !self.aux_combo {
+                return self.toggle_auto();
+            }
+            return false;
+        }
+        if self.aux_held && pressed && (key == SAVE_KEY || key == LOAD_KEY) {
+            self.aux_combo = true;
+            self.combo_key = Some(key);
+            return match key {
+                SAVE_KEY => self.save_snapshot(),
+                _ => self.load_snapshot(),
+            };
+        }
+        if !pressed && self.combo_key == Some(key) {
+            // The key was used to save or load, so it has no op to stop.
+            self.combo_key = None;
+            return false;
+        }
+        if key == 19 {
+            if pressed {
+                self.buffers_rc.lock().unwrap().bounce();
+                return true;
+            }
+            return false;
+        }
+
         let mut boucle = self.boucle_rc.lock().unwrap();
-        let operation = map_key(key);
+        let operation = match &self.mapping {
+            Some(mapping) => mapping.map_midi_note(KEY_1_NOTE + (key - 1) as MidiNote),
+            None => map_key(key),
+        };
         let state_change = match pressed {
             false => StateChange::Off,
             true => StateChange::On,
@@ -326,6 +478,97 @@ impl Patch {
         return false;
     }
 
+    fn handle_freeze(self: &mut Self, frozen: bool) -> UpdateScreenFlag {
+        let mut buffers = self.buffers_rc.lock().unwrap();
+        if frozen {
+            buffers.freeze();
+        } else {
+            buffers.unfreeze();
+        }
+        return true;
+    }
+
+    fn handle_overdub(self: &mut Self, on: bool) -> UpdateScreenFlag {
+        let mut buffers = self.buffers_rc.lock().unwrap();
+        if on {
+            buffers.start_overdub(Overdub { feedback: DEFAULT_OVERDUB_FEEDBACK, print: 0.0 });
+        } else {
+            buffers.stop_overdub();
+        }
+        return true;
+    }
+
+    fn handle_passthrough(self: &mut Self, on: bool) -> UpdateScreenFlag {
+        // Make and drop the input history without holding the buffers, which
+        // the audio callback needs.
+        if on {
+            let length = self.buffers_rc.lock().unwrap().passthrough_length();
+            let passthrough = Passthrough::new(length);
+            let _unused = self.buffers_rc.lock().unwrap().start_passthrough_with(passthrough);
+        } else {
+            let _old = self.buffers_rc.lock().unwrap().take_passthrough();
+        }
+        return true;
+    }
+
+    fn save_snapshot(self: &mut Self) -> UpdateScreenFlag {
+        // Make room for the copy first, and hold each lock only to copy what
+        // it guards, so the audio callback isn't kept waiting.
+        let (loop_length, loop_count) = {
+            let buffers = self.buffers_rc.lock().unwrap();
+            (buffers.play_length(), buffers.output_history().len())
+        };
+        let mut snapshot = Snapshot::with_room(loop_length, loop_count);
+        snapshot.take_settings(&self.boucle_rc.lock().unwrap());
+        snapshot.copy_loops(&self.buffers_rc.lock().unwrap());
+        let status = match snapshot.save(&snapshot_path()) {
+            Ok(()) => "Saved".to_string(),
+            Err(error) => {
+                warn!("{}", error);
+                "Save failed".to_string()
+            },
+        };
+        self.sender.send(("/oled/line/4".to_string(), vec![osc::Type::String(status)])).ok();
+        return false;
+    }
+
+    fn load_snapshot(self: &mut Self) -> UpdateScreenFlag {
+        let status = match Snapshot::load(&snapshot_path()) {
+            Ok(snapshot) => {
+                // Lay the loops out first, so they are only swapped in while
+                // the audio callback is kept waiting.
+                let (buffer_length, loop_count) = {
+                    let buffers = self.buffers_rc.lock().unwrap();
+                    (buffers.buffer_length(), buffers.loop_count())
+                };
+                let space = snapshot.loop_space(buffer_length, loop_count);
+                let _old_loops = snapshot.restore_from(space, &mut self.boucle_rc.lock().unwrap(),
+                                                       &mut self.buffers_rc.lock().unwrap());
+                "Loaded".to_string()
+            },
+            Err(error) => {
+                warn!("{}", error);
+                "Load failed".to_string()
+            },
+        };
+        self.sender.send(("/oled/line/4".to_string(), vec![osc::Type::String(status)])).ok();
+        return true;
+    }
+
+    fn toggle_auto(self: &mut Self) -> UpdateScreenFlag {
+        let boucle = self.boucle_rc.lock().unwrap();
+        let mut buffers = self.buffers_rc.lock().unwrap();
+        if buffers.accompanist.is_some() {
+            info!("Auto mode off");
+            buffers.accompanist = None;
+        } else {
+            info!("Auto mode on, intensity {}", self.auto_intensity);
+            buffers.accompanist = Some(Accompanist::new(self.auto_intensity, boucle.beat_fraction_to_samples * 16.0,
+                                                        SAMPLE_RATE, boucle.seed));
+        }
+        return true;
+    }
+
     fn handle_knobs(self: &mut Self, positions: [i32; 6]) -> UpdateScreenFlag {
         info!("Knobs {} {} {} {} {} {}", positions[0], positions[1], positions[2],
               positions[3], positions[4], positions[5]);
@@ -344,9 +587,40 @@ impl Patch {
             update_screen = true;
         }
 
-        let new_loop_beats = scale_from_1024(MIN_LOOP_BEATS, MAX_LOOP_BEATS, positions[0]);
+        let new_loop_beats = scale_from_1024(MIN_LOOP_BEATS, MAX_LOOP_BEATS, positions[0]).round().min(MAX_LOOP_BEATS);
         if new_loop_beats != self.loop_beats {
             self.loop_beats = new_loop_beats;
+            let beat_fraction_to_samples = self.boucle_rc.lock().unwrap().beat_fraction_to_samples;
+            let loop_length = loop_beats_to_samples(beat_fraction_to_samples, new_loop_beats);
+            // Make room for a longer loop without holding the buffers, which
+            // the audio callback needs.
+            let (buffer_length, loop_count) = {
+                let buffers = self.buffers_rc.lock().unwrap();
+                (buffers.buffer_length(), buffers.loop_count())
+            };
+            let space = match loop_length > buffer_length {
+                true => LoopSpace::new(loop_length, loop_count),
+                false => LoopSpace::default(),
+            };
+            let _old_loops = self.buffers_rc.lock().unwrap().resize_loop_into(loop_length, ResizeFill::Repeat, space);
+            update_screen = true;
+        }
+
+        let new_dry_wet = positions[3] as f32 / 1023.0;
+        {
+            let mut buffers = self.buffers_rc.lock().unwrap();
+            if new_dry_wet != buffers.dry_wet {
+                buffers.dry_wet = new_dry_wet;
+                update_screen = true;
+            }
+        }
+
+        let new_auto_intensity = positions[1] as f32 / 1023.0;
+        if new_auto_intensity != self.auto_intensity {
+            self.auto_intensity = new_auto_intensity;
+            if let Some(accompanist) = &mut self.buffers_rc.lock().unwrap().accompanist {
+                accompanist.set_intensity(new_auto_intensity);
+            }
             update_screen = true;
         }
 
@@ -370,6 +644,26 @@ impl Patch {
                     }
                 }
             },
+            // Footswitch holds the loop while pressed.
+            "/fs" | "/freeze" => {
+                if let [osc::Type::Int(pressed)] = args(message) {
+                    return self.handle_freeze(*pressed >= 1);
+                }
+            },
+            "/overdub" => {
+                if let [osc::Type::Int(on)] = args(message) {
+                    return self.handle_overdub(*on >= 1);
+                }
+            },
+            // Play the live input, glitching its recent past.
+            "/passthrough" => {
+                if let [osc::Type::Int(on)] = args(message) {
+                    return self.handle_passthrough(*on >= 1);
+                }
+            },
+            // Save the loop to storage, or load it back.
+            "/save" => return self.save_snapshot(),
+            "/load" => return self.load_snapshot(),
             "/knobs" => {
                 if let [osc::Type::Int(k1), osc::Type::Int(k2), osc::Type::Int(k3),
                         osc::Type::Int(k4), osc::Type::Int(k5),osc::Type::Int(k6)] = args(message) {
@@ -402,6 +696,25 @@ impl Patch {
 
         let packet = (addr, args);
         self.sender.send(packet).ok();
+
+        let buffers = self.buffers_rc.lock().unwrap();
+        let auto = match buffers.accompanist {
+            Some(_) => format!("Auto: {:.0}%", self.auto_intensity * 100.0),
+            None => "Auto: off".to_string(),
+        };
+        let auto = format!("{}  Wet: {:.0}%", auto, buffers.dry_wet * 100.0);
+        self.sender.send(("/oled/line/2".to_string(), vec![osc::Type::String(auto)])).ok();
+
+        let freeze = if buffers.passthrough.is_some() {
+            "Passthrough"
+        } else if buffers.overdub.is_some() {
+            "Overdub"
+        } else if buffers.is_frozen() {
+            "Frozen"
+        } else {
+            ""
+        };
+        self.sender.send(("/oled/line/3".to_string(), vec![osc::Type::String(freeze.to_string())])).ok();
     }
 
     fn process_events(self: &mut Self) -> UpdateScreenFlag {
@@ -428,7 +741,14 @@ impl Patch {
 pub fn main() {
     env_logger::init();
 
-    let mut patch = Patch::new()
+    let args: Vec<String> = std::env::args().collect();
+    let session_path = match args.as_slice() {
+        [_] => None,
+        [_, option, path] if option == "--session" => Some(PathBuf::from(path)),
+        _ => panic!("Usage: boucle_organelle [--session FILE]"),
+    };
+
+    let mut patch = Patch::new(session_path)
         .map_err(|e| panic!("{}", e.message))
         .unwrap();
     patch.run()
@@ -19,3 +19,11 @@ impl From<io::Error> for PatchError {
         }
     }
 }
+
+impl From<boucle::session::SessionError> for PatchError {
+    fn from(error: boucle::session::SessionError) -> Self {
+        PatchError {
+            message: error.to_string(),
+        }
+    }
+}